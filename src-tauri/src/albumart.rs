@@ -0,0 +1,175 @@
+/*
+    Custom album art
+
+    Art picked by the user instead of what scanning finds. The image is converted to a JPEG no
+    larger than MAX_ART_SIZE, stored in the art cache and kept as an album override, so it stays
+    when the library is updated. It can also be embedded into the album's files or saved as
+    their cover.jpg.
+*/
+
+use crate::library::{album_dir, fill_album_art_details, rescan_tracks, Library};
+use crate::overrides::set_album_art_override;
+use crate::tags::write_cover_art;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::ImageReader;
+use log::warn;
+use std::path::{Path, PathBuf};
+use tauri::{AppHandle, Manager};
+
+/// Largest width or height of custom art, bigger images are scaled down
+const MAX_ART_SIZE: u32 = 1500;
+const JPEG_QUALITY: u8 = 90;
+
+#[derive(serde::Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AlbumArtOptions {
+    /// Also embed the art into the album's files
+    embed: bool,
+    /// Also save the art as cover.jpg in the album's folder
+    write_cover_file: bool,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SetAlbumArtResult {
+    library: Library,
+    /// Files the art couldn't be written to
+    errors: Vec<String>,
+}
+
+/// Decode any supported image and encode it as a JPEG that fits in MAX_ART_SIZE
+fn normalize_image(path: &Path) -> Result<Vec<u8>, String> {
+    let image = ImageReader::open(path)
+        .map_err(|e| e.to_string())?
+        .with_guessed_format()
+        .map_err(|e| e.to_string())?
+        .decode()
+        .map_err(|e| e.to_string())?;
+
+    let image = if image.width() > MAX_ART_SIZE || image.height() > MAX_ART_SIZE {
+        image.resize(MAX_ART_SIZE, MAX_ART_SIZE, FilterType::Lanczos3)
+    } else {
+        image
+    };
+
+    let mut data = vec![];
+
+    JpegEncoder::new_with_quality(&mut data, JPEG_QUALITY)
+        .encode_image(&image.to_rgb8())
+        .map_err(|e| e.to_string())?;
+
+    Ok(data)
+}
+
+/// Delete replaced custom art, only if it is ours. Art from anywhere else is left alone.
+fn remove_cached_art(cache_dir: &Path, album_art: Option<String>) {
+    let Some(album_art) = album_art.map(PathBuf::from) else {
+        return;
+    };
+
+    if album_art.starts_with(cache_dir) {
+        if let Err(err) = std::fs::remove_file(&album_art) {
+            warn!("Failed to remove {}: {}", album_art.display(), err);
+        }
+    }
+}
+
+/// Use the image at `path` as the art of an album, or go back to the art from its files if
+/// `path` is `None`
+#[tauri::command(async)]
+pub fn set_album_art(
+    app_handle: AppHandle,
+    mut library: Library,
+    album_id: String,
+    path: Option<String>,
+    options: Option<AlbumArtOptions>,
+) -> Result<SetAlbumArtResult, String> {
+    let options = options.unwrap_or_default();
+    let cache_dir = app_handle
+        .path()
+        .app_cache_dir()
+        .map_err(|e| e.to_string())?;
+
+    if !library.albums.iter().any(|album| album.id == album_id) {
+        return Err(format!("Album {} not found", album_id));
+    }
+
+    let files: Vec<PathBuf> = library
+        .tracks
+        .iter()
+        .filter(|track| track.album_id == album_id)
+        .map(|track| PathBuf::from(&track.location))
+        .filter(|file| file.exists())
+        .collect();
+
+    let Some(path) = path else {
+        let old = set_album_art_override(&app_handle, &album_id, None)?;
+        remove_cached_art(&cache_dir, old);
+
+        if let Some(album) = library.albums.iter_mut().find(|album| album.id == album_id) {
+            album.album_art = None;
+        }
+
+        rescan_tracks(&app_handle, &mut library, &files, true);
+
+        return Ok(SetAlbumArtResult {
+            library,
+            errors: vec![],
+        });
+    };
+
+    let data = normalize_image(Path::new(&path))?;
+
+    // Named after the contents, so the frontend doesn't keep showing a cached older image
+    let art_path = cache_dir.join(format!("{}-{:x}.jpg", album_id, md5::compute(&data)));
+
+    std::fs::create_dir_all(&cache_dir).map_err(|e| e.to_string())?;
+    std::fs::write(&art_path, &data).map_err(|e| e.to_string())?;
+
+    let art_path = art_path.to_string_lossy().to_string();
+    let old = set_album_art_override(&app_handle, &album_id, Some(art_path.clone()))?;
+
+    if old.as_deref() != Some(art_path.as_str()) {
+        remove_cached_art(&cache_dir, old);
+    }
+
+    if let Some(album) = library.albums.iter_mut().find(|album| album.id == album_id) {
+        album.album_art = Some(art_path.clone());
+        album.animated_album_art = None;
+        album.palette = None;
+        album.blurhash = None;
+    }
+
+    fill_album_art_details(&mut library);
+
+    let mut errors = vec![];
+
+    if options.embed {
+        for file in &files {
+            let location = file.to_string_lossy();
+
+            if let Err(err) = write_cover_art(&location, &art_path) {
+                warn!("Failed to embed album art into {}: {}", location, err);
+                errors.push(format!("{}: {}", location, err));
+            }
+        }
+    }
+
+    if options.write_cover_file {
+        let mut dirs: Vec<&Path> = files.iter().filter_map(|file| album_dir(file)).collect();
+        dirs.sort();
+        dirs.dedup();
+
+        for dir in dirs {
+            let cover = dir.join("cover.jpg");
+
+            if let Err(err) = std::fs::write(&cover, &data) {
+                warn!("Failed to write {}: {}", cover.display(), err);
+                errors.push(format!("{}: {}", cover.display(), err));
+            }
+        }
+    }
+
+    Ok(SetAlbumArtResult { library, errors })
+}
@@ -0,0 +1,400 @@
+/*
+    Background analysis of tracks: acoustic fingerprints, loudness, tempo and key
+*/
+
+use crate::decode::{decode_file, mix_to_mono};
+use crate::fingerprint::{fingerprint, MAX_DURATION};
+use crate::key::detect_key;
+use crate::library::{Library, ProgressUpdatePayload, Track};
+use crate::loudness::{album_loudness, Loudness, LoudnessMeter};
+use crate::tags::write_replay_gain;
+use crate::tempo::detect_tempo;
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Emitter, Manager, State};
+
+// After each track the job rests for the time the track took divided by this, so it uses
+// about two thirds of a core and leaves room for playback and the UI
+const REST_RATIO: u32 = 2;
+const MAX_REST: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+pub struct AnalysisState {
+    running: AtomicBool,
+    cancelled: AtomicBool,
+}
+
+#[derive(serde::Deserialize, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AnalysisOptions {
+    fingerprint: bool,
+    loudness: bool,
+    /// Estimate BPM and key of tracks that aren't tagged with them
+    tempo_and_key: bool,
+    /// Write REPLAYGAIN_* tags to files that don't have them yet
+    write_replay_gain: bool,
+    /// Analyze tracks again even if they already have results
+    reanalyze: bool,
+}
+
+impl Default for AnalysisOptions {
+    fn default() -> Self {
+        AnalysisOptions {
+            fingerprint: true,
+            loudness: true,
+            tempo_and_key: true,
+            write_replay_gain: false,
+            reanalyze: false,
+        }
+    }
+}
+
+/// Results for a single track, sent to the frontend as soon as they are ready.
+/// Only the fields that were analyzed are set.
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct TrackAnalysis {
+    track_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fingerprint: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    loudness: Option<Loudness>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bpm: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    camelot: Option<String>,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct AlbumAnalysis {
+    album_id: String,
+    loudness: Loudness,
+}
+
+/// Tracks of an album to analyze. Album loudness needs every track of the album to be decoded.
+struct AlbumJob {
+    album_id: String,
+    tracks: Vec<Track>,
+    loudness: bool,
+}
+
+fn needs_fingerprint(track: &Track, options: &AnalysisOptions) -> bool {
+    options.fingerprint && (options.reanalyze || track.fingerprint.is_none())
+}
+
+fn needs_loudness(track: &Track, options: &AnalysisOptions) -> bool {
+    options.loudness && (options.reanalyze || track.loudness.is_none())
+}
+
+// Tagged values are never replaced, even when reanalyzing
+fn needs_tempo_and_key(track: &Track, options: &AnalysisOptions) -> bool {
+    options.tempo_and_key && (track.bpm.is_none() || track.key.is_none())
+}
+
+fn album_jobs(library: Library, options: &AnalysisOptions) -> Vec<AlbumJob> {
+    let mut albums: Vec<(String, Vec<Track>)> = vec![];
+    let mut indices: HashMap<String, usize> = HashMap::new();
+
+    for track in library.tracks.into_iter().filter(|track| !track.hidden) {
+        let index = *indices.entry(track.album_id.clone()).or_insert_with(|| {
+            albums.push((track.album_id.clone(), vec![]));
+            albums.len() - 1
+        });
+
+        albums[index].1.push(track);
+    }
+
+    albums
+        .into_iter()
+        .filter_map(|(album_id, tracks)| {
+            let album_missing = library
+                .albums
+                .iter()
+                .find(|album| album.id == album_id)
+                .map_or(true, |album| album.loudness.is_none());
+
+            // Album loudness can't be measured while some of its tracks are unavailable
+            let loudness = options.loudness
+                && !tracks.iter().any(|track| track.offline)
+                && (album_missing || tracks.iter().any(|track| needs_loudness(track, options)));
+
+            let tracks: Vec<Track> = tracks
+                .into_iter()
+                .filter(|track| !track.offline)
+                .filter(|track| {
+                    loudness
+                        || needs_fingerprint(track, options)
+                        || needs_loudness(track, options)
+                        || needs_tempo_and_key(track, options)
+                })
+                .collect();
+
+            if tracks.is_empty() {
+                return None;
+            }
+
+            Some(AlbumJob {
+                album_id,
+                tracks,
+                loudness,
+            })
+        })
+        .collect()
+}
+
+/// Decode a track once and feed it to everything that needs it
+fn analyze_track(
+    track: &Track,
+    wants_fingerprint: bool,
+    wants_loudness: bool,
+    wants_tempo_and_key: bool,
+) -> Result<(TrackAnalysis, Option<LoudnessMeter>), String> {
+    let mut mono = vec![];
+    let mut sample_rate = 0;
+    let mut meter = LoudnessMeter::default();
+    let mut meter_error = None;
+
+    // Fingerprints, tempo and key only use the start of the track
+    let wants_mono = wants_fingerprint || wants_tempo_and_key;
+
+    let max_duration = if wants_loudness {
+        None
+    } else {
+        Some(MAX_DURATION)
+    };
+
+    decode_file(
+        Path::new(&track.location),
+        max_duration,
+        |samples, format| {
+            let mono_len = (MAX_DURATION * format.sample_rate as f64) as usize;
+
+            if wants_mono && mono.len() < mono_len {
+                sample_rate = format.sample_rate;
+                mono.extend(mix_to_mono(samples, format.channels));
+            }
+
+            if wants_loudness {
+                if let Err(err) = meter.add(samples, format) {
+                    meter_error = Some(err);
+                    return false;
+                }
+            }
+
+            true
+        },
+    )?;
+
+    if let Some(err) = meter_error {
+        return Err(err);
+    }
+
+    let fingerprint = if wants_fingerprint {
+        fingerprint(&mono, sample_rate)
+            .map_err(|err| warn!("Failed to fingerprint {}: {}", track.location, err))
+            .ok()
+    } else {
+        None
+    };
+
+    let bpm = if wants_tempo_and_key && track.bpm.is_none() {
+        detect_tempo(&mono, sample_rate)
+    } else {
+        None
+    };
+
+    let key = if wants_tempo_and_key && track.key.is_none() {
+        detect_key(&mono, sample_rate)
+    } else {
+        None
+    };
+
+    let loudness = if wants_loudness {
+        meter
+            .finish()
+            .map_err(|err| warn!("Failed to measure {}: {}", track.location, err))
+            .ok()
+    } else {
+        None
+    };
+
+    let analysis = TrackAnalysis {
+        track_id: track.id.clone(),
+        fingerprint,
+        loudness,
+        bpm,
+        key: key.map(|key| key.name().to_string()),
+        camelot: key.map(|key| key.camelot()),
+    };
+
+    Ok((analysis, wants_loudness.then_some(meter)))
+}
+
+fn emit_progress(app_handle: &AppHandle, message: Option<String>, progress: f64) {
+    app_handle
+        .emit(
+            "progressUpdate",
+            ProgressUpdatePayload {
+                id: "analyzeLibrary".to_string(),
+                title: "Analyzing Library".to_string(),
+                message,
+                progress: Some(progress),
+            },
+        )
+        .unwrap();
+}
+
+/// Returns false if the job was cancelled
+fn run_album_job(
+    app_handle: &AppHandle,
+    state: &AnalysisState,
+    job: &AlbumJob,
+    options: &AnalysisOptions,
+    done: &mut usize,
+    total: usize,
+) -> bool {
+    let mut results = vec![];
+    let mut meters = vec![];
+
+    for track in &job.tracks {
+        if state.cancelled.load(Ordering::SeqCst) {
+            return false;
+        }
+
+        emit_progress(
+            app_handle,
+            Some(track.title.clone()),
+            *done as f64 / total as f64,
+        );
+        *done += 1;
+
+        let started = Instant::now();
+
+        let (analysis, meter) = match analyze_track(
+            track,
+            needs_fingerprint(track, options),
+            job.loudness || needs_loudness(track, options),
+            needs_tempo_and_key(track, options),
+        ) {
+            Ok(result) => result,
+            Err(err) => {
+                warn!("Failed to analyze {}: {}", track.location, err);
+                continue;
+            }
+        };
+
+        app_handle.emit("trackAnalyzed", analysis.clone()).unwrap();
+
+        results.push((track, analysis));
+        meters.extend(meter);
+
+        // Decoding is CPU heavy, give playback and the UI a chance to run
+        std::thread::sleep((started.elapsed() / REST_RATIO).min(MAX_REST));
+    }
+
+    let album = if job.loudness && meters.len() == job.tracks.len() {
+        match album_loudness(&meters) {
+            Ok(loudness) => {
+                app_handle
+                    .emit(
+                        "albumAnalyzed",
+                        AlbumAnalysis {
+                            album_id: job.album_id.clone(),
+                            loudness,
+                        },
+                    )
+                    .unwrap();
+
+                Some(loudness)
+            }
+            Err(err) => {
+                warn!("Failed to measure album {}: {}", job.album_id, err);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    if options.write_replay_gain {
+        for (track, analysis) in results {
+            let Some(loudness) = &analysis.loudness else {
+                continue;
+            };
+
+            if let Err(err) = write_replay_gain(
+                Path::new(&track.location),
+                loudness,
+                album.as_ref(),
+                options.reanalyze,
+            ) {
+                warn!("Failed to write ReplayGain to {}: {}", track.location, err);
+            }
+        }
+    }
+
+    true
+}
+
+/// Start analyzing tracks that are missing results in the background.
+/// Results are sent as `trackAnalyzed` and `albumAnalyzed` events.
+/// Returns false if a job is already running.
+#[tauri::command]
+pub fn start_analysis(
+    app_handle: AppHandle,
+    state: State<AnalysisState>,
+    library: Library,
+    options: Option<AnalysisOptions>,
+) -> Result<bool, String> {
+    let options = options.unwrap_or_default();
+    let jobs = album_jobs(library, &options);
+
+    if jobs.is_empty() {
+        return Ok(true);
+    }
+
+    if state.running.swap(true, Ordering::SeqCst) {
+        return Ok(false);
+    }
+
+    state.cancelled.store(false, Ordering::SeqCst);
+
+    let spawned = std::thread::Builder::new()
+        .name("analysis".to_string())
+        .spawn(move || {
+            let state = app_handle.state::<AnalysisState>();
+            let total = jobs.iter().map(|job| job.tracks.len()).sum();
+            let mut done = 0;
+
+            debug!("Analyzing {} tracks", total);
+
+            for job in &jobs {
+                if !run_album_job(&app_handle, &state, job, &options, &mut done, total) {
+                    debug!("Analysis cancelled");
+                    break;
+                }
+            }
+
+            emit_progress(&app_handle, None, 1.0);
+
+            state.running.store(false, Ordering::SeqCst);
+        });
+
+    if let Err(err) = spawned {
+        state.running.store(false, Ordering::SeqCst);
+        return Err(err.to_string());
+    }
+
+    Ok(true)
+}
+
+#[tauri::command]
+pub fn cancel_analysis(state: State<AnalysisState>) {
+    state.cancelled.store(true, Ordering::SeqCst);
+}
@@ -0,0 +1,493 @@
+/*
+    Library backups
+
+    A backup is a single JSON file with everything that isn't in the music files themselves:
+    the library records, playlists and key-value settings from the frontend's database, the
+    listening history, metadata overrides and config.json without its secrets. Restoring can
+    move music directories to new paths, for when the backup comes from another machine.
+*/
+
+use crate::history::{all_plays, apply_play_stats, replace_history, Play};
+use crate::library::{rescan_tracks, Library};
+use crate::overrides::{replace_overrides, with_overrides, Overrides};
+use crate::search::update_search_index;
+use log::warn;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+use tauri::{AppHandle, Manager};
+use time::OffsetDateTime;
+
+/// Bumped whenever the format changes in a way older versions can't read
+const BACKUP_VERSION: u32 = 1;
+const CONFIG_FILE: &str = "config.json";
+
+/// Config keys left out of backups, and kept as they are when restoring one
+const SECRET_CONFIG_KEYS: &[&str] = &["lastfm_token"];
+/// Database keys left out of backups, the Musixmatch token is fetched again when it's missing
+const SECRET_KV_KEYS: &[&str] = &["musixmatch_token", "musixmatch_exp"];
+
+/// Prefixes of album art locations converted to stream URLs by the frontend
+const STREAM_URL_PREFIXES: &[&str] = &["stream://localhost/", "http://stream.localhost/"];
+
+#[derive(serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Backup {
+    version: u32,
+    #[serde(with = "time::serde::rfc3339")]
+    created_at: OffsetDateTime,
+    /// The cache directory of the machine the backup was made on, album art extracted from
+    /// tags is stored there
+    cache_dir: String,
+    library: Library,
+    /// Stored as is, the frontend owns their format
+    playlists: Vec<serde_json::Value>,
+    kvs: Vec<serde_json::Value>,
+    #[serde(default)]
+    config: Option<serde_json::Value>,
+    #[serde(default)]
+    history: Vec<Play>,
+    #[serde(default)]
+    overrides: Overrides,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupInfo {
+    version: u32,
+    #[serde(with = "time::serde::rfc3339")]
+    created_at: OffsetDateTime,
+    tracks: usize,
+    playlists: usize,
+    plays: usize,
+    /// Music directories of the backed up library, to choose where they are now
+    music_directories: Vec<String>,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RestoredLibrary {
+    library: Library,
+    playlists: Vec<serde_json::Value>,
+    kvs: Vec<serde_json::Value>,
+}
+
+#[derive(serde::Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PathRemap {
+    from: String,
+    to: String,
+}
+
+pub(crate) fn config_path(app_handle: &AppHandle) -> Result<PathBuf, String> {
+    Ok(app_handle
+        .path()
+        .app_config_dir()
+        .map_err(|e| e.to_string())?
+        .join(CONFIG_FILE))
+}
+
+fn read_config(path: &Path) -> Result<Option<serde_json::Value>, String> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+
+    serde_json::from_str(&text).map_err(|e| e.to_string())
+}
+
+fn cache_dir(app_handle: &AppHandle) -> Result<String, String> {
+    Ok(app_handle
+        .path()
+        .app_cache_dir()
+        .map_err(|e| e.to_string())?
+        .to_string_lossy()
+        .to_string())
+}
+
+/// Turn a stream URL back into the path it points to
+pub(crate) fn art_path(location: &str) -> String {
+    STREAM_URL_PREFIXES
+        .iter()
+        .find_map(|prefix| location.strip_prefix(prefix))
+        .map_or(location.to_string(), |path| {
+            let path = path.split_once("?uuid=").map_or(path, |(path, _)| path);
+
+            percent_encoding::percent_decode(path.as_bytes())
+                .decode_utf8_lossy()
+                .to_string()
+        })
+}
+
+fn is_separator(c: char) -> bool {
+    c == '/' || c == '\\'
+}
+
+/// `path` without `prefix`. Windows paths (with a drive letter or a UNC prefix) are compared
+/// case insensitively like Windows does.
+fn strip_path_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    let windows = prefix.starts_with("\\\\")
+        || prefix.split_once(':').map_or(false, |(drive, _)| {
+            drive.len() == 1 && drive.chars().all(|c| c.is_ascii_alphabetic())
+        });
+
+    let mut chars = path.char_indices();
+
+    for expected in prefix.chars() {
+        let (_, c) = chars.next()?;
+
+        let matches = c == expected
+            || (is_separator(c) && is_separator(expected))
+            || (windows && c.to_lowercase().eq(expected.to_lowercase()));
+
+        if !matches {
+            return None;
+        }
+    }
+
+    Some(chars.next().map_or("", |(i, _)| &path[i..]))
+}
+
+/// Replace the first matching prefix of `path`. Separators are compared loosely, so backups
+/// can be moved between Windows and other systems.
+fn remap_path(path: &str, remaps: &[PathRemap]) -> String {
+    for remap in remaps {
+        let from = remap.from.trim_end_matches(['/', '\\']);
+
+        let Some(rest) = strip_path_prefix(path, from) else {
+            continue;
+        };
+
+        if !rest.is_empty() && !rest.starts_with(['/', '\\']) {
+            continue;
+        }
+
+        return format!(
+            "{}{}",
+            remap.to.trim_end_matches(['/', '\\']),
+            rest.replace(['/', '\\'], &MAIN_SEPARATOR.to_string())
+        );
+    }
+
+    path.to_string()
+}
+
+fn without_secret_kvs(kvs: Vec<serde_json::Value>) -> Vec<serde_json::Value> {
+    kvs.into_iter()
+        .filter(|kv| {
+            kv.get("key")
+                .and_then(|key| key.as_str())
+                .map_or(true, |key| !SECRET_KV_KEYS.contains(&key))
+        })
+        .collect()
+}
+
+fn remap_art(art: &mut Option<String>, remaps: &[PathRemap]) {
+    if let Some(location) = art {
+        *location = remap_path(&art_path(location), remaps);
+    }
+}
+
+fn read_backup(path: &str) -> Result<Backup, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let backup: Backup = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+
+    if backup.version > BACKUP_VERSION {
+        return Err(format!(
+            "This backup was made by a newer version of Cleftly (format {}, expected {})",
+            backup.version, BACKUP_VERSION
+        ));
+    }
+
+    Ok(backup)
+}
+
+/// Write a backup of the library and everything around it to `path`
+#[tauri::command(async)]
+pub fn export_library(
+    app_handle: AppHandle,
+    path: String,
+    mut library: Library,
+    playlists: Vec<serde_json::Value>,
+    kvs: Vec<serde_json::Value>,
+) -> Result<(), String> {
+    // Art is stored as plain paths, so it can be remapped when restoring
+    for album in &mut library.albums {
+        remap_art(&mut album.album_art, &[]);
+        remap_art(&mut album.animated_album_art, &[]);
+    }
+
+    let mut config = read_config(&config_path(&app_handle)?)?;
+
+    if let Some(config) = config.as_mut().and_then(|config| config.as_object_mut()) {
+        for key in SECRET_CONFIG_KEYS {
+            config.remove(*key);
+        }
+    }
+
+    let backup = Backup {
+        version: BACKUP_VERSION,
+        created_at: OffsetDateTime::now_utc(),
+        cache_dir: cache_dir(&app_handle)?,
+        library,
+        playlists,
+        kvs: without_secret_kvs(kvs),
+        config,
+        history: all_plays(&app_handle)?,
+        overrides: with_overrides(&app_handle, |overrides| Ok(overrides.clone()))?,
+    };
+
+    let text = serde_json::to_string(&backup).map_err(|e| e.to_string())?;
+
+    std::fs::write(path, text).map_err(|e| e.to_string())
+}
+
+/// Summary of a backup, shown before restoring it
+#[tauri::command(async)]
+pub fn inspect_backup(path: String) -> Result<BackupInfo, String> {
+    let backup = read_backup(&path)?;
+
+    let music_directories = match backup
+        .config
+        .as_ref()
+        .and_then(|config| config.get("music_directories"))
+        .and_then(|dirs| dirs.as_array())
+    {
+        Some(dirs) => dirs
+            .iter()
+            .filter_map(|dir| dir.as_str().map(String::from))
+            .collect(),
+        None => backup
+            .library
+            .roots
+            .iter()
+            .map(|root| root.path.clone())
+            .collect(),
+    };
+
+    Ok(BackupInfo {
+        version: backup.version,
+        created_at: backup.created_at,
+        tracks: backup.library.tracks.len(),
+        playlists: backup.playlists.len(),
+        plays: backup.history.len(),
+        music_directories,
+    })
+}
+
+/// Restore a backup, replacing the listening history and merging its config into config.json.
+/// Paths starting with a remap's `from` are moved to its `to`. The library, playlists and
+/// key-value settings are returned for the frontend to store.
+#[tauri::command(async)]
+pub fn import_library(
+    app_handle: AppHandle,
+    path: String,
+    remaps: Option<Vec<PathRemap>>,
+) -> Result<RestoredLibrary, String> {
+    let mut backup = read_backup(&path)?;
+
+    // Art extracted from tags moves to this machine's cache, before any other remaps
+    let mut remaps = remaps.unwrap_or_default();
+    remaps.insert(
+        0,
+        PathRemap {
+            from: backup.cache_dir.clone(),
+            to: cache_dir(&app_handle)?,
+        },
+    );
+
+    let mut library = backup.library;
+
+    for track in &mut library.tracks {
+        track.location = remap_path(&track.location, &remaps);
+        remap_art(&mut track.album_art, &remaps);
+        remap_art(&mut track.animated_album_art, &remaps);
+    }
+
+    for album in &mut library.albums {
+        remap_art(&mut album.album_art, &remaps);
+        remap_art(&mut album.animated_album_art, &remaps);
+    }
+
+    for root in &mut library.roots {
+        root.path = remap_path(&root.path, &remaps);
+    }
+
+    if let Some(dirs) = backup
+        .config
+        .as_mut()
+        .and_then(|config| config.get_mut("music_directories"))
+        .and_then(|dirs| dirs.as_array_mut())
+    {
+        for dir in dirs.iter_mut() {
+            if let Some(path) = dir.as_str() {
+                *dir = serde_json::Value::String(remap_path(path, &remaps));
+            }
+        }
+    }
+
+    if let Some(serde_json::Value::Object(restored)) = backup.config {
+        let config_path = config_path(&app_handle)?;
+
+        // Merged into the current config, so keys missing from the backup are kept
+        let mut config = match read_config(&config_path)? {
+            Some(serde_json::Value::Object(config)) => config,
+            _ => serde_json::Map::new(),
+        };
+
+        for (key, value) in restored {
+            if !SECRET_CONFIG_KEYS.contains(&key.as_str()) {
+                config.insert(key, value);
+            }
+        }
+
+        if let Some(parent) = config_path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+
+        let text = serde_json::to_string_pretty(&config).map_err(|e| e.to_string())?;
+        std::fs::write(config_path, text).map_err(|e| e.to_string())?;
+    }
+
+    replace_history(&app_handle, backup.history)?;
+
+    backup
+        .overrides
+        .map_album_art(|album_art| remap_path(album_art, &remaps));
+    replace_overrides(&app_handle, backup.overrides)?;
+
+    // Cached art doesn't come with the backup, extract it again from one track of each album
+    let missing_art: HashSet<String> = library
+        .albums
+        .iter()
+        .filter(|album| {
+            album
+                .album_art
+                .as_ref()
+                .map_or(false, |art| !PathBuf::from(art).exists())
+        })
+        .map(|album| album.id.clone())
+        .collect();
+
+    let mut seen = HashSet::new();
+    let files: Vec<PathBuf> = library
+        .tracks
+        .iter()
+        .filter(|track| missing_art.contains(&track.album_id) && seen.insert(&track.album_id))
+        .map(|track| PathBuf::from(&track.location))
+        .filter(|file| file.exists())
+        .collect();
+
+    for album in &mut library.albums {
+        if missing_art.contains(&album.id) {
+            album.album_art = None;
+        }
+    }
+
+    rescan_tracks(&app_handle, &mut library, &files, true);
+
+    if let Err(err) = apply_play_stats(&app_handle, &mut library) {
+        warn!("Failed to apply play stats: {}", err);
+    }
+
+    update_search_index(&app_handle, &library);
+
+    Ok(RestoredLibrary {
+        library,
+        playlists: backup.playlists,
+        // Older backups still have them
+        kvs: without_secret_kvs(backup.kvs),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remap(from: &str, to: &str) -> PathRemap {
+        PathRemap {
+            from: from.to_string(),
+            to: to.to_string(),
+        }
+    }
+
+    fn native(path: &str) -> String {
+        path.replace('/', &MAIN_SEPARATOR.to_string())
+    }
+
+    #[test]
+    fn remap_path_prefix() {
+        let remaps = [remap("/home/me/Music/", "/mnt/music")];
+
+        assert_eq!(
+            remap_path("/home/me/Music/Artist/Song.flac", &remaps),
+            format!("/mnt/music{}", native("/Artist/Song.flac"))
+        );
+        assert_eq!(remap_path("/home/me/Music", &remaps), "/mnt/music");
+        // Only whole directory names
+        assert_eq!(
+            remap_path("/home/me/Music2/Song.flac", &remaps),
+            "/home/me/Music2/Song.flac"
+        );
+        // Other systems are case sensitive
+        assert_eq!(
+            remap_path("/home/me/music/Song.flac", &remaps),
+            "/home/me/music/Song.flac"
+        );
+    }
+
+    #[test]
+    fn remap_path_first_match() {
+        let remaps = [
+            remap("/music/Classical", "/classical"),
+            remap("/music", "/mnt/music"),
+        ];
+
+        assert_eq!(
+            remap_path("/music/Classical/Bach.flac", &remaps),
+            format!("/classical{}", native("/Bach.flac"))
+        );
+        assert_eq!(
+            remap_path("/music/Jazz/Davis.flac", &remaps),
+            format!("/mnt/music{}", native("/Jazz/Davis.flac"))
+        );
+    }
+
+    #[test]
+    fn remap_path_windows() {
+        let remaps = [remap("C:\\Users\\Me\\Music\\", "/home/me/Music")];
+
+        assert_eq!(
+            remap_path("c:\\users\\me\\music\\Artist\\Song.flac", &remaps),
+            format!("/home/me/Music{}", native("/Artist/Song.flac"))
+        );
+        assert_eq!(
+            remap_path("C:/Users/Me/Music/Song.flac", &remaps),
+            format!("/home/me/Music{}", native("/Song.flac"))
+        );
+        assert_eq!(
+            remap_path("D:\\Music\\Song.flac", &remaps),
+            "D:\\Music\\Song.flac"
+        );
+
+        let remaps = [remap("\\\\NAS\\Music", "/mnt/nas")];
+
+        assert_eq!(
+            remap_path("\\\\nas\\music\\Song.flac", &remaps),
+            format!("/mnt/nas{}", native("/Song.flac"))
+        );
+    }
+
+    #[test]
+    fn secret_kvs_left_out() {
+        let kvs = vec![
+            serde_json::json!({ "key": "musixmatch_token", "value": "secret" }),
+            serde_json::json!({ "key": "libraryRoots", "value": [] }),
+        ];
+
+        assert_eq!(
+            without_secret_kvs(kvs),
+            vec![serde_json::json!({ "key": "libraryRoots", "value": [] })]
+        );
+    }
+}
@@ -0,0 +1,178 @@
+/*
+    Browsing the music directories by folder
+
+    Folders are built from where the library's tracks are, so they are listed in the same way
+    for offline music directories, and folders without music aren't.
+*/
+
+use crate::library::{Library, Track};
+use crate::sortname::{natural_key, NaturalKey};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct FolderSummary {
+    path: String,
+    name: String,
+    /// Album art of the first track with art in the folder or its subfolders
+    cover: Option<String>,
+    /// Tracks in the folder and its subfolders
+    track_count: usize,
+    /// In seconds, of all tracks in the folder and its subfolders
+    duration: u64,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Folder {
+    /// `None` for the list of music directories
+    path: Option<String>,
+    name: String,
+    cover: Option<String>,
+    track_count: usize,
+    duration: u64,
+    folders: Vec<FolderSummary>,
+    /// Tracks directly in the folder, by file name
+    track_ids: Vec<String>,
+}
+
+fn path_key(path: &Path) -> Vec<NaturalKey> {
+    path.components()
+        .map(|component| natural_key(&component.as_os_str().to_string_lossy()))
+        .collect()
+}
+
+fn summarize(path: &Path, tracks: &[&Track], album_art: &HashMap<&str, &str>) -> FolderSummary {
+    let cover = tracks
+        .iter()
+        .filter_map(|track| {
+            album_art
+                .get(track.album_id.as_str())
+                .map(|art| (path_key(Path::new(&track.location)), *art))
+        })
+        .min_by(|a, b| a.0.cmp(&b.0))
+        .map(|(_, art)| art.to_string());
+
+    FolderSummary {
+        path: path.to_string_lossy().to_string(),
+        name: path
+            .file_name()
+            .map_or_else(|| path.to_string_lossy(), |name| name.to_string_lossy())
+            .to_string(),
+        cover,
+        track_count: tracks.len(),
+        duration: tracks.iter().map(|track| track.duration).sum(),
+    }
+}
+
+/// Tracks that aren't hidden under `dir`
+fn tracks_in<'a>(library: &'a Library, dir: &Path) -> Vec<&'a Track> {
+    library
+        .tracks
+        .iter()
+        .filter(|track| !track.hidden && Path::new(&track.location).starts_with(dir))
+        .collect()
+}
+
+fn check_in_roots(library: &Library, path: &Path) -> Result<(), String> {
+    if library
+        .roots
+        .iter()
+        .any(|root| path.starts_with(&root.path))
+    {
+        Ok(())
+    } else {
+        Err(format!("{} is not in a music directory", path.display()))
+    }
+}
+
+/// A folder with its subfolders and tracks, or the music directories if `path` is `None`
+#[tauri::command(async)]
+pub fn browse_folder(library: Library, path: Option<String>) -> Result<Folder, String> {
+    let album_art: HashMap<&str, &str> = library
+        .albums
+        .iter()
+        .filter_map(|album| Some((album.id.as_str(), album.album_art.as_deref()?)))
+        .collect();
+
+    let Some(path) = path else {
+        let folders: Vec<FolderSummary> = library
+            .roots
+            .iter()
+            .map(|root| {
+                let dir = Path::new(&root.path);
+                summarize(dir, &tracks_in(&library, dir), &album_art)
+            })
+            .collect();
+
+        return Ok(Folder {
+            path: None,
+            name: String::new(),
+            cover: None,
+            track_count: folders.iter().map(|folder| folder.track_count).sum(),
+            duration: folders.iter().map(|folder| folder.duration).sum(),
+            folders,
+            track_ids: vec![],
+        });
+    };
+
+    let dir = Path::new(&path);
+    check_in_roots(&library, dir)?;
+
+    let tracks = tracks_in(&library, dir);
+    let summary = summarize(dir, &tracks, &album_art);
+
+    // Tracks in subfolders by the subfolder right below `dir`
+    let mut subfolders: HashMap<PathBuf, Vec<&Track>> = HashMap::new();
+    let mut direct = vec![];
+
+    for track in tracks {
+        let subfolder = Path::new(&track.location)
+            .strip_prefix(dir)
+            .ok()
+            .and_then(|rest| {
+                let mut components = rest.components();
+                let first = components.next()?;
+
+                // Only if the track isn't directly in `dir`
+                components.next().map(|_| dir.join(first))
+            });
+
+        match subfolder {
+            Some(subfolder) => subfolders.entry(subfolder).or_default().push(track),
+            None => direct.push(track),
+        }
+    }
+
+    let mut folders: Vec<FolderSummary> = subfolders
+        .iter()
+        .map(|(subfolder, tracks)| summarize(subfolder, tracks, &album_art))
+        .collect();
+    folders.sort_by_cached_key(|folder| natural_key(&folder.name));
+
+    direct.sort_by_cached_key(|track| path_key(Path::new(&track.location)));
+
+    Ok(Folder {
+        path: Some(summary.path),
+        name: summary.name,
+        cover: summary.cover,
+        track_count: summary.track_count,
+        duration: summary.duration,
+        folders,
+        track_ids: direct.iter().map(|track| track.id.clone()).collect(),
+    })
+}
+
+/// Ids of all tracks in a folder and its subfolders, in folder order, for playing or queueing
+/// the whole folder
+#[tauri::command(async)]
+pub fn folder_tracks(library: Library, path: String) -> Result<Vec<String>, String> {
+    let dir = Path::new(&path);
+    check_in_roots(&library, dir)?;
+
+    let mut tracks = tracks_in(&library, dir);
+    tracks.sort_by_cached_key(|track| path_key(Path::new(&track.location)));
+
+    Ok(tracks.iter().map(|track| track.id.clone()).collect())
+}
@@ -0,0 +1,190 @@
+/*
+    Classical music, tracks grouped into the works they are movements of
+*/
+
+use crate::library::{Library, Track};
+use crate::search::normalize;
+use crate::sortname::natural_key;
+use std::collections::HashMap;
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Work {
+    name: String,
+    composer_id: Option<String>,
+    /// Ordered by movement, recordings of the same work on different albums are separate works
+    track_ids: Vec<String>,
+    album_id: String,
+    /// In seconds
+    duration: u64,
+}
+
+fn movement_order(track: &Track) -> (u32, u32, u32) {
+    (
+        track.movement_num.unwrap_or(u32::MAX),
+        track.disc_num,
+        track.track_num,
+    )
+}
+
+/// Tracks with a work, grouped by work and album. Only works by `composer_id` if it is set.
+#[tauri::command(async)]
+pub fn group_by_work(library: Library, composer_id: Option<String>) -> Result<Vec<Work>, String> {
+    let mut works: HashMap<(Option<&str>, String, &str), Vec<&Track>> = HashMap::new();
+
+    for track in &library.tracks {
+        let Some(work) = &track.work else {
+            continue;
+        };
+
+        if track.hidden || (composer_id.is_some() && track.composer_id != composer_id) {
+            continue;
+        }
+
+        works
+            .entry((
+                track.composer_id.as_deref(),
+                normalize(work.trim()),
+                track.album_id.as_str(),
+            ))
+            .or_default()
+            .push(track);
+    }
+
+    let composer_names: HashMap<&str, &str> = library
+        .composers
+        .iter()
+        .map(|composer| (composer.id.as_str(), composer.sort_name.as_str()))
+        .collect();
+
+    let mut works: Vec<Work> = works
+        .into_values()
+        .map(|mut tracks| {
+            tracks.sort_by_key(|track| movement_order(track));
+
+            Work {
+                name: tracks[0].work.clone().unwrap_or_default(),
+                composer_id: tracks[0].composer_id.clone(),
+                track_ids: tracks.iter().map(|track| track.id.clone()).collect(),
+                album_id: tracks[0].album_id.clone(),
+                duration: tracks.iter().map(|track| track.duration).sum(),
+            }
+        })
+        .collect();
+
+    works.sort_by_cached_key(|work| {
+        let composer = work
+            .composer_id
+            .as_deref()
+            .and_then(|id| composer_names.get(id))
+            .copied()
+            .unwrap_or_default();
+
+        (
+            natural_key(composer),
+            natural_key(&work.name),
+            work.album_id.clone(),
+        )
+    });
+
+    Ok(works)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::library::{test_track, Composer};
+    use time::OffsetDateTime;
+
+    fn movement(id: &str, work: &str, album_id: &str, movement_num: Option<u32>) -> Track {
+        Track {
+            composer_id: Some("beethoven".to_string()),
+            work: Some(work.to_string()),
+            album_id: album_id.to_string(),
+            movement_num,
+            ..test_track(id)
+        }
+    }
+
+    fn composer(id: &str, sort_name: &str) -> Composer {
+        Composer {
+            id: id.to_string(),
+            name: sort_name.to_string(),
+            sort_name: sort_name.to_string(),
+            tagged_sort_name: None,
+            created_at: OffsetDateTime::UNIX_EPOCH,
+        }
+    }
+
+    fn library(tracks: Vec<Track>) -> Library {
+        Library {
+            tracks,
+            albums: vec![],
+            artists: vec![],
+            composers: vec![
+                composer("beethoven", "Beethoven, Ludwig van"),
+                composer("bach", "Bach, Johann Sebastian"),
+            ],
+            roots: vec![],
+        }
+    }
+
+    fn track_ids(work: &Work) -> Vec<&str> {
+        work.track_ids.iter().map(|id| id.as_str()).collect()
+    }
+
+    #[test]
+    fn group_by_work_movements() {
+        let library = library(vec![
+            movement("iii", "Symphony No. 5", "album", Some(3)),
+            movement("i", "Symphony No. 5", "album", Some(1)),
+            // Spelled differently on one track
+            movement("ii", "symphony no. 5 ", "album", Some(2)),
+            movement("unnumbered", "Symphony No. 5", "album", None),
+            Track {
+                hidden: true,
+                ..movement("hidden", "Symphony No. 5", "album", Some(4))
+            },
+            test_track("no-work"),
+        ]);
+
+        let works = group_by_work(library, None).unwrap();
+
+        assert_eq!(works.len(), 1);
+        assert_eq!(track_ids(&works[0]), vec!["i", "ii", "iii", "unnumbered"]);
+        assert_eq!(works[0].duration, 4 * 180);
+        assert_eq!(works[0].composer_id.as_deref(), Some("beethoven"));
+    }
+
+    #[test]
+    fn group_by_work_recordings() {
+        let library = library(vec![
+            movement("karajan", "Symphony No. 5", "karajan", Some(1)),
+            movement("kleiber", "Symphony No. 5", "kleiber", Some(1)),
+            movement("fidelio", "Fidelio", "karajan", Some(1)),
+            Track {
+                composer_id: Some("bach".to_string()),
+                ..movement("goldberg", "Goldberg Variations", "gould", Some(1))
+            },
+        ]);
+
+        let works = group_by_work(library.clone(), None).unwrap();
+        let ids: Vec<Vec<&str>> = works.iter().map(track_ids).collect();
+
+        // By composer, then work, then album
+        assert_eq!(
+            ids,
+            vec![
+                vec!["goldberg"],
+                vec!["fidelio"],
+                vec!["karajan"],
+                vec!["kleiber"]
+            ]
+        );
+
+        let works = group_by_work(library, Some("bach".to_string())).unwrap();
+
+        assert_eq!(works.len(), 1);
+        assert_eq!(works[0].name, "Goldberg Variations");
+    }
+}
@@ -0,0 +1,325 @@
+/*
+    Importing when tracks were added from other players
+
+    Reads iTunes and Apple Music library exports (Library.xml), and CSV files with a path and a
+    date on each line. Dates are RFC 3339, "YYYY-MM-DD HH:MM:SS", "YYYY-MM-DD" or Unix seconds.
+*/
+
+use crate::library::{date_by_tracks, Library};
+use std::collections::{HashMap, HashSet};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+/// Path components compared when a path doesn't match exactly, usually artist/album/file
+const SUFFIX_COMPONENTS: usize = 3;
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DateAddedImport {
+    library: Library,
+    /// Tracks that got a date
+    matched: usize,
+    /// Entries of the file without a track in the library
+    unmatched: usize,
+}
+
+fn parse_date(text: &str) -> Option<OffsetDateTime> {
+    let text = text.trim().trim_matches('"');
+
+    if let Ok(seconds) = text.parse::<i64>() {
+        return OffsetDateTime::from_unix_timestamp(seconds).ok();
+    }
+
+    // Dates without a time or time zone are read as UTC
+    let text = match text.len() {
+        10 => format!("{}T00:00:00Z", text),
+        19 => format!("{}Z", text.replacen(' ', "T", 1)),
+        _ => text.to_string(),
+    };
+
+    OffsetDateTime::parse(&text, &Rfc3339).ok()
+}
+
+fn unescape_xml(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&#38;", "&")
+        .replace("&amp;", "&")
+}
+
+/// Value of `<key>{key}</key><{tag}>value</{tag}>` in a plist dict
+fn plist_value<'a>(dict: &'a str, key: &str, tag: &str) -> Option<&'a str> {
+    let rest = &dict[dict.find(&format!("<key>{}</key>", key))?..];
+    let start = rest.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let end = rest.find(&format!("</{}>", tag))?;
+
+    rest.get(start..end)
+}
+
+/// Turn a `file://` URL into a path
+fn file_url_path(url: &str) -> String {
+    let path = url
+        .strip_prefix("file://localhost")
+        .or_else(|| url.strip_prefix("file://"))
+        .unwrap_or(url);
+
+    let path = percent_encoding::percent_decode(path.as_bytes())
+        .decode_utf8_lossy()
+        .to_string();
+
+    // "/C:/Music/..." on Windows
+    match path.as_bytes() {
+        [b'/', drive, b':', ..] if drive.is_ascii_alphabetic() => path[1..].to_string(),
+        _ => path,
+    }
+}
+
+/// Tracks of an iTunes library export, every track is a dict without dicts inside it
+fn parse_itunes(text: &str) -> Vec<(String, OffsetDateTime)> {
+    let mut entries = vec![];
+    let mut rest = text;
+
+    while let Some(end) = rest.find("</dict>") {
+        let block = &rest[..end];
+        rest = &rest[end + "</dict>".len()..];
+
+        let Some(start) = block.rfind("<dict>") else {
+            continue;
+        };
+        let dict = &block[start..];
+
+        let location = plist_value(dict, "Location", "string");
+        let date = plist_value(dict, "Date Added", "date").and_then(parse_date);
+
+        if let (Some(location), Some(date)) = (location, date) {
+            entries.push((file_url_path(&unescape_xml(location)), date));
+        }
+    }
+
+    entries
+}
+
+/// Lines of `path,date`, lines that can't be read (like a header) are skipped
+fn parse_csv(text: &str) -> Vec<(String, OffsetDateTime)> {
+    text.lines()
+        .filter_map(|line| {
+            // Paths can contain commas, dates can't
+            let (path, date) = line.rsplit_once(',')?;
+            let path = path.trim().trim_matches('"');
+
+            Some((path.to_string(), parse_date(date)?)).filter(|_| !path.is_empty())
+        })
+        .collect()
+}
+
+/// Last components of a path, lowercase and with `/` separators
+fn path_suffix(path: &str) -> String {
+    let components: Vec<&str> = path.split(['/', '\\']).filter(|c| !c.is_empty()).collect();
+
+    components[components.len().saturating_sub(SUFFIX_COMPONENTS)..]
+        .join("/")
+        .to_lowercase()
+}
+
+/// Set when tracks were added from another player's library. Tracks are matched by their path,
+/// or by their last folders and file name when the music was somewhere else.
+#[tauri::command(async)]
+pub fn import_date_added(mut library: Library, path: String) -> Result<DateAddedImport, String> {
+    let text = std::fs::read_to_string(&path).map_err(|e| e.to_string())?;
+
+    let entries = if text.contains("<plist") {
+        parse_itunes(&text)
+    } else {
+        parse_csv(&text)
+    };
+
+    if entries.is_empty() {
+        return Err("No dates found in the file".to_string());
+    }
+
+    let by_location: HashMap<&str, usize> = library
+        .tracks
+        .iter()
+        .enumerate()
+        .map(|(i, track)| (track.location.as_str(), i))
+        .collect();
+
+    // Suffixes shared by several tracks aren't used, they can't tell them apart
+    let mut by_suffix: HashMap<String, Option<usize>> = HashMap::new();
+
+    for (i, track) in library.tracks.iter().enumerate() {
+        by_suffix
+            .entry(path_suffix(&track.location))
+            .and_modify(|index| *index = None)
+            .or_insert(Some(i));
+    }
+
+    let mut dates = HashMap::new();
+    let mut unmatched = 0;
+
+    for (location, date) in entries {
+        let index = by_location
+            .get(location.as_str())
+            .copied()
+            .or_else(|| by_suffix.get(&path_suffix(&location)).copied().flatten());
+
+        match index {
+            Some(index) => {
+                dates.insert(index, date);
+            }
+            None => unmatched += 1,
+        }
+    }
+
+    let mut dated_ids = HashSet::new();
+
+    for (&index, &date) in &dates {
+        let track = &mut library.tracks[index];
+        track.created_at = date;
+
+        dated_ids.insert(track.album_id.clone());
+        dated_ids.insert(track.artist_id.clone());
+        dated_ids.extend(track.composer_id.clone());
+    }
+
+    // Album artists of the dated albums too
+    let album_artists: Vec<String> = library
+        .albums
+        .iter()
+        .filter(|album| dated_ids.contains(&album.id))
+        .map(|album| album.artist_id.clone())
+        .collect();
+    dated_ids.extend(album_artists);
+
+    date_by_tracks(&mut library, |id| dated_ids.contains(id));
+
+    Ok(DateAddedImport {
+        library,
+        matched: dates.len(),
+        unmatched,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(text: &str) -> OffsetDateTime {
+        OffsetDateTime::parse(text, &Rfc3339).unwrap()
+    }
+
+    #[test]
+    fn parse_date_formats() {
+        assert_eq!(
+            parse_date("2019-03-01T12:30:00Z"),
+            Some(date("2019-03-01T12:30:00Z"))
+        );
+        assert_eq!(
+            parse_date("2019-03-01T13:30:00+01:00"),
+            Some(date("2019-03-01T12:30:00Z"))
+        );
+        assert_eq!(
+            parse_date("2019-03-01 12:30:00"),
+            Some(date("2019-03-01T12:30:00Z"))
+        );
+        assert_eq!(
+            parse_date("\"2019-03-01\""),
+            Some(date("2019-03-01T00:00:00Z"))
+        );
+        assert_eq!(
+            parse_date(" 1551443400 "),
+            Some(date("2019-03-01T12:30:00Z"))
+        );
+    }
+
+    #[test]
+    fn parse_date_invalid() {
+        assert_eq!(parse_date(""), None);
+        assert_eq!(parse_date("date"), None);
+        assert_eq!(parse_date("2019-13-01"), None);
+        assert_eq!(parse_date("01/03/2019"), None);
+    }
+
+    #[test]
+    fn parse_itunes_tracks() {
+        let text = r#"<?xml version="1.0" encoding="UTF-8"?>
+<plist version="1.0">
+<dict>
+    <key>Tracks</key>
+    <dict>
+        <key>1</key>
+        <dict>
+            <key>Name</key><string>Tom &amp; Jerry</string>
+            <key>Date Added</key><date>2019-03-01T12:30:00Z</date>
+            <key>Location</key><string>file:///Users/me/Music/Tom%20&amp;%20Jerry/01%20Intro.mp3</string>
+        </dict>
+        <key>2</key>
+        <dict>
+            <key>Date Added</key><date>2020-01-01T00:00:00Z</date>
+            <key>Location</key><string>file://localhost/C:/Music/Song.flac</string>
+        </dict>
+        <key>3</key>
+        <dict>
+            <key>Name</key><string>Stream without a location</string>
+            <key>Date Added</key><date>2020-01-01T00:00:00Z</date>
+        </dict>
+    </dict>
+</dict>
+</plist>"#;
+
+        assert_eq!(
+            parse_itunes(text),
+            vec![
+                (
+                    "/Users/me/Music/Tom & Jerry/01 Intro.mp3".to_string(),
+                    date("2019-03-01T12:30:00Z")
+                ),
+                (
+                    "C:/Music/Song.flac".to_string(),
+                    date("2020-01-01T00:00:00Z")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_csv_lines() {
+        let text = "path,date\n\
+                    /music/Crosby, Stills & Nash/Suite.flac,2019-03-01\n\
+                    \"/music/quoted.mp3\",1551443400\n\
+                    \n\
+                    ,2019-03-01\n\
+                    /music/no date.mp3\n\
+                    /music/bad date.mp3,yesterday\n";
+
+        assert_eq!(
+            parse_csv(text),
+            vec![
+                (
+                    "/music/Crosby, Stills & Nash/Suite.flac".to_string(),
+                    date("2019-03-01T00:00:00Z")
+                ),
+                (
+                    "/music/quoted.mp3".to_string(),
+                    date("2019-03-01T12:30:00Z")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_csv_empty() {
+        assert_eq!(parse_csv(""), vec![]);
+    }
+
+    #[test]
+    fn path_suffixes() {
+        assert_eq!(
+            path_suffix("C:\\Music\\Artist\\Album\\01 Song.mp3"),
+            "artist/album/01 song.mp3"
+        );
+        assert_eq!(path_suffix("/Song.mp3"), "song.mp3");
+    }
+}
@@ -0,0 +1,219 @@
+/*
+    Decoding audio files for analysis
+*/
+
+use std::fs::File;
+use std::path::Path;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct AudioFormat {
+    pub(crate) sample_rate: u32,
+    pub(crate) channels: usize,
+}
+
+/// Decode an audio file, calling `on_samples` with the interleaved samples of each packet.
+/// Decoding stops after `max_duration` seconds, or when `on_samples` returns false.
+pub(crate) fn decode_file(
+    path: &Path,
+    max_duration: Option<f64>,
+    mut on_samples: impl FnMut(&[f32], AudioFormat) -> bool,
+) -> Result<AudioFormat, String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+
+    let mut hint = Hint::new();
+
+    if let Some(ext) = path.extension().and_then(|ext| ext.to_str()) {
+        hint.with_extension(ext);
+    }
+
+    let probed = symphonia::default::get_probe()
+        .format(
+            &hint,
+            mss,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .map_err(|e| e.to_string())?;
+
+    let mut reader = probed.format;
+
+    let track = reader
+        .tracks()
+        .iter()
+        .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or("No audio track found")?;
+
+    let track_id = track.id;
+
+    let mut decoder = symphonia::default::get_codecs()
+        .make(&track.codec_params, &DecoderOptions::default())
+        .map_err(|e| e.to_string())?;
+
+    let mut format = AudioFormat {
+        sample_rate: track.codec_params.sample_rate.unwrap_or(44100),
+        channels: track
+            .codec_params
+            .channels
+            .map(|channels| channels.count())
+            .unwrap_or(2),
+    };
+
+    let mut sample_buf: Option<SampleBuffer<f32>> = None;
+    let mut decoded_frames = 0u64;
+
+    loop {
+        let packet = match reader.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(err))
+                if err.kind() == std::io::ErrorKind::UnexpectedEof =>
+            {
+                break
+            }
+            Err(SymphoniaError::ResetRequired) => break,
+            Err(err) => return Err(err.to_string()),
+        };
+
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // Corrupt packets are skipped
+            Err(SymphoniaError::DecodeError(_)) => continue,
+            Err(err) => return Err(err.to_string()),
+        };
+
+        let spec = *decoded.spec();
+
+        format = AudioFormat {
+            sample_rate: spec.rate,
+            channels: spec.channels.count(),
+        };
+
+        // Packets can be larger than the first one
+        if sample_buf.as_ref().map_or(true, |buf| {
+            buf.capacity() < decoded.capacity() * format.channels
+        }) {
+            sample_buf = Some(SampleBuffer::new(decoded.capacity() as u64, spec));
+        }
+
+        let buf = sample_buf.as_mut().unwrap();
+        buf.copy_interleaved_ref(decoded);
+
+        if !on_samples(buf.samples(), format) {
+            break;
+        }
+
+        decoded_frames += (buf.samples().len() / format.channels.max(1)) as u64;
+
+        if let Some(max_duration) = max_duration {
+            if decoded_frames as f64 / format.sample_rate as f64 >= max_duration {
+                break;
+            }
+        }
+    }
+
+    Ok(format)
+}
+
+/// Mix interleaved samples down to mono
+pub(crate) fn mix_to_mono(samples: &[f32], channels: usize) -> impl Iterator<Item = f32> + '_ {
+    samples
+        .chunks_exact(channels.max(1))
+        .map(|frame| frame.iter().sum::<f32>() / frame.len() as f32)
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// Band-limited resampling using a windowed sinc filter
+pub(crate) fn resample(samples: &[f32], from: u32, to: u32) -> Vec<f32> {
+    if from == to || samples.is_empty() {
+        return samples.to_vec();
+    }
+
+    const HALF_TAPS: isize = 8;
+    // Output samples fall between input samples at only a few offsets for common rates
+    // (1 for 44100 Hz to 11025 Hz, 147 for 48000 Hz), uncommon ones are rounded to the nearest
+    const MAX_PHASES: u64 = 1024;
+
+    let (from, to) = (u64::from(from), u64::from(to));
+    let ratio = from as f64 / to as f64;
+    // Cut off below the new Nyquist frequency when downsampling
+    let cutoff = if ratio > 1.0 { 0.95 / ratio } else { 0.95 };
+    let len = (samples.len() as f64 / ratio) as usize;
+
+    // Widen the filter when downsampling so it still spans enough zero crossings
+    let half_width = (HALF_TAPS as f64 / cutoff).ceil() as isize;
+    let taps = 2 * half_width as usize;
+    let phases = (to / gcd(from, to)).min(MAX_PHASES);
+
+    // The filter weights for each offset, computed once instead of for every output sample
+    let kernel: Vec<f64> = (0..phases)
+        .flat_map(|phase| {
+            (0..taps).map(move |tap| {
+                let x = (tap as isize - half_width + 1) as f64 - phase as f64 / phases as f64;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    let arg = std::f64::consts::PI * x * cutoff;
+                    arg.sin() / arg
+                };
+                // Blackman window
+                let t = (x / half_width as f64 + 1.0) / 2.0;
+                let window = 0.42 - 0.5 * (2.0 * std::f64::consts::PI * t).cos()
+                    + 0.08 * (4.0 * std::f64::consts::PI * t).cos();
+
+                sinc * window
+            })
+        })
+        .collect();
+
+    (0..len as u64)
+        .map(|n| {
+            let position = n * from;
+            let mut center = (position / to) as isize;
+            let mut phase = ((position % to) * phases + to / 2) / to;
+
+            if phase == phases {
+                center += 1;
+                phase = 0;
+            }
+
+            let weights = &kernel[phase as usize * taps..(phase as usize + 1) * taps];
+            let first = center - half_width + 1;
+
+            let mut sum = 0.0;
+            let mut weight = 0.0;
+
+            for (i, w) in (first..).zip(weights) {
+                if i < 0 || i as usize >= samples.len() {
+                    continue;
+                }
+
+                sum += samples[i as usize] as f64 * w;
+                weight += w;
+            }
+
+            if weight == 0.0 {
+                0.0
+            } else {
+                (sum / weight) as f32
+            }
+        })
+        .collect()
+}
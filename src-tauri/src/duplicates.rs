@@ -0,0 +1,455 @@
+/*
+    Finding and resolving duplicate tracks
+*/
+
+use crate::fingerprint::{decompress, similarity};
+use crate::library::{load_audio_properties, prune_library, Library, Track};
+use crate::search::update_search_index;
+use log::{debug, warn};
+use regex::Regex;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use tauri::AppHandle;
+
+const LOSSLESS_EXTENSIONS: &[&str] = &["flac", "wav", "wave", "aiff", "aif"];
+
+#[derive(serde::Deserialize, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct DuplicateOptions {
+    /// Maximum difference in duration (seconds) between duplicates
+    duration_tolerance: u64,
+    /// Only consider tracks on the same album duplicates
+    same_album: bool,
+    /// Also group tracks whose acoustic fingerprints match at least this closely (0-1),
+    /// even if their tags differ
+    min_fingerprint_similarity: Option<f64>,
+}
+
+impl Default for DuplicateOptions {
+    fn default() -> Self {
+        DuplicateOptions {
+            duration_tolerance: 2,
+            same_album: false,
+            min_fingerprint_similarity: None,
+        }
+    }
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DuplicateCandidate {
+    track_id: String,
+    location: String,
+    lossless: bool,
+    bitrate: Option<u32>,
+    sample_rate: Option<u32>,
+    bit_depth: Option<u8>,
+    /// Share of album art, year and genres that are present (0-1)
+    tag_completeness: f64,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DuplicateGroup {
+    /// Best quality first
+    tracks: Vec<DuplicateCandidate>,
+}
+
+#[derive(serde::Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum DuplicateAction {
+    Hide,
+    Trash,
+}
+
+#[derive(serde::Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DuplicateResolution {
+    keep: String,
+    remove: Vec<String>,
+    action: DuplicateAction,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ResolveDuplicatesResult {
+    library: Library,
+    /// Trashed track id -> id of the track that was kept, for updating playlists
+    replaced: HashMap<String, String>,
+    errors: Vec<String>,
+}
+
+/// Lowercase, drop remaster notes ("Song (Remastered 2011)", "Song - 2009 Remaster")
+/// and anything that isn't a letter or digit
+fn normalize_title(title: &str, remaster: &Regex) -> String {
+    remaster
+        .replace_all(&title.to_lowercase(), "")
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .collect()
+}
+
+fn is_lossless(track: &Track) -> bool {
+    let ext = Path::new(&track.location)
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+
+    // Only lossless codecs report a bit depth (e.g. ALAC in .m4a)
+    LOSSLESS_EXTENSIONS.contains(&ext.as_str()) || track.bit_depth.is_some()
+}
+
+fn tag_completeness(library: &Library, track: &Track) -> f64 {
+    let album = library
+        .albums
+        .iter()
+        .find(|album| album.id == track.album_id);
+
+    let present = [
+        album.map_or(false, |album| album.album_art.is_some()),
+        album.map_or(false, |album| album.year.is_some()),
+        !track.genres.is_empty(),
+    ];
+
+    present.iter().filter(|present| **present).count() as f64 / present.len() as f64
+}
+
+fn candidate(library: &Library, track: &Track) -> DuplicateCandidate {
+    DuplicateCandidate {
+        track_id: track.id.clone(),
+        location: track.location.clone(),
+        lossless: is_lossless(track),
+        bitrate: track.bitrate,
+        sample_rate: track.sample_rate,
+        bit_depth: track.bit_depth,
+        tag_completeness: tag_completeness(library, track),
+    }
+}
+
+fn compare_quality(a: &DuplicateCandidate, b: &DuplicateCandidate) -> Ordering {
+    // Sample rate and bit depth only matter between lossless files, a 48kHz MP3 isn't better
+    // than a 44.1kHz one
+    let key = |c: &DuplicateCandidate| {
+        if c.lossless {
+            (1, c.bit_depth.unwrap_or(0), c.sample_rate.unwrap_or(0))
+        } else {
+            (0, 0, 0)
+        }
+    };
+
+    key(b)
+        .cmp(&key(a))
+        .then(b.bitrate.unwrap_or(0).cmp(&a.bitrate.unwrap_or(0)))
+        .then(b.tag_completeness.total_cmp(&a.tag_completeness))
+}
+
+fn group(library: &Library, tracks: Vec<&Track>) -> DuplicateGroup {
+    let mut candidates: Vec<DuplicateCandidate> = tracks
+        .into_iter()
+        .map(|track| {
+            // Tracks scanned by older versions don't have audio properties yet
+            let mut track = track.clone();
+
+            if let Err(err) = load_audio_properties(&mut track) {
+                debug!("Failed to read properties of {}: {}", track.location, err);
+            }
+
+            candidate(library, &track)
+        })
+        .collect();
+
+    candidates.sort_by(compare_quality);
+
+    DuplicateGroup { tracks: candidates }
+}
+
+/// Group fingerprinted tracks that sound the same, skipping tracks that are already grouped
+fn fingerprint_groups(
+    library: &Library,
+    grouped: &HashSet<String>,
+    min_similarity: f64,
+    options: &DuplicateOptions,
+) -> Vec<DuplicateGroup> {
+    let mut tracks: Vec<(&Track, Vec<u32>)> = library
+        .tracks
+        .iter()
+        .filter(|track| !track.hidden && !grouped.contains(&track.id))
+        .filter_map(|track| Some((track, decompress(track.fingerprint.as_ref()?)?)))
+        .collect();
+
+    tracks.sort_by_key(|(track, _)| track.duration);
+
+    let mut used = vec![false; tracks.len()];
+    let mut groups = vec![];
+
+    for (i, (track, fingerprint)) in tracks.iter().enumerate() {
+        if used[i] {
+            continue;
+        }
+
+        let mut matches = vec![*track];
+
+        for (j, (other, other_fingerprint)) in tracks.iter().enumerate().skip(i + 1) {
+            if other.duration - track.duration > options.duration_tolerance {
+                break;
+            }
+
+            if used[j] || (options.same_album && other.album_id != track.album_id) {
+                continue;
+            }
+
+            if similarity(fingerprint, other_fingerprint) >= min_similarity {
+                matches.push(*other);
+                used[j] = true;
+            }
+        }
+
+        if matches.len() > 1 {
+            groups.push(group(library, matches));
+        }
+    }
+
+    groups
+}
+
+#[tauri::command(async)]
+pub fn find_duplicates(
+    library: Library,
+    options: Option<DuplicateOptions>,
+) -> Result<Vec<DuplicateGroup>, String> {
+    let options = options.unwrap_or_default();
+    let remaster = Regex::new(r"\s*[(\[][^)\]]*remaster[^)\]]*[)\]]|\s+-\s+[^-]*remaster.*$")
+        .map_err(|e| e.to_string())?;
+
+    let mut buckets: HashMap<(String, String, String), Vec<&Track>> = HashMap::new();
+
+    for track in library.tracks.iter().filter(|track| !track.hidden) {
+        let album = if options.same_album {
+            track.album_id.clone()
+        } else {
+            String::new()
+        };
+
+        buckets
+            .entry((
+                track.artist_id.clone(),
+                normalize_title(&track.title, &remaster),
+                album,
+            ))
+            .or_default()
+            .push(track);
+    }
+
+    let mut groups = vec![];
+
+    for mut tracks in buckets.into_values().filter(|tracks| tracks.len() > 1) {
+        // Split into runs of tracks with similar durations
+        tracks.sort_by_key(|track| track.duration);
+
+        let mut runs: Vec<Vec<&Track>> = vec![];
+
+        for track in tracks {
+            match runs.last_mut() {
+                Some(run)
+                    if track.duration - run.last().unwrap().duration
+                        <= options.duration_tolerance =>
+                {
+                    run.push(track)
+                }
+                _ => runs.push(vec![track]),
+            }
+        }
+
+        for run in runs.into_iter().filter(|run| run.len() > 1) {
+            groups.push(group(&library, run));
+        }
+    }
+
+    if let Some(min_similarity) = options.min_fingerprint_similarity {
+        let grouped: HashSet<String> = groups
+            .iter()
+            .flat_map(|group| group.tracks.iter().map(|c| c.track_id.clone()))
+            .collect();
+
+        groups.extend(fingerprint_groups(
+            &library,
+            &grouped,
+            min_similarity,
+            &options,
+        ));
+    }
+
+    debug!("Found {} groups of duplicates", groups.len());
+
+    Ok(groups)
+}
+
+#[tauri::command(async)]
+pub fn resolve_duplicates(
+    app_handle: AppHandle,
+    mut library: Library,
+    resolutions: Vec<DuplicateResolution>,
+) -> Result<ResolveDuplicatesResult, String> {
+    let mut replaced = HashMap::new();
+    let mut errors = vec![];
+
+    for resolution in resolutions {
+        for id in resolution
+            .remove
+            .iter()
+            .filter(|id| **id != resolution.keep)
+        {
+            let Some(index) = library.tracks.iter().position(|track| &track.id == id) else {
+                continue;
+            };
+
+            match resolution.action {
+                DuplicateAction::Hide => library.tracks[index].hidden = true,
+                DuplicateAction::Trash => {
+                    let location = library.tracks[index].location.clone();
+
+                    if let Err(err) = trash::delete(&location) {
+                        warn!("Failed to move {} to the trash: {}", location, err);
+                        errors.push(format!("{}: {}", location, err));
+                        continue;
+                    }
+
+                    library.tracks.remove(index);
+                    replaced.insert(id.clone(), resolution.keep.clone());
+                }
+            }
+        }
+    }
+
+    prune_library(&mut library);
+    update_search_index(&app_handle, &library);
+
+    Ok(ResolveDuplicatesResult {
+        library,
+        replaced,
+        errors,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fingerprint::compress;
+    use crate::library::test_track;
+
+    /// A track whose audio properties are already known, so they aren't read from the file
+    fn track(id: &str, title: &str, duration: u64, bitrate: u32) -> Track {
+        Track {
+            title: title.to_string(),
+            location: format!("/music/{}.mp3", id),
+            duration,
+            bitrate: Some(bitrate),
+            file_size: Some(1),
+            ..test_track(id)
+        }
+    }
+
+    fn library(tracks: Vec<Track>) -> Library {
+        Library {
+            tracks,
+            albums: vec![],
+            artists: vec![],
+            composers: vec![],
+            roots: vec![],
+        }
+    }
+
+    fn group_ids(groups: &[DuplicateGroup]) -> Vec<Vec<&str>> {
+        groups
+            .iter()
+            .map(|group| {
+                group
+                    .tracks
+                    .iter()
+                    .map(|candidate| candidate.track_id.as_str())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn find_duplicates_by_tags() {
+        let library = library(vec![
+            track("mp3", "Song", 180, 320),
+            Track {
+                location: "/music/flac.flac".to_string(),
+                ..track("flac", "Song (Remastered 2011)", 181, 900)
+            },
+            track("low", "song - 2009 Remaster", 182, 128),
+            track("live", "Song!", 240, 320),
+            Track {
+                artist_id: "other".to_string(),
+                ..track("cover", "Song", 180, 320)
+            },
+            Track {
+                hidden: true,
+                ..track("hidden", "Song", 180, 320)
+            },
+            track("other", "Other Song", 180, 320),
+        ]);
+
+        let groups = find_duplicates(library, None).unwrap();
+
+        // Lossless first, then by bitrate
+        assert_eq!(group_ids(&groups), vec![vec!["flac", "mp3", "low"]]);
+    }
+
+    #[test]
+    fn find_duplicates_same_album() {
+        let tracks = vec![
+            track("single", "Song", 180, 320),
+            Track {
+                album_id: "compilation".to_string(),
+                ..track("compilation", "Song", 180, 256)
+            },
+        ];
+
+        let groups = find_duplicates(library(tracks.clone()), None).unwrap();
+        assert_eq!(group_ids(&groups), vec![vec!["single", "compilation"]]);
+
+        let options = DuplicateOptions {
+            same_album: true,
+            ..Default::default()
+        };
+        let groups = find_duplicates(library(tracks), Some(options)).unwrap();
+        assert!(groups.is_empty());
+    }
+
+    #[test]
+    fn find_duplicates_by_fingerprint() {
+        let fingerprint: Vec<u32> = (0..100u32).map(|i| i.wrapping_mul(2_654_435_761)).collect();
+        let different: Vec<u32> = fingerprint.iter().map(|value| !value).collect();
+        let with_fingerprint = |id: &str, title: &str, fingerprint: &[u32]| Track {
+            fingerprint: Some(compress(fingerprint)),
+            ..track(id, title, 180, 320)
+        };
+
+        let library = library(vec![
+            with_fingerprint("a", "Song", &fingerprint),
+            with_fingerprint("b", "Song", &fingerprint),
+            with_fingerprint("mistagged", "Track 01", &fingerprint),
+            with_fingerprint("renamed", "Untitled", &fingerprint),
+            with_fingerprint("unrelated", "Other Song", &different),
+        ]);
+
+        let groups = find_duplicates(library.clone(), None).unwrap();
+        assert_eq!(group_ids(&groups), vec![vec!["a", "b"]]);
+
+        // Tracks already grouped by their tags aren't grouped again
+        let options = DuplicateOptions {
+            min_fingerprint_similarity: Some(0.9),
+            ..Default::default()
+        };
+        let groups = find_duplicates(library, Some(options)).unwrap();
+        assert_eq!(
+            group_ids(&groups),
+            vec![vec!["a", "b"], vec!["mistagged", "renamed"]]
+        );
+    }
+}
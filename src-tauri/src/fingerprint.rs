@@ -0,0 +1,508 @@
+/*
+    Chromaprint compatible acoustic fingerprints
+
+    Follows the default algorithm of Chromaprint (TEST2): the audio is resampled to 11025 Hz,
+    turned into a sequence of chroma vectors and run through a set of classifiers that each
+    produce 2 bits of a 32 bit sub-fingerprint for every ~124ms of audio.
+    Fingerprints are stored in the same compressed, base64 encoded form as `fpcalc` outputs.
+*/
+
+use crate::decode::resample;
+use rustfft::{num_complex::Complex, FftPlanner};
+
+const SAMPLE_RATE: u32 = 11025;
+const FRAME_SIZE: usize = 4096;
+const FRAME_STEP: usize = FRAME_SIZE / 3;
+const MIN_FREQ: f64 = 28.0;
+const MAX_FREQ: f64 = 3520.0;
+const NUM_BANDS: usize = 12;
+
+// Same as fpcalc
+pub(crate) const MAX_DURATION: f64 = 120.0;
+
+const ALGORITHM_ID: u8 = 1;
+
+const CHROMA_FILTER: [f64; 5] = [0.25, 0.75, 1.0, 0.75, 0.25];
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+// (filter type, y, height, width), quantizer thresholds
+const CLASSIFIERS: [((u8, usize, usize, usize), [f64; 3]); 16] = [
+    ((0, 4, 3, 15), [1.98215, 2.35817, 2.63523]),
+    ((4, 4, 6, 15), [-1.03809, -0.651211, -0.282167]),
+    ((1, 0, 4, 16), [-0.298702, 0.119262, 0.558497]),
+    ((3, 8, 2, 12), [-0.105439, 0.0153946, 0.135898]),
+    ((3, 4, 4, 8), [-0.142891, 0.0258736, 0.200632]),
+    ((4, 0, 3, 5), [-0.826319, -0.590612, -0.368214]),
+    ((1, 2, 2, 9), [-0.557409, -0.233035, 0.0534525]),
+    ((2, 7, 3, 4), [-0.0646826, 0.00620476, 0.0784847]),
+    ((2, 6, 2, 16), [-0.192387, -0.029699, 0.215855]),
+    ((2, 1, 3, 2), [-0.0397818, -0.00568076, 0.0292026]),
+    ((5, 10, 1, 15), [-0.53823, -0.369934, -0.190235]),
+    ((3, 6, 2, 10), [-0.124877, 0.0296483, 0.139239]),
+    ((2, 1, 1, 14), [-0.101475, 0.0225617, 0.231971]),
+    ((3, 5, 6, 4), [-0.0799915, -0.00729616, 0.063262]),
+    ((1, 9, 2, 12), [-0.272556, 0.019424, 0.302559]),
+    ((3, 4, 2, 14), [-0.164292, -0.0321188, 0.0846339]),
+];
+
+const MAX_FILTER_WIDTH: usize = 16;
+
+/// Power spectrum of overlapping, Hamming windowed frames
+fn spectrogram(samples: &[f32]) -> Vec<Vec<f64>> {
+    if samples.len() < FRAME_SIZE {
+        return vec![];
+    }
+
+    let fft = FftPlanner::new().plan_fft_forward(FRAME_SIZE);
+
+    let window: Vec<f64> = (0..FRAME_SIZE)
+        .map(|i| {
+            0.54 - 0.46 * (2.0 * std::f64::consts::PI * i as f64 / (FRAME_SIZE - 1) as f64).cos()
+        })
+        .collect();
+
+    let mut buffer = vec![Complex::new(0.0, 0.0); FRAME_SIZE];
+
+    (0..=(samples.len() - FRAME_SIZE) / FRAME_STEP)
+        .map(|frame| {
+            let start = frame * FRAME_STEP;
+
+            for (i, value) in buffer.iter_mut().enumerate() {
+                *value = Complex::new(samples[start + i] as f64 * window[i], 0.0);
+            }
+
+            fft.process(&mut buffer);
+
+            buffer[..=FRAME_SIZE / 2]
+                .iter()
+                .map(|value| value.norm_sqr())
+                .collect()
+        })
+        .collect()
+}
+
+/// Fold the spectrum into 12 pitch classes
+fn chroma(spectrum: &[Vec<f64>]) -> Vec<[f64; NUM_BANDS]> {
+    let freq_to_index =
+        |freq: f64| (FRAME_SIZE as f64 * freq / SAMPLE_RATE as f64).round() as usize;
+
+    let min_index = freq_to_index(MIN_FREQ).max(1);
+    let max_index = freq_to_index(MAX_FREQ).min(FRAME_SIZE / 2);
+
+    let notes: Vec<usize> = (min_index..max_index)
+        .map(|i| {
+            let freq = i as f64 * SAMPLE_RATE as f64 / FRAME_SIZE as f64;
+            let octave = (freq / (440.0 / 16.0)).log2();
+
+            (NUM_BANDS as f64 * (octave - octave.floor())) as usize
+        })
+        .collect();
+
+    spectrum
+        .iter()
+        .map(|frame| {
+            let mut features = [0.0; NUM_BANDS];
+
+            for (i, note) in (min_index..max_index).zip(&notes) {
+                features[*note] += frame[i];
+            }
+
+            features
+        })
+        .collect()
+}
+
+/// Smooth over time, then normalize each vector
+fn filter_and_normalize(chroma: &[[f64; NUM_BANDS]]) -> Vec<[f64; NUM_BANDS]> {
+    chroma
+        .windows(CHROMA_FILTER.len())
+        .map(|frames| {
+            let mut features = [0.0; NUM_BANDS];
+
+            for (frame, coefficient) in frames.iter().zip(CHROMA_FILTER) {
+                for (feature, value) in features.iter_mut().zip(frame) {
+                    *feature += value * coefficient;
+                }
+            }
+
+            let norm = features.iter().map(|v| v * v).sum::<f64>().sqrt();
+
+            if norm < 0.01 {
+                [0.0; NUM_BANDS]
+            } else {
+                features.map(|v| v / norm)
+            }
+        })
+        .collect()
+}
+
+/// Summed-area table of the chroma image, with a row and column of zeros in front
+struct IntegralImage {
+    rows: usize,
+    data: Vec<f64>,
+}
+
+impl IntegralImage {
+    fn new(image: &[[f64; NUM_BANDS]]) -> Self {
+        let width = NUM_BANDS + 1;
+        let mut data = vec![0.0; (image.len() + 1) * width];
+
+        for (x, row) in image.iter().enumerate() {
+            for (y, value) in row.iter().enumerate() {
+                data[(x + 1) * width + y + 1] =
+                    value + data[x * width + y + 1] + data[(x + 1) * width + y]
+                        - data[x * width + y];
+            }
+        }
+
+        IntegralImage {
+            rows: image.len(),
+            data,
+        }
+    }
+
+    /// Sum of rows `x1..x2` and bands `y1..y2`
+    fn area(&self, x1: usize, y1: usize, x2: usize, y2: usize) -> f64 {
+        let width = NUM_BANDS + 1;
+
+        self.data[x2 * width + y2] - self.data[x1 * width + y2] - self.data[x2 * width + y1]
+            + self.data[x1 * width + y1]
+    }
+}
+
+fn subtract_log(a: f64, b: f64) -> f64 {
+    (1.0 + a).ln() - (1.0 + b).ln()
+}
+
+fn apply_filter(image: &IntegralImage, x: usize, filter: (u8, usize, usize, usize)) -> f64 {
+    let (kind, y, h, w) = filter;
+    let area = |x1, y1, x2, y2| image.area(x1, y1, x2, y2);
+
+    match kind {
+        0 => subtract_log(area(x, y, x + w, y + h), 0.0),
+        1 => {
+            let h_2 = h / 2;
+            subtract_log(area(x, y + h_2, x + w, y + h), area(x, y, x + w, y + h_2))
+        }
+        2 => {
+            let w_2 = w / 2;
+            subtract_log(area(x + w_2, y, x + w, y + h), area(x, y, x + w_2, y + h))
+        }
+        3 => {
+            let (w_2, h_2) = (w / 2, h / 2);
+            subtract_log(
+                area(x, y + h_2, x + w_2, y + h) + area(x + w_2, y, x + w, y + h_2),
+                area(x, y, x + w_2, y + h_2) + area(x + w_2, y + h_2, x + w, y + h),
+            )
+        }
+        4 => {
+            let h_3 = h / 3;
+            subtract_log(
+                area(x, y + h_3, x + w, y + 2 * h_3),
+                area(x, y, x + w, y + h_3) + area(x, y + 2 * h_3, x + w, y + h),
+            )
+        }
+        _ => {
+            let w_3 = w / 3;
+            subtract_log(
+                area(x + w_3, y, x + 2 * w_3, y + h),
+                area(x, y, x + w_3, y + h) + area(x + 2 * w_3, y, x + w, y + h),
+            )
+        }
+    }
+}
+
+fn quantize(value: f64, thresholds: [f64; 3]) -> u32 {
+    // Gray code, so neighbouring values differ by one bit
+    if value < thresholds[1] {
+        if value < thresholds[0] {
+            0
+        } else {
+            1
+        }
+    } else if value < thresholds[2] {
+        3
+    } else {
+        2
+    }
+}
+
+fn calculate(image: &[[f64; NUM_BANDS]]) -> Vec<u32> {
+    if image.len() < MAX_FILTER_WIDTH {
+        return vec![];
+    }
+
+    let integral = IntegralImage::new(image);
+
+    (0..=integral.rows - MAX_FILTER_WIDTH)
+        .map(|x| {
+            CLASSIFIERS.iter().fold(0u32, |bits, (filter, thresholds)| {
+                (bits << 2) | quantize(apply_filter(&integral, x, *filter), *thresholds)
+            })
+        })
+        .collect()
+}
+
+/// Raw sub-fingerprints of mono audio
+pub(crate) fn fingerprint_samples(samples: &[f32], sample_rate: u32) -> Vec<u32> {
+    let samples = resample(samples, sample_rate, SAMPLE_RATE);
+
+    calculate(&filter_and_normalize(&chroma(&spectrogram(&samples))))
+}
+
+/// Compressed fingerprint of mono audio, only the first `MAX_DURATION` seconds are used
+pub(crate) fn fingerprint(samples: &[f32], sample_rate: u32) -> Result<String, String> {
+    let len = samples
+        .len()
+        .min((MAX_DURATION * sample_rate as f64) as usize);
+    let fingerprint = fingerprint_samples(&samples[..len], sample_rate);
+
+    if fingerprint.is_empty() {
+        return Err("Track is too short to fingerprint".to_string());
+    }
+
+    Ok(compress(&fingerprint))
+}
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    bits: usize,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, width: usize) {
+        for i in 0..width {
+            if self.bits % 8 == 0 {
+                self.bytes.push(0);
+            }
+
+            if value & (1 << i) != 0 {
+                *self.bytes.last_mut().unwrap() |= 1 << (self.bits % 8);
+            }
+
+            self.bits += 1;
+        }
+    }
+}
+
+/// Read the `index`th `width` bit value of a bit stream starting at byte `offset`
+fn read_bits(bytes: &[u8], offset: usize, index: usize, width: usize) -> Option<u32> {
+    (0..width).try_fold(0, |value, i| {
+        let bit = offset * 8 + index * width + i;
+        let byte = bytes.get(bit / 8)?;
+
+        Some(value | (((byte >> (bit % 8)) & 1) as u32) << i)
+    })
+}
+
+/// Compress and base64 encode sub-fingerprints the same way Chromaprint does
+pub(crate) fn compress(fingerprint: &[u32]) -> String {
+    // Positions of the bits that changed since the previous sub-fingerprint, as deltas,
+    // terminated by a 0 for every sub-fingerprint
+    let mut deltas = vec![];
+    let mut previous = 0;
+
+    for &sub in fingerprint {
+        let mut x = sub ^ previous;
+        let mut bit = 1;
+        let mut last_bit = 0;
+
+        while x != 0 {
+            if x & 1 != 0 {
+                deltas.push(bit - last_bit);
+                last_bit = bit;
+            }
+
+            x >>= 1;
+            bit += 1;
+        }
+
+        deltas.push(0);
+        previous = sub;
+    }
+
+    let len = fingerprint.len();
+    let mut out = vec![ALGORITHM_ID, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+
+    // Small deltas fit in 3 bits, larger ones spill over into a second 5 bit array
+    let mut normal = BitWriter {
+        bytes: vec![],
+        bits: 0,
+    };
+    let mut exceptional = BitWriter {
+        bytes: vec![],
+        bits: 0,
+    };
+
+    for &delta in &deltas {
+        normal.write(delta.min(7), 3);
+
+        if delta >= 7 {
+            exceptional.write(delta - 7, 5);
+        }
+    }
+
+    out.extend(normal.bytes);
+    out.extend(exceptional.bytes);
+
+    out.chunks(3)
+        .flat_map(|chunk| {
+            let n = chunk
+                .iter()
+                .enumerate()
+                .fold(0u32, |n, (i, byte)| n | (*byte as u32) << (16 - 8 * i));
+
+            (0..=chunk.len())
+                .map(move |i| BASE64_ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char)
+        })
+        .collect()
+}
+
+/// Reverse of `compress`
+pub(crate) fn decompress(encoded: &str) -> Option<Vec<u32>> {
+    let values: Vec<u32> = encoded
+        .bytes()
+        .map(|c| {
+            BASE64_ALPHABET
+                .iter()
+                .position(|a| *a == c)
+                .map(|i| i as u32)
+        })
+        .collect::<Option<_>>()?;
+
+    let bytes: Vec<u8> = values
+        .chunks(4)
+        .flat_map(|chunk| {
+            let n = chunk
+                .iter()
+                .enumerate()
+                .fold(0u32, |n, (i, v)| n | v << (18 - 6 * i));
+
+            (0..chunk.len().saturating_sub(1)).map(move |i| (n >> (16 - 8 * i)) as u8)
+        })
+        .collect();
+
+    if bytes.len() < 4 {
+        return None;
+    }
+
+    let len = (bytes[1] as usize) << 16 | (bytes[2] as usize) << 8 | bytes[3] as usize;
+
+    // Read 3 bit values until we have seen `len` terminators
+    let mut normal = vec![];
+    let mut terminators = 0;
+
+    while terminators < len {
+        let value = read_bits(&bytes, 4, normal.len(), 3)?;
+
+        if value == 0 {
+            terminators += 1;
+        }
+
+        normal.push(value);
+    }
+
+    let exceptional_offset = 4 + (normal.len() * 3 + 7) / 8;
+    let mut exceptional_index = 0;
+
+    let mut fingerprint = Vec::with_capacity(len);
+    let mut current = 0u32;
+    let mut last_bit = 0;
+
+    for value in normal {
+        if value == 0 {
+            fingerprint.push(current);
+            last_bit = 0;
+            continue;
+        }
+
+        let delta = if value == 7 {
+            exceptional_index += 1;
+            7 + read_bits(&bytes, exceptional_offset, exceptional_index - 1, 5)?
+        } else {
+            value
+        };
+
+        last_bit += delta;
+
+        if last_bit > 32 {
+            return None;
+        }
+
+        current ^= 1 << (last_bit - 1);
+    }
+
+    Some(fingerprint)
+}
+
+/// Share of matching bits between two fingerprints (0-1), trying small offsets to account
+/// for different amounts of silence at the start
+pub(crate) fn similarity(a: &[u32], b: &[u32]) -> f64 {
+    const MAX_OFFSET: isize = 8;
+
+    (-MAX_OFFSET..=MAX_OFFSET)
+        .filter_map(|offset| {
+            let pairs: Vec<(u32, u32)> = if offset >= 0 {
+                a.iter()
+                    .skip(offset as usize)
+                    .zip(b)
+                    .map(|(x, y)| (*x, *y))
+                    .collect()
+            } else {
+                a.iter()
+                    .zip(b.iter().skip((-offset) as usize))
+                    .map(|(x, y)| (*x, *y))
+                    .collect()
+            };
+
+            if pairs.is_empty() {
+                return None;
+            }
+
+            let differing: u32 = pairs.iter().map(|(x, y)| (x ^ y).count_ones()).sum();
+
+            Some(1.0 - differing as f64 / (pairs.len() * 32) as f64)
+        })
+        .fold(0.0, f64::max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compress_round_trip() {
+        let fingerprint = vec![
+            0,
+            1,
+            u32::MAX,
+            0x8000_0000,
+            0xdead_beef,
+            0xdead_beef,
+            0x0f0f_f0f0,
+        ];
+
+        assert_eq!(decompress(&compress(&fingerprint)), Some(fingerprint));
+    }
+
+    #[test]
+    fn compress_matches_chromaprint() {
+        assert_eq!(compress(&[1]), "AQAAAQE");
+    }
+
+    #[test]
+    fn compress_empty() {
+        assert_eq!(decompress(&compress(&[])), Some(vec![]));
+    }
+
+    #[test]
+    fn decompress_invalid() {
+        assert_eq!(decompress(""), None);
+        assert_eq!(decompress("AQ"), None);
+        assert_eq!(decompress("AQAA+QE"), None);
+        // Says it has more sub-fingerprints than it does
+        assert_eq!(decompress("AQAAAgE"), None);
+    }
+}
@@ -0,0 +1,332 @@
+/*
+    Listening history
+
+    Every play is appended to a log, play counts, skip counts and last played dates are derived
+    from it rather than stored on tracks.
+*/
+
+use crate::library::Library;
+use log::warn;
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tauri::{AppHandle, Manager, State};
+use time::OffsetDateTime;
+
+const HISTORY_FILE: &str = "history.jsonl";
+const DEFAULT_LIMIT: usize = 10;
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum PlayOutcome {
+    /// Played until the end
+    Completed,
+    /// Another track was started before the end
+    Skipped,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Play {
+    track_id: String,
+    #[serde(with = "time::serde::rfc3339")]
+    started_at: OffsetDateTime,
+    /// Time actually listened, not counting pauses and seeking
+    listened_ms: u64,
+    outcome: PlayOutcome,
+}
+
+#[derive(serde::Serialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayStats {
+    play_count: u32,
+    skip_count: u32,
+    #[serde(with = "time::serde::rfc3339::option")]
+    last_played_at: Option<OffsetDateTime>,
+}
+
+impl PlayStats {
+    fn add(&mut self, play: &Play) {
+        match play.outcome {
+            PlayOutcome::Completed => self.play_count += 1,
+            PlayOutcome::Skipped => self.skip_count += 1,
+        }
+
+        if self
+            .last_played_at
+            .map_or(true, |last| play.started_at > last)
+        {
+            self.last_played_at = Some(play.started_at);
+        }
+    }
+}
+
+/// The log, loaded on first use
+#[derive(Default)]
+pub struct HistoryState(Mutex<Option<Vec<Play>>>);
+
+fn history_path(app_handle: &AppHandle) -> Result<PathBuf, String> {
+    Ok(app_handle
+        .path()
+        .app_data_dir()
+        .map_err(|e| e.to_string())?
+        .join(HISTORY_FILE))
+}
+
+fn load(app_handle: &AppHandle) -> Result<Vec<Play>, String> {
+    let path = history_path(app_handle)?;
+
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+
+    let text = std::fs::read_to_string(&path).map_err(|e| e.to_string())?;
+
+    // A line cut short by a crash shouldn't lose the rest of the history
+    Ok(text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| {
+            serde_json::from_str(line)
+                .map_err(|err| warn!("Skipping invalid history entry: {}", err))
+                .ok()
+        })
+        .collect())
+}
+
+/// Run `f` with the loaded history
+fn with_history<T>(
+    app_handle: &AppHandle,
+    state: &HistoryState,
+    f: impl FnOnce(&mut Vec<Play>) -> Result<T, String>,
+) -> Result<T, String> {
+    let mut history = state.0.lock().map_err(|e| e.to_string())?;
+
+    if history.is_none() {
+        *history = Some(load(app_handle)?);
+    }
+
+    f(history.as_mut().unwrap())
+}
+
+fn stats_by_track(plays: &[Play]) -> HashMap<&str, PlayStats> {
+    let mut stats: HashMap<&str, PlayStats> = HashMap::new();
+
+    for play in plays {
+        stats.entry(play.track_id.as_str()).or_default().add(play);
+    }
+
+    stats
+}
+
+/// Set play counts, skip counts and last played dates of the library's tracks from the history
+pub(crate) fn apply_play_stats(
+    app_handle: &AppHandle,
+    library: &mut Library,
+) -> Result<(), String> {
+    with_history(app_handle, &app_handle.state::<HistoryState>(), |plays| {
+        let stats = stats_by_track(plays);
+
+        for track in &mut library.tracks {
+            let track_stats = stats.get(track.id.as_str()).cloned().unwrap_or_default();
+
+            track.play_count = track_stats.play_count;
+            track.skip_count = track_stats.skip_count;
+            track.last_played_at = track_stats.last_played_at;
+        }
+
+        Ok(())
+    })
+}
+
+/// Every play in the history, oldest first
+pub(crate) fn all_plays(app_handle: &AppHandle) -> Result<Vec<Play>, String> {
+    with_history(app_handle, &app_handle.state::<HistoryState>(), |plays| {
+        Ok(plays.clone())
+    })
+}
+
+/// Replace the whole history, e.g. when restoring a backup
+pub(crate) fn replace_history(app_handle: &AppHandle, new_plays: Vec<Play>) -> Result<(), String> {
+    let path = history_path(app_handle)?;
+
+    with_history(app_handle, &app_handle.state::<HistoryState>(), |plays| {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+
+        let mut text = String::new();
+
+        for play in &new_plays {
+            text.push_str(&serde_json::to_string(play).map_err(|e| e.to_string())?);
+            text.push('\n');
+        }
+
+        std::fs::write(&path, text).map_err(|e| e.to_string())?;
+
+        *plays = new_plays;
+
+        Ok(())
+    })
+}
+
+/// Add a play to the history, returns the updated stats of its track
+#[tauri::command(async)]
+pub fn record_play(
+    app_handle: AppHandle,
+    state: State<HistoryState>,
+    play: Play,
+) -> Result<PlayStats, String> {
+    let path = history_path(&app_handle)?;
+
+    with_history(&app_handle, &state, |plays| {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| e.to_string())?;
+
+        let line = serde_json::to_string(&play).map_err(|e| e.to_string())?;
+        writeln!(file, "{}", line).map_err(|e| e.to_string())?;
+
+        plays.push(play);
+
+        let track_id = &plays.last().unwrap().track_id;
+
+        Ok(plays.iter().filter(|play| &play.track_id == track_id).fold(
+            PlayStats::default(),
+            |mut stats, play| {
+                stats.add(play);
+                stats
+            },
+        ))
+    })
+}
+
+#[derive(serde::Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct DateRange {
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    from: Option<OffsetDateTime>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    to: Option<OffsetDateTime>,
+}
+
+#[derive(serde::Serialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct TopEntry {
+    /// Track, album or artist id
+    id: String,
+    plays: u32,
+    skips: u32,
+    listened_ms: u64,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TopListening {
+    tracks: Vec<TopEntry>,
+    albums: Vec<TopEntry>,
+    artists: Vec<TopEntry>,
+    total_plays: u32,
+    total_listened_ms: u64,
+}
+
+fn top(entries: HashMap<&str, TopEntry>, limit: usize) -> Vec<TopEntry> {
+    let mut entries: Vec<TopEntry> = entries.into_values().collect();
+
+    entries.sort_by(|a, b| {
+        b.plays
+            .cmp(&a.plays)
+            .then(b.listened_ms.cmp(&a.listened_ms))
+            .then(a.id.cmp(&b.id))
+    });
+    entries.truncate(limit);
+
+    entries
+}
+
+/// Most played tracks, albums and artists of plays started within `range`
+#[tauri::command(async)]
+pub fn get_top_listening(
+    app_handle: AppHandle,
+    state: State<HistoryState>,
+    library: Library,
+    range: Option<DateRange>,
+    limit: Option<usize>,
+) -> Result<TopListening, String> {
+    let range = range.unwrap_or_default();
+    let limit = limit.unwrap_or(DEFAULT_LIMIT);
+
+    let tracks: HashMap<&str, (&str, &str)> = library
+        .tracks
+        .iter()
+        .map(|track| {
+            (
+                track.id.as_str(),
+                (track.album_id.as_str(), track.artist_id.as_str()),
+            )
+        })
+        .collect();
+
+    with_history(&app_handle, &state, |plays| {
+        let mut top_tracks: HashMap<&str, TopEntry> = HashMap::new();
+        let mut top_albums: HashMap<&str, TopEntry> = HashMap::new();
+        let mut top_artists: HashMap<&str, TopEntry> = HashMap::new();
+        let mut total_plays = 0;
+        let mut total_listened_ms = 0;
+
+        for play in plays.iter().filter(|play| {
+            range.from.map_or(true, |from| play.started_at >= from)
+                && range.to.map_or(true, |to| play.started_at < to)
+        }) {
+            // Tracks that were removed from the library still count towards the totals
+            let (album_id, artist_id) = tracks
+                .get(play.track_id.as_str())
+                .copied()
+                .unwrap_or_default();
+
+            for (entries, id) in [
+                (&mut top_tracks, play.track_id.as_str()),
+                (&mut top_albums, album_id),
+                (&mut top_artists, artist_id),
+            ] {
+                if id.is_empty() {
+                    continue;
+                }
+
+                let entry = entries.entry(id).or_insert_with(|| TopEntry {
+                    id: id.to_string(),
+                    ..Default::default()
+                });
+
+                match play.outcome {
+                    PlayOutcome::Completed => entry.plays += 1,
+                    PlayOutcome::Skipped => entry.skips += 1,
+                }
+
+                entry.listened_ms += play.listened_ms;
+            }
+
+            if play.outcome == PlayOutcome::Completed {
+                total_plays += 1;
+            }
+
+            total_listened_ms += play.listened_ms;
+        }
+
+        Ok(TopListening {
+            tracks: top(top_tracks, limit),
+            albums: top(top_albums, limit),
+            artists: top(top_artists, limit),
+            total_plays,
+            total_listened_ms,
+        })
+    })
+}
@@ -0,0 +1,268 @@
+/*
+    Musical key detection and notation
+*/
+
+use crate::decode::resample;
+use rustfft::{num_complex::Complex, FftPlanner};
+
+const SAMPLE_RATE: u32 = 11025;
+const FRAME_SIZE: usize = 8192;
+const HOP_SIZE: usize = 4096;
+const MIN_FREQ: f64 = 55.0;
+const MAX_FREQ: f64 = 1760.0;
+
+// Krumhansl-Kessler key profiles, starting at the tonic
+const MAJOR_PROFILE: [f64; 12] = [
+    6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88,
+];
+const MINOR_PROFILE: [f64; 12] = [
+    6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17,
+];
+
+const MAJOR_NAMES: [&str; 12] = [
+    "C", "Db", "D", "Eb", "E", "F", "F#", "G", "Ab", "A", "Bb", "B",
+];
+const MINOR_NAMES: [&str; 12] = [
+    "Cm", "C#m", "Dm", "Ebm", "Em", "Fm", "F#m", "Gm", "G#m", "Am", "Bbm", "Bm",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Key {
+    /// Pitch class of the tonic, C is 0
+    tonic: u8,
+    minor: bool,
+}
+
+impl Key {
+    /// Parse a key as written in TKEY/INITIALKEY tags: standard notation ("Am", "F#", "Bb minor"),
+    /// Camelot ("8A") or Open Key ("1m")
+    pub(crate) fn parse(value: &str) -> Option<Key> {
+        let value = value.trim();
+        let digits = value.chars().take_while(|c| c.is_ascii_digit()).count();
+
+        if digits > 0 {
+            let number: u8 = value[..digits]
+                .parse()
+                .ok()
+                .filter(|n| (1..=12).contains(n))?;
+
+            return match value[digits..].trim().to_lowercase().as_str() {
+                "a" => Some(Key::from_camelot(number, true)),
+                "b" => Some(Key::from_camelot(number, false)),
+                // Open Key is Camelot shifted by 7, 1d is C major
+                "m" => Some(Key::from_camelot((number + 6) % 12 + 1, true)),
+                "d" => Some(Key::from_camelot((number + 6) % 12 + 1, false)),
+                _ => None,
+            };
+        }
+
+        let mut chars = value.chars();
+
+        let natural = match chars.next()?.to_ascii_uppercase() {
+            'C' => 0,
+            'D' => 2,
+            'E' => 4,
+            'F' => 5,
+            'G' => 7,
+            'A' => 9,
+            'B' => 11,
+            _ => return None,
+        };
+
+        let rest = chars.as_str();
+
+        let (tonic, rest) = if let Some(rest) = rest.strip_prefix(&['#', '♯'][..]) {
+            ((natural + 1) % 12, rest)
+        } else if let Some(rest) = rest.strip_prefix(&['b', '♭'][..]) {
+            ((natural + 11) % 12, rest)
+        } else {
+            (natural, rest)
+        };
+
+        let minor = match rest.trim().to_lowercase().as_str() {
+            "" | "maj" | "major" => false,
+            "m" | "min" | "minor" => true,
+            _ => return None,
+        };
+
+        Some(Key { tonic, minor })
+    }
+
+    fn from_camelot(number: u8, minor: bool) -> Key {
+        // 8B is C major, each step adds a fifth. 7 is its own inverse mod 12.
+        let major_tonic = (7 * ((number + 4) % 12)) % 12;
+
+        Key {
+            tonic: if minor {
+                (major_tonic + 9) % 12
+            } else {
+                major_tonic
+            },
+            minor,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        if self.minor {
+            MINOR_NAMES[self.tonic as usize]
+        } else {
+            MAJOR_NAMES[self.tonic as usize]
+        }
+    }
+
+    /// Camelot wheel notation, e.g. "8A" for A minor
+    pub(crate) fn camelot(&self) -> String {
+        // Minor keys share the number of their relative major
+        let major_tonic = if self.minor {
+            (self.tonic + 3) % 12
+        } else {
+            self.tonic
+        };
+
+        format!(
+            "{}{}",
+            (7 * major_tonic as usize + 7) % 12 + 1,
+            if self.minor { "A" } else { "B" }
+        )
+    }
+}
+
+/// Average pitch class profile of the audio
+fn chromagram(samples: &[f32]) -> [f64; 12] {
+    let mut chroma = [0.0; 12];
+
+    if samples.len() < FRAME_SIZE {
+        return chroma;
+    }
+
+    let fft = FftPlanner::new().plan_fft_forward(FRAME_SIZE);
+
+    let window: Vec<f64> = (0..FRAME_SIZE)
+        .map(|i| 0.5 - 0.5 * (2.0 * std::f64::consts::PI * i as f64 / FRAME_SIZE as f64).cos())
+        .collect();
+
+    let bin_freq = SAMPLE_RATE as f64 / FRAME_SIZE as f64;
+    let min_bin = (MIN_FREQ / bin_freq).ceil() as usize;
+    let max_bin = (MAX_FREQ / bin_freq).floor() as usize;
+
+    let pitch_classes: Vec<usize> = (min_bin..=max_bin)
+        .map(|bin| {
+            let midi = 69.0 + 12.0 * (bin as f64 * bin_freq / 440.0).log2();
+            midi.round() as usize % 12
+        })
+        .collect();
+
+    let mut buffer = vec![Complex::new(0.0, 0.0); FRAME_SIZE];
+
+    for start in (0..=samples.len() - FRAME_SIZE).step_by(HOP_SIZE) {
+        for (i, value) in buffer.iter_mut().enumerate() {
+            *value = Complex::new(samples[start + i] as f64 * window[i], 0.0);
+        }
+
+        fft.process(&mut buffer);
+
+        let mut frame = [0.0; 12];
+
+        for (bin, pitch_class) in (min_bin..=max_bin).zip(&pitch_classes) {
+            frame[*pitch_class] += buffer[bin].norm();
+        }
+
+        // Every frame counts the same, whether it's loud or quiet
+        let max = frame.iter().cloned().fold(0.0, f64::max);
+
+        if max > 0.0 {
+            for (total, value) in chroma.iter_mut().zip(frame) {
+                *total += value / max;
+            }
+        }
+    }
+
+    chroma
+}
+
+fn correlation(a: &[f64; 12], b: &[f64; 12]) -> f64 {
+    let mean_a = a.iter().sum::<f64>() / 12.0;
+    let mean_b = b.iter().sum::<f64>() / 12.0;
+
+    let (mut covariance, mut var_a, mut var_b) = (0.0, 0.0, 0.0);
+
+    for (x, y) in a.iter().zip(b) {
+        covariance += (x - mean_a) * (y - mean_b);
+        var_a += (x - mean_a).powi(2);
+        var_b += (y - mean_b).powi(2);
+    }
+
+    if var_a == 0.0 || var_b == 0.0 {
+        return 0.0;
+    }
+
+    covariance / (var_a * var_b).sqrt()
+}
+
+/// Estimate the key of mono audio by matching its pitch class profile against each key
+pub(crate) fn detect_key(samples: &[f32], sample_rate: u32) -> Option<Key> {
+    let chroma = chromagram(&resample(samples, sample_rate, SAMPLE_RATE));
+
+    if chroma.iter().all(|value| *value == 0.0) {
+        return None;
+    }
+
+    (0..12u8)
+        .flat_map(|tonic| [(tonic, false), (tonic, true)])
+        .map(|(tonic, minor)| {
+            let profile = if minor { MINOR_PROFILE } else { MAJOR_PROFILE };
+            let rotated: [f64; 12] = std::array::from_fn(|i| chroma[(tonic as usize + i) % 12]);
+
+            (Key { tonic, minor }, correlation(&rotated, &profile))
+        })
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(key, _)| key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(value: &str) -> Option<(&'static str, String)> {
+        Key::parse(value).map(|key| (key.name(), key.camelot()))
+    }
+
+    #[test]
+    fn parse_standard_notation() {
+        assert_eq!(parse("C"), Some(("C", "8B".to_string())));
+        assert_eq!(parse("Am"), Some(("Am", "8A".to_string())));
+        assert_eq!(parse("F#"), Some(("F#", "2B".to_string())));
+        assert_eq!(parse("Bb minor"), Some(("Bbm", "3A".to_string())));
+        assert_eq!(parse("D♭ maj"), Some(("Db", "3B".to_string())));
+        assert_eq!(parse(" g#min "), Some(("G#m", "1A".to_string())));
+    }
+
+    #[test]
+    fn parse_camelot_and_open_key() {
+        assert_eq!(parse("8A"), Some(("Am", "8A".to_string())));
+        assert_eq!(parse("12b"), Some(("E", "12B".to_string())));
+        assert_eq!(parse("1d"), Some(("C", "8B".to_string())));
+        assert_eq!(parse("1m"), Some(("Am", "8A".to_string())));
+    }
+
+    #[test]
+    fn camelot_round_trip() {
+        for number in 1..=12 {
+            for letter in ["A", "B"] {
+                let camelot = format!("{}{}", number, letter);
+
+                assert_eq!(Key::parse(&camelot).map(|key| key.camelot()), Some(camelot));
+            }
+        }
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert_eq!(Key::parse(""), None);
+        assert_eq!(Key::parse("H"), None);
+        assert_eq!(Key::parse("0A"), None);
+        assert_eq!(Key::parse("13B"), None);
+        assert_eq!(Key::parse("8C"), None);
+        assert_eq!(Key::parse("C dorian"), None);
+    }
+}
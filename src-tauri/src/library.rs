@@ -2,14 +2,27 @@
     Library scanning and management
 */
 
-use lofty::{Accessor, AudioFile, Tag, TaggedFile, TaggedFileExt};
+use crate::backup::art_path;
+use crate::history::apply_play_stats;
+use crate::key::Key;
+use crate::loudness::Loudness;
+use crate::overrides::apply_overrides;
+use crate::palette::{extract_palette, Palette};
+use crate::placeholder::encode_placeholder;
+use crate::search::update_search_index;
+use crate::sortname::{configured_articles, generate_sort_name, sort_library, DEFAULT_ARTICLES};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use image::ImageReader;
+use lofty::{Accessor, AudioFile, FileProperties, Tag, TaggedFile, TaggedFileExt};
 use log::{debug, warn};
 use regex::Regex;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
-use std::fs::read_dir;
+use std::fs::{read_dir, Metadata as FsMetadata};
 use std::path::{Path, PathBuf};
-use tauri::{Emitter, Manager};
+use std::rc::Rc;
+use tauri::{AppHandle, Emitter, Manager};
 use time::OffsetDateTime;
 
 const SUPPORTED_EXTENSIONS: &[&str] = &[
@@ -19,12 +32,31 @@ const SUPPORTED_EXTENSIONS: &[&str] = &[
 const COVER_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "gif"];
 const ANIM_COVER_EXTENSIONS: &[&str] = &["mp4", "webm", "mov"];
 
+// Per-directory ignore file, uses gitignore syntax
+const IGNORE_FILE_NAME: &str = ".cleftlyignore";
+
+// Directories that are never part of a music library (NAS thumbnails, trash folders, etc.)
+const DEFAULT_EXCLUDES: &[&str] = &[
+    "@eaDir",
+    "#recycle",
+    "#snapshot",
+    ".Trash-*",
+    ".Trashes",
+    "$RECYCLE.BIN",
+    "System Volume Information",
+    "lost+found",
+    "__MACOSX",
+    ".AppleDouble",
+    ".Spotlight-V100",
+    ".fseventsd",
+];
+
 #[derive(Clone, serde::Serialize)]
-struct ProgressUpdatePayload {
-    id: String,
-    title: String,
-    message: Option<String>,
-    progress: Option<f64>,
+pub(crate) struct ProgressUpdatePayload {
+    pub(crate) id: String,
+    pub(crate) title: String,
+    pub(crate) message: Option<String>,
+    pub(crate) progress: Option<f64>,
 }
 
 #[derive(Debug)]
@@ -33,12 +65,26 @@ struct AlbumArt {
     mime_type: String,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 struct Metadata {
     title: String,
     artist: String,
     album_artist: String,
     album: String,
+    // From the TITLESORT, ARTISTSORT, ALBUMARTISTSORT and ALBUMSORT tags
+    title_sort: Option<String>,
+    artist_sort: Option<String>,
+    album_artist_sort: Option<String>,
+    album_sort: Option<String>,
+    // Classical music
+    composer: Option<String>,
+    composer_sort: Option<String>,
+    conductor: Option<String>,
+    performer: Option<String>,
+    work: Option<String>,
+    movement: Option<String>,
+    movement_num: Option<u32>,
+    movement_total: Option<u32>,
     album_art: Option<AlbumArt>,
     duration: u64,
     genres: Vec<String>,
@@ -47,64 +93,221 @@ struct Metadata {
     disc_num: u32,
     total_discs: u32,
     year: Option<u32>,
+    release_date: Option<String>,
+    original_date: Option<String>,
+    bpm: Option<f64>,
+    key: Option<Key>,
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Track {
-    id: String,
-    location: String,
-    r#type: Option<String>,
-    title: String,
-    artist_id: String,
-    album_id: String,
-    album_art: Option<String>,
-    animated_album_art: Option<String>,
-    genres: Vec<String>,
-    duration: u64,
-    track_num: u32,
-    total_tracks: u32,
-    disc_num: u32,
-    total_discs: u32,
+    pub(crate) id: String,
+    pub(crate) location: String,
+    pub(crate) r#type: Option<String>,
+    pub(crate) title: String,
+    /// The TITLESORT tag, or the title with a leading article moved to the end
+    #[serde(default)]
+    pub(crate) sort_title: String,
+    #[serde(default)]
+    pub(crate) tagged_sort_title: Option<String>,
+    pub(crate) artist_id: String,
+    pub(crate) album_id: String,
+    pub(crate) album_art: Option<String>,
+    pub(crate) animated_album_art: Option<String>,
+    pub(crate) genres: Vec<String>,
+    pub(crate) duration: u64,
+    pub(crate) track_num: u32,
+    pub(crate) total_tracks: u32,
+    pub(crate) disc_num: u32,
+    pub(crate) total_discs: u32,
     #[serde(with = "time::serde::rfc3339")]
-    created_at: OffsetDateTime,
-    #[serde(with = "time::serde::rfc3339")]
-    last_played_at: OffsetDateTime, // TODO: Make optional
+    pub(crate) created_at: OffsetDateTime,
+    // Derived from the listening history
+    /// Unset for tracks that were never played
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub(crate) last_played_at: Option<OffsetDateTime>,
+    #[serde(default)]
+    pub(crate) play_count: u32,
+    #[serde(default)]
+    pub(crate) skip_count: u32,
+    /// 1 to 5 stars
+    #[serde(default)]
+    pub(crate) rating: Option<u8>,
+    /// The track's music directory is currently unavailable (e.g. an unplugged drive)
+    #[serde(default)]
+    pub(crate) offline: bool,
+    /// Hidden from the library, e.g. a duplicate the user chose not to keep
+    #[serde(default)]
+    pub(crate) hidden: bool,
+    // Audio properties, missing for tracks scanned by older versions
+    #[serde(default)]
+    pub(crate) bitrate: Option<u32>,
+    #[serde(default)]
+    pub(crate) sample_rate: Option<u32>,
+    #[serde(default)]
+    pub(crate) bit_depth: Option<u8>,
+    #[serde(default)]
+    pub(crate) file_size: Option<u64>,
+    /// Compressed Chromaprint fingerprint, filled in by the background analysis
+    #[serde(default)]
+    pub(crate) fingerprint: Option<String>,
+    #[serde(default)]
+    pub(crate) loudness: Option<Loudness>,
+    /// From the BPM tag, or estimated by the background analysis
+    #[serde(default)]
+    pub(crate) bpm: Option<f64>,
+    /// Standard notation, e.g. "Am" or "F#"
+    #[serde(default)]
+    pub(crate) key: Option<String>,
+    /// The key on the Camelot wheel, e.g. "8A"
+    #[serde(default)]
+    pub(crate) camelot: Option<String>,
+    // Classical music, from the COMPOSER, CONDUCTOR, PERFORMER, WORK and MOVEMENT* tags
+    #[serde(default)]
+    pub(crate) composer_id: Option<String>,
+    #[serde(default)]
+    pub(crate) conductor: Option<String>,
+    #[serde(default)]
+    pub(crate) performer: Option<String>,
+    #[serde(default)]
+    pub(crate) work: Option<String>,
+    #[serde(default)]
+    pub(crate) movement: Option<String>,
+    #[serde(default)]
+    pub(crate) movement_num: Option<u32>,
+    #[serde(default)]
+    pub(crate) movement_total: Option<u32>,
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Album {
-    id: String,
-    name: String,
-    genres: Vec<String>,
-    artist_id: String,
-    album_art: Option<String>,
-    animated_album_art: Option<String>,
+    pub(crate) id: String,
+    pub(crate) name: String,
+    /// The ALBUMSORT tag, or the name with a leading article moved to the end
+    #[serde(default)]
+    pub(crate) sort_name: String,
+    #[serde(default)]
+    pub(crate) tagged_sort_name: Option<String>,
+    pub(crate) genres: Vec<String>,
+    pub(crate) artist_id: String,
+    pub(crate) album_art: Option<String>,
+    pub(crate) animated_album_art: Option<String>,
+    /// Colors of the album art
+    #[serde(default)]
+    pub(crate) palette: Option<Palette>,
+    /// BlurHash of the album art, shown while it loads
+    #[serde(default)]
+    pub(crate) blurhash: Option<String>,
     #[serde(with = "time::serde::rfc3339")]
-    created_at: OffsetDateTime,
-    year: Option<u32>,
+    pub(crate) created_at: OffsetDateTime,
+    pub(crate) year: Option<u32>,
+    /// Partial ISO 8601 dates, "1969", "1969-09" or "1969-09-26"
+    #[serde(default)]
+    pub(crate) release_date: Option<String>,
+    /// First release of a reissue or remaster
+    #[serde(default)]
+    pub(crate) original_date: Option<String>,
+    #[serde(default)]
+    pub(crate) loudness: Option<Loudness>,
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Artist {
-    id: String,
-    name: String,
-    genres: Vec<String>,
+    pub(crate) id: String,
+    pub(crate) name: String,
+    /// The ALBUMARTISTSORT or ARTISTSORT tag, or the name with a leading article moved to the end
+    #[serde(default)]
+    pub(crate) sort_name: String,
+    #[serde(default)]
+    pub(crate) tagged_sort_name: Option<String>,
+    pub(crate) genres: Vec<String>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub(crate) created_at: OffsetDateTime,
+}
+
+#[derive(serde::Deserialize, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ScanOptions {
+    /// Glob patterns to exclude from scans. Patterns containing a path separator
+    /// are matched against the full path, others against the file or directory name.
+    exclude_globs: Vec<String>,
+    /// Descend into hidden directories
+    include_hidden: bool,
+    /// Follow symlinked files and directories
+    follow_symlinks: bool,
+    /// Descend into directories that are on a different filesystem than the music directory
+    cross_filesystems: bool,
+    /// Maximum number of directory levels to descend below a music directory
+    max_depth: Option<usize>,
+    /// Leading articles ignored when sorting names without sort tags, e.g. "The"
+    sort_articles: Vec<String>,
+    /// Where the dates new tracks were added come from
+    date_added: DateAddedPolicy,
+}
+
+#[derive(serde::Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum DateAddedPolicy {
+    /// When the file was created, or last modified if the filesystem doesn't keep track
+    #[default]
+    Created,
+    /// When the file was last modified
+    Modified,
+    /// When the file was first scanned
+    Scanned,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions {
+            exclude_globs: vec![],
+            include_hidden: false,
+            follow_symlinks: true,
+            cross_filesystems: true,
+            max_depth: None,
+            sort_articles: DEFAULT_ARTICLES.iter().map(|a| a.to_string()).collect(),
+            date_added: DateAddedPolicy::default(),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Composer {
+    pub(crate) id: String,
+    pub(crate) name: String,
+    /// The COMPOSERSORT tag, or the name with a leading article moved to the end
+    #[serde(default)]
+    pub(crate) sort_name: String,
+    #[serde(default)]
+    pub(crate) tagged_sort_name: Option<String>,
     #[serde(with = "time::serde::rfc3339")]
-    created_at: OffsetDateTime,
+    pub(crate) created_at: OffsetDateTime,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LibraryRoot {
+    pub(crate) path: String,
+    pub(crate) available: bool,
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Library {
-    tracks: Vec<Track>,
-    albums: Vec<Album>,
-    artists: Vec<Artist>,
+    pub(crate) tracks: Vec<Track>,
+    pub(crate) albums: Vec<Album>,
+    pub(crate) artists: Vec<Artist>,
+    #[serde(default)]
+    pub(crate) composers: Vec<Composer>,
+    #[serde(default)]
+    pub(crate) roots: Vec<LibraryRoot>,
 }
 
-fn idify(name: &str) -> String {
+pub(crate) fn idify(name: &str) -> String {
     let reg = Regex::new("[^a-zA-Z0-9 -]").unwrap();
     format!(
         "{:x}",
@@ -112,41 +315,322 @@ fn idify(name: &str) -> String {
     )
 }
 
-fn recurse(path: impl AsRef<Path>) -> Vec<PathBuf> {
-    let Ok(entries) = read_dir(path) else {
+struct IgnoreRules {
+    path_excludes: GlobSet,
+    name_excludes: GlobSet,
+    default_excludes: GlobSet,
+    include_hidden: bool,
+}
+
+impl IgnoreRules {
+    fn new(options: &ScanOptions) -> Result<Self, String> {
+        let mut path_excludes = GlobSetBuilder::new();
+        let mut name_excludes = GlobSetBuilder::new();
+
+        for pattern in &options.exclude_globs {
+            let glob = GlobBuilder::new(pattern)
+                .literal_separator(true)
+                .build()
+                .map_err(|e| format!("Invalid exclude glob \"{}\": {}", pattern, e))?;
+
+            if pattern.contains('/') || pattern.contains(std::path::MAIN_SEPARATOR) {
+                path_excludes.add(glob);
+            } else {
+                name_excludes.add(glob);
+            }
+        }
+
+        let mut default_excludes = GlobSetBuilder::new();
+
+        for pattern in DEFAULT_EXCLUDES {
+            default_excludes.add(
+                GlobBuilder::new(pattern)
+                    .case_insensitive(true)
+                    .build()
+                    .map_err(|e| e.to_string())?,
+            );
+        }
+
+        Ok(IgnoreRules {
+            path_excludes: path_excludes.build().map_err(|e| e.to_string())?,
+            name_excludes: name_excludes.build().map_err(|e| e.to_string())?,
+            default_excludes: default_excludes.build().map_err(|e| e.to_string())?,
+            include_hidden: options.include_hidden,
+        })
+    }
+
+    /// Check the global rules and every `.cleftlyignore` between the music directory and `path`
+    fn is_excluded(&self, path: &Path, is_dir: bool, ignore_files: &[Rc<Gitignore>]) -> bool {
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            return true;
+        };
+
+        // AppleDouble resource forks
+        if name.starts_with("._") {
+            return true;
+        }
+
+        if is_dir {
+            if !self.include_hidden && name.starts_with('.') {
+                return true;
+            }
+
+            if self.default_excludes.is_match(name) {
+                return true;
+            }
+        }
+
+        if self.name_excludes.is_match(name) || self.path_excludes.is_match(path) {
+            return true;
+        }
+
+        // Deeper ignore files take precedence, so a whitelist (!pattern) can undo a parent rule
+        for ignore_file in ignore_files.iter().rev() {
+            let matched = ignore_file.matched(path, is_dir);
+
+            if matched.is_ignore() {
+                return true;
+            }
+
+            if matched.is_whitelist() {
+                return false;
+            }
+        }
+
+        false
+    }
+}
+
+fn load_ignore_file(dir: &Path) -> Option<Gitignore> {
+    let ignore_path = dir.join(IGNORE_FILE_NAME);
+
+    if !ignore_path.is_file() {
+        return None;
+    }
+
+    let mut builder = GitignoreBuilder::new(dir);
+
+    if let Some(err) = builder.add(&ignore_path) {
+        warn!("Failed to parse {}: {}", ignore_path.display(), err);
+    }
+
+    match builder.build() {
+        Ok(ignore_file) => Some(ignore_file),
+        Err(err) => {
+            warn!("Failed to parse {}: {}", ignore_path.display(), err);
+            None
+        }
+    }
+}
+
+// Identifies a directory regardless of the path (or symlink) it was reached through
+#[cfg(unix)]
+type DirKey = (u64, u64);
+
+#[cfg(not(unix))]
+type DirKey = PathBuf;
+
+#[cfg(unix)]
+fn dir_key(_path: &Path, meta: &FsMetadata) -> Option<DirKey> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some((meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+fn dir_key(path: &Path, _meta: &FsMetadata) -> Option<DirKey> {
+    std::fs::canonicalize(path).ok()
+}
+
+#[cfg(unix)]
+fn device_id(meta: &FsMetadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some(meta.dev())
+}
+
+// TODO: Volume serial numbers on Windows
+#[cfg(not(unix))]
+fn device_id(_meta: &FsMetadata) -> Option<u64> {
+    None
+}
+
+fn recurse(root: impl AsRef<Path>, options: &ScanOptions, rules: &IgnoreRules) -> Vec<PathBuf> {
+    let root = root.as_ref();
+
+    let Ok(root_meta) = std::fs::metadata(root) else {
         return vec![];
     };
 
-    entries
-        .flatten()
-        .flat_map(|entry| {
+    let root_device = device_id(&root_meta);
+    let mut visited: HashSet<DirKey> = HashSet::new();
+    let mut files = vec![];
+
+    if let Some(key) = dir_key(root, &root_meta) {
+        visited.insert(key);
+    }
+
+    // (directory, depth below root, ignore files that apply to it)
+    let mut stack: Vec<(PathBuf, usize, Vec<Rc<Gitignore>>)> =
+        vec![(root.to_path_buf(), 0, vec![])];
+
+    while let Some((dir, depth, mut ignore_files)) = stack.pop() {
+        let Ok(entries) = read_dir(&dir) else {
+            continue;
+        };
+
+        if let Some(ignore_file) = load_ignore_file(&dir) {
+            ignore_files.push(Rc::new(ignore_file));
+        }
+
+        for entry in entries.flatten() {
+            // The path under the music directory, also below a symlink, so ignore rules and
+            // exclude globs match where the file is in the library rather than where it points
+            let path = entry.path();
+
+            // Does not follow symlinks
             let Ok(meta) = entry.metadata() else {
-                return vec![];
+                continue;
+            };
+
+            let meta = if meta.is_symlink() {
+                if !options.follow_symlinks {
+                    continue;
+                }
+
+                match std::fs::metadata(&path) {
+                    Ok(meta) => meta,
+                    Err(_) => {
+                        debug!("Skipping broken symlink {}", path.display());
+                        continue;
+                    }
+                }
+            } else {
+                meta
             };
 
             if meta.is_dir() {
-                return recurse(entry.path());
-            }
+                if rules.is_excluded(&path, true, &ignore_files) {
+                    debug!("Skipping excluded directory {}", path.display());
+                    continue;
+                }
 
-            if meta.is_file() {
-                return vec![entry.path()];
-            }
+                if options.max_depth.map_or(false, |max| depth + 1 > max) {
+                    debug!("Skipping {}, maximum depth reached", path.display());
+                    continue;
+                }
 
-            if meta.is_symlink() {
-                let Ok(resolved_path) = std::fs::read_link(entry.path()) else {
-                    return vec![];
-                };
+                if !options.cross_filesystems && device_id(&meta) != root_device {
+                    debug!("Skipping {}, on a different filesystem", path.display());
+                    continue;
+                }
+
+                if let Some(key) = dir_key(&path, &meta) {
+                    if !visited.insert(key) {
+                        warn!(
+                            "Skipping {}, directory already scanned (symlink loop?)",
+                            path.display()
+                        );
+                        continue;
+                    }
+                }
 
-                if resolved_path.is_dir() {
-                    return recurse(resolved_path);
+                stack.push((path, depth + 1, ignore_files.clone()));
+            } else if meta.is_file() {
+                if rules.is_excluded(&path, false, &ignore_files) {
+                    continue;
                 }
 
-                return vec![resolved_path];
+                files.push(path);
             }
+        }
+    }
 
-            vec![]
-        })
-        .collect()
+    files
+}
+
+/// A music directory is unavailable if it is missing or can't be read, e.g. an unmounted drive or
+/// network share. An empty directory is available, its tracks were deleted.
+fn is_root_available(root: &Path) -> bool {
+    read_dir(root).is_ok()
+}
+
+/// Disc number of a disc subfolder of an album, e.g. "CD1", "Disc 2" or "Disk_03 - Bonus"
+fn disc_folder_number(dir: &Path) -> Option<u32> {
+    let reg = Regex::new(r"(?i)^(?:cd|dis[ck])[\s._-]*(\d+)(?:\s*[-:(\[].*)?$").unwrap();
+    let name = dir.file_name()?.to_str()?;
+
+    reg.captures(name.trim())?.get(1)?.as_str().parse().ok()
+}
+
+/// Highest disc number of the disc subfolders of `album_dir`
+fn disc_folder_count(album_dir: &Path) -> Option<u32> {
+    read_dir(album_dir)
+        .ok()?
+        .flatten()
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| disc_folder_number(&entry.path()))
+        .max()
+}
+
+/// The album folder of `file`, skipping a disc subfolder
+pub(crate) fn album_dir(file: &Path) -> Option<&Path> {
+    let parent = file.parent()?;
+
+    match disc_folder_number(parent) {
+        Some(_) => parent.parent(),
+        None => Some(parent),
+    }
+}
+
+fn text_tag(tag: &Tag, key: &lofty::ItemKey) -> Option<String> {
+    tag.get_string(key)
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+/// Normalize a date to a partial ISO 8601 date, e.g. "1969", "1969-09" or "1969-09-26".
+/// Times are dropped, and "/" or "." separators are accepted.
+fn parse_date(value: &str) -> Option<String> {
+    let date = value.trim().split(['T', ' ']).next()?;
+    let mut parts = date.split(['-', '/', '.']);
+
+    let year = parts
+        .next()
+        .filter(|year| year.len() == 4 && year.bytes().all(|b| b.is_ascii_digit()))
+        .filter(|year| *year != "0000")?;
+
+    let mut normalized = year.to_string();
+
+    for max in [12, 31] {
+        let Some(part) = parts.next() else {
+            break;
+        };
+
+        match part.parse::<u32>() {
+            Ok(value) if (1..=max).contains(&value) => {
+                normalized.push_str(&format!("-{:02}", value));
+            }
+            _ => break,
+        }
+    }
+
+    Some(normalized)
+}
+
+/// A number tag, "2" or "2/4" style
+fn number_tag(tag: &Tag, key: &lofty::ItemKey) -> Option<u32> {
+    tag.get_string(key)?
+        .split('/')
+        .next()?
+        .trim()
+        .parse()
+        .ok()
+        .filter(|number| *number > 0)
+}
+
+fn date_tag(tag: &Tag, key: &lofty::ItemKey) -> Option<String> {
+    tag.get_string(key).and_then(parse_date)
 }
 
 fn parse_metadata_tags(
@@ -156,16 +640,19 @@ fn parse_metadata_tags(
 ) -> Result<Metadata, String> {
     let fallback_title = file.file_stem().unwrap().to_str().unwrap().to_string();
 
-    let fallback_album = file
-        .parent()
-        .and_then(|parent| parent.file_stem())
+    let fallback_disc = file.parent().and_then(disc_folder_number);
+    let fallback_total_discs = fallback_disc
+        .and_then(|_| album_dir(&file))
+        .and_then(disc_folder_count);
+
+    let fallback_album = album_dir(&file)
+        .and_then(|dir| dir.file_stem())
         .and_then(|stem| stem.to_str())
         .unwrap_or("Unknown Album")
         .to_string();
 
-    let fallback_artist = file
-        .parent()
-        .and_then(|parent| parent.parent())
+    let fallback_artist = album_dir(&file)
+        .and_then(|dir| dir.parent())
         .and_then(|parent| parent.file_stem())
         .and_then(|stem| stem.to_str())
         .unwrap_or("Unknown Artist")
@@ -173,6 +660,10 @@ fn parse_metadata_tags(
 
     match tag {
         Some(tag) => {
+            // TDRL / RELEASEDATE, or the recording date (TDRC / DATE) most files only have
+            let release_date = date_tag(tag, &lofty::ItemKey::ReleaseDate)
+                .or_else(|| date_tag(tag, &lofty::ItemKey::RecordingDate));
+
             let album_artist = if let Some(artist) = tag.artist().as_deref() {
                 artist.to_string()
             } else {
@@ -199,6 +690,28 @@ fn parse_metadata_tags(
                     .as_deref()
                     .unwrap_or(&fallback_album)
                     .to_string(),
+                title_sort: text_tag(tag, &lofty::ItemKey::TrackTitleSortOrder),
+                artist_sort: text_tag(tag, &lofty::ItemKey::TrackArtistSortOrder),
+                // The album artist falls back to the artist, so does its sort tag
+                album_artist_sort: match tag.get_string(&lofty::ItemKey::AlbumArtist) {
+                    Some(_) => text_tag(tag, &lofty::ItemKey::AlbumArtistSortOrder),
+                    None => text_tag(tag, &lofty::ItemKey::AlbumArtistSortOrder)
+                        .or_else(|| text_tag(tag, &lofty::ItemKey::TrackArtistSortOrder)),
+                },
+                album_sort: text_tag(tag, &lofty::ItemKey::AlbumTitleSortOrder),
+                composer: text_tag(tag, &lofty::ItemKey::Composer),
+                composer_sort: text_tag(tag, &lofty::ItemKey::ComposerSortOrder),
+                conductor: text_tag(tag, &lofty::ItemKey::Conductor),
+                performer: text_tag(tag, &lofty::ItemKey::Performer),
+                // iTunes writes the work to TIT1 in ID3, other formats have a WORK field
+                work: text_tag(tag, &lofty::ItemKey::Work).or_else(|| {
+                    (tag.tag_type() == lofty::TagType::Id3v2)
+                        .then(|| text_tag(tag, &lofty::ItemKey::ContentGroup))
+                        .flatten()
+                }),
+                movement: text_tag(tag, &lofty::ItemKey::Movement),
+                movement_num: number_tag(tag, &lofty::ItemKey::MovementNumber),
+                movement_total: number_tag(tag, &lofty::ItemKey::MovementTotal),
                 album_art: tag.pictures().first().and_then(|v| {
                     Some(AlbumArt {
                         mime_type: v
@@ -217,9 +730,22 @@ fn parse_metadata_tags(
                     .unwrap_or_default(),
                 track_num: tag.track().unwrap_or(1),
                 total_tracks: tag.track_total().unwrap_or(1),
-                disc_num: tag.disk().unwrap_or(1),
-                total_discs: tag.disk_total().unwrap_or(1),
-                year: tag.year(),
+                disc_num: tag.disk().or(fallback_disc).unwrap_or(1),
+                total_discs: tag.disk_total().or(fallback_total_discs).unwrap_or(1),
+                year: release_date
+                    .as_deref()
+                    .and_then(|date| date[..4].parse().ok())
+                    .or(tag.year()),
+                release_date,
+                original_date: date_tag(tag, &lofty::ItemKey::OriginalReleaseDate),
+                bpm: tag
+                    .get_string(&lofty::ItemKey::IntegerBpm)
+                    .or_else(|| tag.get_string(&lofty::ItemKey::Bpm))
+                    .and_then(|bpm| bpm.trim().parse::<f64>().ok())
+                    .filter(|bpm| *bpm > 0.0),
+                key: tag
+                    .get_string(&lofty::ItemKey::InitialKey)
+                    .and_then(Key::parse),
             })
         }
 
@@ -228,14 +754,30 @@ fn parse_metadata_tags(
             artist: fallback_artist.clone(),
             album_artist: fallback_artist,
             album: fallback_album,
+            title_sort: None,
+            artist_sort: None,
+            album_artist_sort: None,
+            album_sort: None,
+            composer: None,
+            composer_sort: None,
+            conductor: None,
+            performer: None,
+            work: None,
+            movement: None,
+            movement_num: None,
+            movement_total: None,
             album_art: None,
             duration: tagged_file.properties().duration().as_secs(),
             genres: vec![],
             track_num: 1,
             total_tracks: 1,
-            disc_num: 1,
-            total_discs: 1,
+            disc_num: fallback_disc.unwrap_or(1),
+            total_discs: fallback_total_discs.unwrap_or(1),
             year: None,
+            release_date: None,
+            original_date: None,
+            bpm: None,
+            key: None,
         }),
     }
 }
@@ -269,35 +811,547 @@ fn get_or_add_album_art(
     Ok(path)
 }
 
+/// Check if `path` is named `name` (case-insensitive) with one of `extensions`
+fn is_named_file(path: &Path, name: &str, extensions: &[&str]) -> bool {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_lowercase());
+
+    stem.as_deref() == Some(name)
+        && extensions.iter().any(|ext| {
+            path.extension()
+                .unwrap_or(OsStr::new(""))
+                .to_ascii_lowercase()
+                .to_string_lossy()
+                == *ext
+        })
+}
+
+/// Check if `path` is a `cover.*` or `anim.*` file picked up as album art
+pub(crate) fn is_album_art_file(path: &Path) -> bool {
+    is_named_file(path, "cover", COVER_EXTENSIONS)
+        || is_named_file(path, "anim", ANIM_COVER_EXTENSIONS)
+}
+
+/// Find the static and animated album art to use for the album `file` belongs to
+fn resolve_album_art(
+    app_handle: &AppHandle,
+    album_id: &str,
+    file: &Path,
+    album_art: Option<AlbumArt>,
+    cover_files: &[PathBuf],
+    anim_cover_files: &[PathBuf],
+) -> (Option<String>, Option<String>) {
+    let mut album_art_path: Option<String> = None;
+
+    // Covers next to the file come first, then covers in the album folder above a disc folder
+    let find_cover = |covers: &[PathBuf]| {
+        [file.parent(), album_dir(file)]
+            .into_iter()
+            .find_map(|dir| covers.iter().find(|cover| cover.parent() == dir))
+            .cloned()
+    };
+
+    let cover = find_cover(cover_files);
+
+    if let Some(cover) = cover {
+        album_art_path = Some(cover.to_string_lossy().to_string());
+    } else if let Some(album_art) = album_art {
+        // Otherwise, use the embedded album art if it exists
+        album_art_path = match get_or_add_album_art(
+            app_handle.path().app_cache_dir().unwrap(),
+            album_id.to_string(),
+            album_art,
+        ) {
+            Ok(path) => Some(path.to_str().unwrap_or_default().to_string()),
+            Err(err) => {
+                eprintln!("Failed to get or add album art: {}", err);
+                None
+            }
+        };
+    }
+
+    let anim_album_art_path =
+        find_cover(anim_cover_files).map(|cover_file| cover_file.to_string_lossy().to_string());
+
+    debug!("Album art path: {:?}", album_art_path);
+
+    (album_art_path, anim_album_art_path)
+}
+
+/// When `file` was added to the library according to `policy`
+fn date_added(file: &Path, policy: DateAddedPolicy) -> OffsetDateTime {
+    let now = OffsetDateTime::now_utc();
+    let metadata = std::fs::metadata(file).ok();
+
+    let time = match policy {
+        DateAddedPolicy::Created => {
+            metadata.and_then(|metadata| metadata.created().or_else(|_| metadata.modified()).ok())
+        }
+        DateAddedPolicy::Modified => metadata.and_then(|metadata| metadata.modified().ok()),
+        DateAddedPolicy::Scanned => None,
+    };
+
+    // Dates in the future, e.g. from a wrong clock, are as good as none
+    time.map(OffsetDateTime::from)
+        .filter(|time| *time <= now)
+        .unwrap_or(now)
+}
+
+fn earliest(dates: &mut HashMap<String, OffsetDateTime>, id: &str, date: OffsetDateTime) {
+    let earliest = dates.entry(id.to_string()).or_insert(date);
+    *earliest = (*earliest).min(date);
+}
+
+/// Date the albums, artists and composers `include` returns true for by their earliest track
+pub(crate) fn date_by_tracks(library: &mut Library, include: impl Fn(&str) -> bool) {
+    let mut dates = HashMap::new();
+
+    for track in &library.tracks {
+        earliest(&mut dates, &track.album_id, track.created_at);
+        earliest(&mut dates, &track.artist_id, track.created_at);
+
+        if let Some(composer_id) = &track.composer_id {
+            earliest(&mut dates, composer_id, track.created_at);
+        }
+    }
+
+    // Album artists don't always have tracks of their own
+    for album in &library.albums {
+        if let Some(date) = dates.get(&album.id).copied() {
+            earliest(&mut dates, &album.artist_id, date);
+        }
+    }
+
+    for album in &mut library.albums {
+        if let Some(date) = dates.get(&album.id).filter(|_| include(&album.id)) {
+            album.created_at = *date;
+        }
+    }
+
+    for artist in &mut library.artists {
+        if let Some(date) = dates.get(&artist.id).filter(|_| include(&artist.id)) {
+            artist.created_at = *date;
+        }
+    }
+
+    for composer in &mut library.composers {
+        if let Some(date) = dates.get(&composer.id).filter(|_| include(&composer.id)) {
+            composer.created_at = *date;
+        }
+    }
+}
+
+/// Compute the palette and placeholder of albums with art that don't have them yet
+pub(crate) fn fill_album_art_details(library: &mut Library) {
+    for album in &mut library.albums {
+        let Some(album_art) = album.album_art.as_deref() else {
+            album.palette = None;
+            album.blurhash = None;
+            continue;
+        };
+
+        if album.palette.is_some() && album.blurhash.is_some() {
+            continue;
+        }
+
+        let image = ImageReader::open(art_path(album_art))
+            .map_err(|e| e.to_string())
+            .and_then(|reader| reader.with_guessed_format().map_err(|e| e.to_string()))
+            .and_then(|reader| reader.decode().map_err(|e| e.to_string()));
+
+        match image {
+            Ok(image) => {
+                album.palette = extract_palette(&image);
+                album.blurhash = encode_placeholder(&image);
+            }
+            Err(err) => warn!("Failed to read album art {}: {}", album_art, err),
+        }
+    }
+}
+
+/// Album fields that come from the tags of its tracks
+fn set_album_tags(album: &mut Album, metadata: &Metadata) {
+    album.year = metadata.year;
+    album.release_date = metadata.release_date.clone();
+    album.original_date = metadata.original_date.clone();
+    album.genres = metadata.genres.clone();
+}
+
+/// Read a file's tags, add its artists and album to the library if they don't exist yet and
+/// build its track. The track itself is not added to the library. With `refresh_album_tags`,
+/// an existing album takes the year, dates and genres of the file, for files whose tags changed.
+fn scan_file(
+    app_handle: &AppHandle,
+    library: &mut Library,
+    file: &Path,
+    cover_files: &[PathBuf],
+    anim_cover_files: &[PathBuf],
+    refresh_album_art: bool,
+    refresh_album_tags: bool,
+) -> Result<Track, String> {
+    let tagged_file = lofty::Probe::open(file)
+        .map_err(|e| e.to_string())?
+        .guess_file_type()
+        .map_err(|e| e.to_string())?
+        .read()
+        .map_err(|e| e.to_string())?;
+
+    let metadata =
+        parse_metadata_tags(tagged_file.primary_tag(), file.to_path_buf(), &tagged_file)?;
+
+    let album_artist_id = idify(&metadata.album_artist);
+    let artist_id = idify(&metadata.artist);
+    let album_id = idify(format!("{}-{}", &metadata.album, album_artist_id).as_str());
+    let id = idify(format!("{}-{}-{}", &metadata.title, artist_id, album_id).as_str());
+
+    // Create artist if it doesn't exist in the library
+    if !library
+        .artists
+        .iter()
+        .any(|artist| artist.id == album_artist_id)
+    {
+        library.artists.push(Artist {
+            id: album_artist_id.clone(),
+            sort_name: metadata
+                .album_artist_sort
+                .clone()
+                .unwrap_or_else(|| generate_sort_name(&metadata.album_artist, DEFAULT_ARTICLES)),
+            tagged_sort_name: metadata.album_artist_sort,
+            name: metadata.album_artist,
+            genres: vec![],
+            created_at: OffsetDateTime::now_utc(),
+        });
+    }
+
+    if !library.artists.iter().any(|artist| artist.id == artist_id) {
+        library.artists.push(Artist {
+            id: artist_id.clone(),
+            sort_name: metadata
+                .artist_sort
+                .clone()
+                .unwrap_or_else(|| generate_sort_name(&metadata.artist, DEFAULT_ARTICLES)),
+            tagged_sort_name: metadata.artist_sort,
+            name: metadata.artist,
+            genres: vec![],
+            created_at: OffsetDateTime::now_utc(),
+        });
+    }
+
+    let composer_id = metadata.composer.as_deref().map(idify);
+
+    if let (Some(id), Some(name)) = (&composer_id, &metadata.composer) {
+        if !library.composers.iter().any(|composer| &composer.id == id) {
+            library.composers.push(Composer {
+                id: id.clone(),
+                name: name.clone(),
+                sort_name: metadata
+                    .composer_sort
+                    .clone()
+                    .unwrap_or_else(|| generate_sort_name(name, DEFAULT_ARTICLES)),
+                tagged_sort_name: metadata.composer_sort.clone(),
+                created_at: OffsetDateTime::now_utc(),
+            });
+        }
+    }
+
+    // Create album if it doesn't exist in the library
+    match library.albums.iter_mut().find(|album| album.id == album_id) {
+        Some(album) => {
+            if refresh_album_tags {
+                set_album_tags(album, &metadata);
+            }
+
+            if refresh_album_art {
+                (album.album_art, album.animated_album_art) = resolve_album_art(
+                    app_handle,
+                    &album_id,
+                    file,
+                    metadata.album_art,
+                    cover_files,
+                    anim_cover_files,
+                );
+                album.palette = None;
+                album.blurhash = None;
+            }
+        }
+        None => {
+            let (album_art_path, anim_album_art_path) = resolve_album_art(
+                app_handle,
+                &album_id,
+                file,
+                metadata.album_art,
+                cover_files,
+                anim_cover_files,
+            );
+
+            library.albums.push(Album {
+                id: album_id.clone(),
+                sort_name: metadata
+                    .album_sort
+                    .clone()
+                    .unwrap_or_else(|| generate_sort_name(&metadata.album, DEFAULT_ARTICLES)),
+                tagged_sort_name: metadata.album_sort,
+                name: metadata.album,
+                artist_id: album_artist_id.clone(),
+                genres: metadata.genres.clone(),
+                album_art: album_art_path,
+                animated_album_art: anim_album_art_path,
+                palette: None,
+                blurhash: None,
+                year: metadata.year,
+                release_date: metadata.release_date,
+                original_date: metadata.original_date,
+                created_at: OffsetDateTime::now_utc(),
+                loudness: None,
+            })
+        }
+    }
+
+    let mut track = Track {
+        id,
+        sort_title: metadata
+            .title_sort
+            .clone()
+            .unwrap_or_else(|| generate_sort_name(&metadata.title, DEFAULT_ARTICLES)),
+        tagged_sort_title: metadata.title_sort,
+        title: metadata.title,
+        artist_id,
+        album_id,
+        track_num: metadata.track_num,
+        disc_num: metadata.disc_num,
+        total_discs: metadata.total_discs,
+        album_art: None,
+        animated_album_art: None,
+        duration: metadata.duration,
+        genres: metadata.genres,
+        location: file.to_string_lossy().to_string(),
+        total_tracks: metadata.total_tracks,
+        r#type: Some("local".to_string()),
+        created_at: OffsetDateTime::now_utc(),
+        last_played_at: None,
+        play_count: 0,
+        skip_count: 0,
+        rating: None,
+        offline: false,
+        hidden: false,
+        bitrate: None,
+        sample_rate: None,
+        bit_depth: None,
+        file_size: None,
+        fingerprint: None,
+        loudness: None,
+        bpm: metadata.bpm,
+        key: metadata.key.map(|key| key.name().to_string()),
+        camelot: metadata.key.map(|key| key.camelot()),
+        composer_id,
+        conductor: metadata.conductor,
+        performer: metadata.performer,
+        work: metadata.work,
+        movement: metadata.movement,
+        movement_num: metadata.movement_num,
+        movement_total: metadata.movement_total,
+    };
+
+    set_audio_properties(&mut track, tagged_file.properties(), file);
+
+    Ok(track)
+}
+
+fn set_audio_properties(track: &mut Track, properties: &FileProperties, file: &Path) {
+    track.bitrate = properties.audio_bitrate().or(properties.overall_bitrate());
+    track.sample_rate = properties.sample_rate();
+    track.bit_depth = properties.bit_depth();
+    track.file_size = std::fs::metadata(file).ok().map(|meta| meta.len());
+}
+
+/// Read audio properties for tracks that were scanned before they were stored
+pub(crate) fn load_audio_properties(track: &mut Track) -> Result<(), String> {
+    // The size is always set when the file could be read, the bitrate isn't known for all formats
+    if track.file_size.is_some() {
+        return Ok(());
+    }
+
+    let file = PathBuf::from(&track.location);
+    let tagged_file = lofty::read_from_path(&file).map_err(|e| e.to_string())?;
+
+    set_audio_properties(track, tagged_file.properties(), &file);
+
+    Ok(())
+}
+
+/// Remove albums and artists that no longer have any tracks
+pub(crate) fn prune_library(library: &mut Library) {
+    // Remove all albums with 0 tracks
+    library.albums.retain(|album| {
+        library
+            .tracks
+            .iter()
+            .any(|track| track.album_id == album.id)
+    });
+
+    // Remove all artists with 0 tracks, unless an album has them as album artist
+    library.artists.retain(|artist| {
+        library
+            .tracks
+            .iter()
+            .any(|track| track.artist_id == artist.id)
+            || library
+                .albums
+                .iter()
+                .any(|album| album.artist_id == artist.id)
+    });
+
+    library.composers.retain(|composer| {
+        library
+            .tracks
+            .iter()
+            .any(|track| track.composer_id.as_ref() == Some(&composer.id))
+    });
+}
+
+/// Re-read the tags of files that were changed outside of a library update.
+/// Tracks keep their id and dates, so playlists still point to them.
+pub(crate) fn rescan_tracks(
+    app_handle: &AppHandle,
+    library: &mut Library,
+    files: &[PathBuf],
+    refresh_album_art: bool,
+) {
+    for file in files {
+        // Covers next to the file, or in the album folder above a disc folder
+        let mut dirs = vec![file.parent()];
+        if album_dir(file) != file.parent() {
+            dirs.push(album_dir(file));
+        }
+
+        let siblings: Vec<PathBuf> = dirs
+            .into_iter()
+            .flatten()
+            .filter_map(|dir| read_dir(dir).ok())
+            .flat_map(|entries| entries.flatten().map(|entry| entry.path()))
+            .collect();
+
+        let cover_files: Vec<PathBuf> = siblings
+            .iter()
+            .filter(|path| is_named_file(path, "cover", COVER_EXTENSIONS))
+            .cloned()
+            .collect();
+
+        let anim_cover_files: Vec<PathBuf> = siblings
+            .iter()
+            .filter(|path| is_named_file(path, "anim", ANIM_COVER_EXTENSIONS))
+            .cloned()
+            .collect();
+
+        let mut track = match scan_file(
+            app_handle,
+            library,
+            file,
+            &cover_files,
+            &anim_cover_files,
+            refresh_album_art,
+            true,
+        ) {
+            Ok(track) => track,
+            Err(err) => {
+                warn!("Failed to rescan {}: {}", file.display(), err);
+                continue;
+            }
+        };
+
+        match library
+            .tracks
+            .iter_mut()
+            .find(|existing| existing.location == track.location)
+        {
+            Some(existing) => {
+                track.id = existing.id.clone();
+                track.created_at = existing.created_at;
+                track.last_played_at = existing.last_played_at;
+                track.play_count = existing.play_count;
+                track.skip_count = existing.skip_count;
+                track.rating = existing.rating;
+                track.hidden = existing.hidden;
+                // Editing tags doesn't change the audio
+                track.fingerprint = existing.fingerprint.take();
+                track.loudness = existing.loudness.take();
+
+                // Keep estimated values unless the file is now tagged
+                if track.bpm.is_none() {
+                    track.bpm = existing.bpm;
+                }
+
+                if track.key.is_none() {
+                    track.key = existing.key.take();
+                    track.camelot = existing.camelot.take();
+                }
+                *existing = track;
+            }
+            None => library.tracks.push(track),
+        }
+    }
+
+    apply_overrides(app_handle, library);
+    fill_album_art_details(library);
+    prune_library(library);
+
+    // Sort names were generated with the default articles until now
+    sort_library(library, &configured_articles(app_handle));
+}
+
 #[tauri::command(async)] // Run me in a separate thread
 pub fn update_library(
     app_handle: tauri::AppHandle,
     library: Library,
     music_directories: Vec<String>,
+    scan_options: Option<ScanOptions>,
 ) -> Result<Library, String> {
     if music_directories.is_empty() {
         return Ok(Library {
             tracks: vec![],
             albums: vec![],
             artists: vec![],
+            composers: vec![],
+            roots: vec![],
         });
     }
 
     let mut new_library = library.clone();
 
-    let all_files = music_directories
-        .iter()
-        .map(|dir| recurse(PathBuf::from(dir)))
-        .flatten()
-        .filter(|path| {
-            !path.iter().any(|_f| {
-                path.file_name()
-                    .unwrap()
-                    .to_str()
-                    .unwrap()
-                    .starts_with("._")
-            })
+    let scan_options = scan_options.unwrap_or_default();
+    let rules = IgnoreRules::new(&scan_options)?;
+
+    let mut roots = vec![];
+    let mut found_files = vec![];
+
+    for dir in &music_directories {
+        let root = PathBuf::from(dir);
+        let root_files = recurse(&root, &scan_options, &rules);
+
+        let available = is_root_available(&root);
+
+        if !available {
+            let known_tracks = library
+                .tracks
+                .iter()
+                .filter(|track| Path::new(&track.location).starts_with(&root))
+                .count();
+
+            warn!(
+                "Music directory {} is unavailable, keeping its {} tracks offline",
+                dir, known_tracks
+            );
+        }
+
+        roots.push(LibraryRoot {
+            path: dir.clone(),
+            available,
         });
+        found_files.extend(root_files);
+    }
+
+    let all_files = found_files.into_iter();
 
     let files = all_files.clone().filter(|path| {
         SUPPORTED_EXTENSIONS.iter().any(|ext| {
@@ -316,39 +1370,15 @@ pub fn update_library(
     });
 
     // Files named cover.png/jpg/jpeg/gif
-    let cover_files = all_files.clone().into_iter().filter(|path| {
-        str::to_lowercase(
-            path.with_extension("")
-                .file_name()
-                .unwrap()
-                .to_str()
-                .unwrap(),
-        ) == "cover"
-            && COVER_EXTENSIONS.iter().any(|ext| {
-                path.extension()
-                    .unwrap_or(OsStr::new(""))
-                    .to_ascii_lowercase()
-                    .to_string_lossy()
-                    == ext.to_string()
-            })
-    });
+    let cover_files: Vec<PathBuf> = all_files
+        .clone()
+        .filter(|path| is_named_file(path, "cover", COVER_EXTENSIONS))
+        .collect();
 
-    let anim_cover_files = all_files.clone().into_iter().filter(|path| {
-        str::to_lowercase(
-            path.with_extension("")
-                .file_name()
-                .unwrap()
-                .to_str()
-                .unwrap(),
-        ) == "anim"
-            && ANIM_COVER_EXTENSIONS.iter().any(|ext| {
-                path.extension()
-                    .unwrap_or(OsStr::new(""))
-                    .to_ascii_lowercase()
-                    .to_string_lossy()
-                    == ext.to_string()
-            })
-    });
+    let anim_cover_files: Vec<PathBuf> = all_files
+        .clone()
+        .filter(|path| is_named_file(path, "anim", ANIM_COVER_EXTENSIONS))
+        .collect();
 
     debug!(
         "{} files found, {} new",
@@ -358,6 +1388,20 @@ pub fn update_library(
 
     let mut prev_perc = 0.0;
 
+    // Only what is added now is dated by its tracks, existing dates are kept
+    let known_ids: HashSet<String> = new_library
+        .albums
+        .iter()
+        .map(|album| album.id.clone())
+        .chain(new_library.artists.iter().map(|artist| artist.id.clone()))
+        .chain(
+            new_library
+                .composers
+                .iter()
+                .map(|composer| composer.id.clone()),
+        )
+        .collect();
+
     for (filei, file) in new_files.clone().enumerate() {
         debug!("Scanning {}", file.display());
 
@@ -378,162 +1422,403 @@ pub fn update_library(
             prev_perc = new_perc;
         }
 
-        let tagged_file = lofty::Probe::open(file.clone())
-            .unwrap()
-            .guess_file_type()
-            .unwrap()
-            .read()
-            .unwrap();
+        match scan_file(
+            &app_handle,
+            &mut new_library,
+            &file,
+            &cover_files,
+            &anim_cover_files,
+            false,
+            false,
+        ) {
+            Ok(mut track) => {
+                track.created_at = date_added(&file, scan_options.date_added);
 
-        let metadata = parse_metadata_tags(tagged_file.primary_tag(), file.clone(), &tagged_file);
+                // Create track if it doesn't exist in the library
+                if !new_library
+                    .tracks
+                    .iter()
+                    .any(|t| t.id == track.id && t.album_id == track.album_id)
+                {
+                    new_library.tracks.push(track);
+                }
+            }
+            Err(err) => warn!("Failed to scan {}: {}", file.display(), err),
+        }
+    }
 
-        if let Ok(metadata) = metadata {
-            let album_artist_id = idify(&metadata.album_artist);
-            let artist_id = idify(&metadata.artist);
-            let album_id = idify(format!("{}-{}", &metadata.album, album_artist_id).as_str());
-            let id = idify(format!("{}-{}-{}", &metadata.title, artist_id, album_id).as_str());
+    let found_locations: HashSet<String> = all_files
+        .map(|file| file.to_string_lossy().to_string())
+        .collect();
 
-            // Create artist if it doesn't exist in the library
-            if !new_library
-                .artists
-                .iter()
-                .any(|artist| artist.id == album_artist_id)
-            {
-                new_library.artists.push(Artist {
-                    id: album_artist_id.clone(),
-                    name: metadata.album_artist,
-                    genres: vec![],
-                    created_at: OffsetDateTime::now_utc(),
-                });
-            }
+    // Remove deleted files from tracklist, unless their music directory is just unavailable
+    new_library.tracks.retain_mut(|track| {
+        if found_locations.contains(&track.location) {
+            track.offline = false;
+            return true;
+        }
 
-            if !new_library
-                .artists
-                .iter()
-                .any(|artist| artist.id == artist_id)
-            {
-                new_library.artists.push(Artist {
-                    id: artist_id.clone(),
-                    name: metadata.artist,
-                    genres: vec![],
-                    created_at: OffsetDateTime::now_utc(),
-                });
-            }
+        let location = Path::new(&track.location);
 
-            // Create album if it doesn't exist in the library
-            if !new_library.albums.iter().any(|album| album.id == album_id) {
-                // If cover_files len > 0, use the first file
-                let mut album_art_path: Option<String> = None;
-                let mut anim_album_art_path: Option<String> = None;
+        track.offline = roots
+            .iter()
+            .any(|root| !root.available && location.starts_with(&root.path));
 
-                // Find cover_files wiht same directory
-                let covers = cover_files
-                    .clone()
-                    .filter(|cover_file| cover_file.parent().unwrap() == file.parent().unwrap());
-
-                if covers.clone().count() > 0 {
-                    album_art_path =
-                        Some(covers.clone().next().unwrap().to_str().unwrap().to_string());
-                } else {
-                    // Get album art if it exists
-                    let album_art = metadata.album_art;
-
-                    if let Some(album_art) = album_art {
-                        album_art_path = match get_or_add_album_art(
-                            app_handle.path().app_cache_dir().unwrap(),
-                            album_id.clone(),
-                            album_art,
-                        ) {
-                            Ok(path) => Some(path.to_str().unwrap_or_default().to_string()),
-                            Err(err) => {
-                                eprintln!("Failed to get or add album art: {}", err);
-                                None
-                            }
-                        };
-                    }
-                }
+        track.offline
+    });
 
-                let anim_covers = anim_cover_files
-                    .clone()
-                    .filter(|cover_file| cover_file.parent().unwrap() == file.parent().unwrap());
-
-                if anim_covers.clone().count() > 0 {
-                    anim_album_art_path = Some(
-                        anim_covers
-                            .clone()
-                            .next()
-                            .unwrap()
-                            .to_string_lossy()
-                            .to_string(),
-                    );
-                }
+    // Tracks scanned by older versions don't have audio properties yet
+    for track in new_library.tracks.iter_mut().filter(|track| !track.offline) {
+        if let Err(err) = load_audio_properties(track) {
+            debug!("Failed to read properties of {}: {}", track.location, err);
+        }
+    }
 
-                debug!("Album art path: {:?}", album_art_path);
-
-                new_library.albums.push(Album {
-                    id: album_id.clone(),
-                    name: metadata.album,
-                    artist_id: album_artist_id.clone(),
-                    genres: metadata.genres.clone(),
-                    album_art: album_art_path,
-                    animated_album_art: anim_album_art_path,
-                    year: metadata.year,
-                    created_at: OffsetDateTime::now_utc(),
-                })
-            }
+    date_by_tracks(&mut new_library, |id| !known_ids.contains(id));
 
-            // Create track if it doesn't exist in the library
-            if !new_library
-                .tracks
-                .iter()
-                .any(|track| track.id == id && track.album_id == album_id)
-            {
-                new_library.tracks.push(Track {
-                    id,
-                    title: metadata.title,
-                    artist_id: artist_id,
-                    album_id: album_id,
-                    track_num: metadata.track_num,
-                    disc_num: metadata.disc_num,
-                    total_discs: metadata.total_discs,
-                    album_art: None,
-                    animated_album_art: None,
-                    duration: metadata.duration,
-                    genres: metadata.genres,
-                    location: file.to_str().unwrap().to_string(),
-                    total_tracks: metadata.total_tracks,
-                    r#type: Some("local".to_string()),
-                    created_at: OffsetDateTime::now_utc(),
-                    last_played_at: OffsetDateTime::now_utc(),
-                });
-            }
-        } else {
-            warn!("Failed to parse metadata: {}", file.to_str().unwrap());
+    apply_overrides(&app_handle, &mut new_library);
+    fill_album_art_details(&mut new_library);
+    prune_library(&mut new_library);
+
+    new_library.roots = roots;
+
+    // Last, sort names set by the scan and the overrides use the default articles
+    sort_library(&mut new_library, &scan_options.sort_articles);
+
+    if let Err(err) = apply_play_stats(&app_handle, &mut new_library) {
+        warn!("Failed to read listening history: {}", err);
+    }
+
+    update_search_index(&app_handle, &new_library);
+
+    Ok(new_library)
+}
+
+/// A track with only its required fields set, for tests
+#[cfg(test)]
+pub(crate) fn test_track(id: &str) -> Track {
+    serde_json::from_value(serde_json::json!({
+        "id": id,
+        "location": format!("/music/{}.flac", id),
+        "type": null,
+        "title": id,
+        "artistId": "artist",
+        "albumId": "album",
+        "albumArt": null,
+        "animatedAlbumArt": null,
+        "genres": [],
+        "duration": 180,
+        "trackNum": 1,
+        "totalTracks": 1,
+        "discNum": 1,
+        "totalDiscs": 1,
+        "createdAt": "1970-01-01T00:00:00Z",
+    }))
+    .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn album(year: u32) -> Album {
+        Album {
+            id: "album".to_string(),
+            name: "Abbey Road".to_string(),
+            sort_name: "Abbey Road".to_string(),
+            tagged_sort_name: None,
+            genres: vec!["Rock".to_string()],
+            artist_id: "artist".to_string(),
+            album_art: None,
+            animated_album_art: None,
+            palette: None,
+            blurhash: None,
+            created_at: OffsetDateTime::UNIX_EPOCH,
+            year: Some(year),
+            release_date: Some(format!("{}-09-26", year)),
+            original_date: None,
+            loudness: None,
         }
     }
 
-    // Remove deleted files from tracklist
-    new_library.tracks.retain(|track| {
-        all_files
-            .clone()
-            .any(|file| file.to_str().unwrap() == track.location)
-    });
+    fn artist(id: &str) -> Artist {
+        Artist {
+            id: id.to_string(),
+            name: id.to_string(),
+            sort_name: id.to_string(),
+            tagged_sort_name: None,
+            genres: vec![],
+            created_at: OffsetDateTime::UNIX_EPOCH,
+        }
+    }
 
-    // Remove all albums with 0 tracks
-    new_library.albums.retain(|album| {
-        new_library
-            .tracks
+    #[test]
+    fn prune_library_keeps_album_artists() {
+        let track = test_track("track");
+        let mut empty_album = album(1970);
+        empty_album.id = "empty".to_string();
+        let mut library = Library {
+            tracks: vec![track],
+            albums: vec![
+                Album {
+                    artist_id: "various-artists".to_string(),
+                    ..album(1969)
+                },
+                empty_album,
+            ],
+            artists: vec![
+                artist("artist"),
+                artist("various-artists"),
+                artist("unused"),
+            ],
+            composers: vec![],
+            roots: vec![],
+        };
+
+        prune_library(&mut library);
+
+        let albums: Vec<&str> = library
+            .albums
             .iter()
-            .any(|track| track.album_id == album.id)
-    });
+            .map(|album| album.id.as_str())
+            .collect();
+        let artists: Vec<&str> = library
+            .artists
+            .iter()
+            .map(|artist| artist.id.as_str())
+            .collect();
+        assert_eq!(albums, vec!["album"]);
+        assert_eq!(artists, vec!["artist", "various-artists"]);
+    }
 
-    // Remove all artists with 0 tracks
-    new_library.artists.retain(|artist| {
-        new_library
-            .tracks
+    #[test]
+    fn set_album_tags_after_year_edit() {
+        let mut album = album(1970);
+
+        set_album_tags(
+            &mut album,
+            &Metadata {
+                year: Some(1969),
+                release_date: Some("1969".to_string()),
+                genres: vec!["Pop".to_string(), "Rock".to_string()],
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(album.year, Some(1969));
+        assert_eq!(album.release_date.as_deref(), Some("1969"));
+        assert_eq!(album.original_date, None);
+        assert_eq!(album.genres, vec!["Pop", "Rock"]);
+    }
+
+    #[test]
+    fn set_album_tags_removed_year() {
+        let mut album = album(1969);
+
+        set_album_tags(&mut album, &Metadata::default());
+
+        assert_eq!(album.year, None);
+        assert_eq!(album.release_date, None);
+        assert!(album.genres.is_empty());
+    }
+
+    #[test]
+    fn parse_date_partial() {
+        assert_eq!(parse_date("1969").as_deref(), Some("1969"));
+        assert_eq!(parse_date("1969-09").as_deref(), Some("1969-09"));
+        assert_eq!(parse_date(" 1969-09-26 ").as_deref(), Some("1969-09-26"));
+    }
+
+    #[test]
+    fn parse_date_formats() {
+        assert_eq!(
+            parse_date("1969-09-26T12:00:00Z").as_deref(),
+            Some("1969-09-26")
+        );
+        assert_eq!(
+            parse_date("1969-09-26 12:00:00").as_deref(),
+            Some("1969-09-26")
+        );
+        assert_eq!(parse_date("1969/9/26").as_deref(), Some("1969-09-26"));
+        assert_eq!(parse_date("1969.09.26").as_deref(), Some("1969-09-26"));
+    }
+
+    #[test]
+    fn parse_date_drops_invalid_parts() {
+        assert_eq!(parse_date("1969-13-01").as_deref(), Some("1969"));
+        assert_eq!(parse_date("1969-09-32").as_deref(), Some("1969-09"));
+        assert_eq!(parse_date("1969-00").as_deref(), Some("1969"));
+    }
+
+    #[test]
+    fn parse_date_invalid() {
+        assert_eq!(parse_date(""), None);
+        assert_eq!(parse_date("0000-01-01"), None);
+        assert_eq!(parse_date("69"), None);
+        assert_eq!(parse_date("19690926"), None);
+        assert_eq!(parse_date("Unknown"), None);
+    }
+
+    /// An empty directory for a test, removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("cleftly-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+
+        fn write(&self, path: &str, contents: &str) -> PathBuf {
+            let path = self.0.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, contents).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Files found below `root`, relative to it
+    fn scan(root: &Path, options: &ScanOptions) -> Vec<String> {
+        let rules = IgnoreRules::new(options).unwrap();
+        let mut files: Vec<String> = recurse(root, options, &rules)
             .iter()
-            .any(|track| track.artist_id == artist.id)
-    });
+            .map(|file| {
+                file.strip_prefix(root)
+                    .unwrap()
+                    .to_string_lossy()
+                    .replace('\\', "/")
+            })
+            .collect();
 
-    Ok(new_library)
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn recurse_ignore_files() {
+        let root = TempDir::new("ignore-files");
+        root.write(".cleftlyignore", "*.txt\n/Samples/\n");
+        root.write("Album/.cleftlyignore", "!keep.txt\n");
+        root.write("Album/01 Song.mp3", "");
+        root.write("Album/notes.txt", "");
+        root.write("Album/keep.txt", "");
+        root.write("Album/._01 Song.mp3", "");
+        root.write("Album/Samples/kick.wav", "");
+        root.write("Samples/kick.wav", "");
+        root.write("@eaDir/01 Song.mp3/SYNOFILE_THUMB_M.jpg", "");
+        root.write(".Trash-1000/files/Song.mp3", "");
+        root.write(".hidden/Song.mp3", "");
+
+        assert_eq!(
+            scan(&root.0, &ScanOptions::default()),
+            vec![
+                ".cleftlyignore",
+                "Album/.cleftlyignore",
+                "Album/01 Song.mp3",
+                "Album/Samples/kick.wav",
+                "Album/keep.txt",
+            ]
+        );
+    }
+
+    #[test]
+    fn recurse_exclude_globs() {
+        let root = TempDir::new("exclude-globs");
+        root.write("Album/01 Song.flac", "");
+        root.write("Album/01 Song.cue", "");
+        root.write("Podcasts/Episode.mp3", "");
+        root.write("Album/Podcasts/Song.mp3", "");
+
+        let options = ScanOptions {
+            exclude_globs: vec![
+                "*.cue".to_string(),
+                format!("{}/Podcasts", root.0.to_string_lossy().replace('\\', "/")),
+            ],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            scan(&root.0, &options),
+            vec!["Album/01 Song.flac", "Album/Podcasts/Song.mp3"]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn recurse_ignore_symlinked_dirs() {
+        let root = TempDir::new("ignore-symlinks");
+        let outside = TempDir::new("ignore-symlinks-target");
+        // Rules match the path under the music directory, not where the symlink points
+        root.write(".cleftlyignore", "/Linked/Samples/\n");
+        outside.write("Samples/kick.wav", "");
+        outside.write("Album/Song.mp3", "");
+        outside.write("Album/.cleftlyignore", "*.txt\n");
+        outside.write("Album/notes.txt", "");
+        std::os::unix::fs::symlink(&outside.0, root.0.join("Linked")).unwrap();
+
+        assert_eq!(
+            scan(&root.0, &ScanOptions::default()),
+            vec![
+                ".cleftlyignore",
+                "Linked/Album/.cleftlyignore",
+                "Linked/Album/Song.mp3"
+            ]
+        );
+    }
+
+    #[test]
+    fn recurse_max_depth() {
+        let root = TempDir::new("max-depth");
+        root.write("Song.mp3", "");
+        root.write("Artist/Song.mp3", "");
+        root.write("Artist/Album/Song.mp3", "");
+
+        let options = ScanOptions {
+            max_depth: Some(1),
+            ..Default::default()
+        };
+
+        assert_eq!(scan(&root.0, &options), vec!["Artist/Song.mp3", "Song.mp3"]);
+
+        let options = ScanOptions {
+            max_depth: Some(0),
+            ..Default::default()
+        };
+
+        assert_eq!(scan(&root.0, &options), vec!["Song.mp3"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn recurse_symlinks() {
+        let root = TempDir::new("symlinks");
+        let outside = TempDir::new("symlinks-target");
+        root.write("Album/Song.mp3", "");
+        outside.write("Album/Song.mp3", "");
+        std::os::unix::fs::symlink(&outside.0, root.0.join("Linked")).unwrap();
+        std::os::unix::fs::symlink(outside.0.join("Album/Song.mp3"), root.0.join("Single.mp3"))
+            .unwrap();
+        // Loops are only scanned once
+        std::os::unix::fs::symlink(&root.0, root.0.join("Album/Loop")).unwrap();
+        std::os::unix::fs::symlink(root.0.join("Missing"), root.0.join("Broken")).unwrap();
+
+        assert_eq!(
+            scan(&root.0, &ScanOptions::default()),
+            vec!["Album/Song.mp3", "Linked/Album/Song.mp3", "Single.mp3"]
+        );
+
+        let options = ScanOptions {
+            follow_symlinks: false,
+            ..Default::default()
+        };
+
+        assert_eq!(scan(&root.0, &options), vec!["Album/Song.mp3"]);
+    }
 }
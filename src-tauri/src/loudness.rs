@@ -0,0 +1,109 @@
+/*
+    EBU R128 loudness measurement
+*/
+
+use crate::decode::AudioFormat;
+use ebur128::{EbuR128, Mode};
+
+// ReplayGain 2.0 reference level
+const REFERENCE_LOUDNESS: f64 = -18.0;
+
+// Anything quieter is below the absolute gate, e.g. silent tracks
+const MIN_LOUDNESS: f64 = -70.0;
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Loudness {
+    /// Integrated loudness in LUFS
+    pub(crate) integrated: f64,
+    /// Loudness range in LU
+    pub(crate) range: f64,
+    /// True peak as a linear amplitude, 1.0 is full scale
+    pub(crate) true_peak: f64,
+}
+
+impl Loudness {
+    /// ReplayGain 2.0 gain in dB
+    pub(crate) fn gain(&self) -> f64 {
+        REFERENCE_LOUDNESS - self.integrated
+    }
+}
+
+/// Measures the loudness of decoded audio as it comes in
+#[derive(Default)]
+pub(crate) struct LoudnessMeter {
+    meter: Option<(EbuR128, AudioFormat)>,
+}
+
+impl LoudnessMeter {
+    pub(crate) fn add(&mut self, samples: &[f32], format: AudioFormat) -> Result<(), String> {
+        if self.meter.is_none() {
+            let meter = EbuR128::new(
+                format.channels as u32,
+                format.sample_rate,
+                // The histogram keeps memory use constant for long tracks
+                Mode::I | Mode::LRA | Mode::TRUE_PEAK | Mode::HISTOGRAM,
+            )
+            .map_err(|e| e.to_string())?;
+
+            self.meter = Some((meter, format));
+        }
+
+        let (meter, meter_format) = self.meter.as_mut().unwrap();
+
+        // Files that change format midway are rare, skip the odd packets
+        if meter_format.channels != format.channels
+            || meter_format.sample_rate != format.sample_rate
+        {
+            return Ok(());
+        }
+
+        meter.add_frames_f32(samples).map_err(|e| e.to_string())
+    }
+
+    fn true_peak(&self) -> Result<f64, String> {
+        let Some((meter, format)) = &self.meter else {
+            return Ok(0.0);
+        };
+
+        (0..format.channels as u32).try_fold(0.0, |peak: f64, channel| {
+            Ok(peak.max(meter.true_peak(channel).map_err(|e| e.to_string())?))
+        })
+    }
+
+    pub(crate) fn finish(&self) -> Result<Loudness, String> {
+        let Some((meter, _)) = &self.meter else {
+            return Err("No audio decoded".to_string());
+        };
+
+        Ok(Loudness {
+            integrated: meter
+                .loudness_global()
+                .map_err(|e| e.to_string())?
+                .max(MIN_LOUDNESS),
+            range: meter.loudness_range().map_err(|e| e.to_string())?,
+            true_peak: self.true_peak()?,
+        })
+    }
+}
+
+/// Loudness of an album, measured over all of its tracks as if they were one
+pub(crate) fn album_loudness(meters: &[LoudnessMeter]) -> Result<Loudness, String> {
+    let album_meters = || {
+        meters
+            .iter()
+            .filter_map(|m| m.meter.as_ref().map(|(m, _)| m))
+    };
+
+    let true_peak = meters.iter().try_fold(0.0, |peak: f64, meter| {
+        Ok::<_, String>(peak.max(meter.true_peak()?))
+    })?;
+
+    Ok(Loudness {
+        integrated: EbuR128::loudness_global_multiple(album_meters())
+            .map_err(|e| e.to_string())?
+            .max(MIN_LOUDNESS),
+        range: EbuR128::loudness_range_multiple(album_meters()).map_err(|e| e.to_string())?,
+        true_peak,
+    })
+}
@@ -2,14 +2,41 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 // mod audio;
+mod albumart;
+mod analysis;
+mod backup;
+mod browse;
+mod classical;
+mod dateadded;
+mod decode;
 mod discordrpc;
+mod duplicates;
 mod files;
+mod fingerprint;
+mod history;
+mod key;
 mod library;
+mod loudness;
+mod organize;
+mod overrides;
+mod palette;
+mod placeholder;
+mod search;
+mod smartplaylist;
+mod sortname;
+mod stats;
 mod stream;
+mod tags;
+mod tempo;
+mod waveform;
 mod ytdl;
 // use audio::Audio;
+use analysis::AnalysisState;
 use declarative_discord_rich_presence::DeclarativeDiscordIpcClient;
+use history::HistoryState;
 use http::{header::CONTENT_TYPE, response::Builder as ResponseBuilder, StatusCode};
+use overrides::OverrideState;
+use search::SearchState;
 use std::sync::Mutex;
 use stream::handle_stream_request;
 use tauri::Manager;
@@ -71,6 +98,10 @@ fn main() {
             }
         })
         // .manage(Audio(Mutex::new(Sink::try_new(&stream_handle).unwrap())))
+        .manage(AnalysisState::default())
+        .manage(HistoryState::default())
+        .manage(OverrideState::default())
+        .manage(SearchState::default())
         .plugin(tauri_plugin_persisted_scope::init())
         // .plugin(tauri_plugin_context_menu::init())
         .plugin(
@@ -101,10 +132,33 @@ fn main() {
             // audio::audio_seek,
             // audio::audio_current_time,
             // audio::audio_duration,
+            albumart::set_album_art,
+            analysis::cancel_analysis,
+            analysis::start_analysis,
+            backup::export_library,
+            backup::import_library,
+            backup::inspect_backup,
+            browse::browse_folder,
+            browse::folder_tracks,
+            classical::group_by_work,
+            dateadded::import_date_added,
             discordrpc::clear_activity,
             discordrpc::set_activity,
+            duplicates::find_duplicates,
+            duplicates::resolve_duplicates,
             files::show_in_folder,
+            history::get_top_listening,
+            history::record_play,
             library::update_library,
+            organize::organize_library,
+            overrides::get_overrides,
+            overrides::set_override,
+            search::index_library,
+            search::search_library,
+            smartplaylist::evaluate_smart_playlists,
+            stats::library_stats,
+            tags::write_tags,
+            waveform::get_waveform,
             ytdl::get_ytdl_url,
             ytdl::check_for_ytdl,
         ])
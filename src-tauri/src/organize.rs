@@ -0,0 +1,618 @@
+/*
+    Renaming and moving files based on their metadata
+*/
+
+use crate::library::{is_album_art_file, Library, Track};
+use crate::search::update_search_index;
+use log::{debug, warn};
+use std::collections::{HashMap, HashSet};
+use std::fs::read_dir;
+use std::path::{Path, PathBuf};
+use tauri::AppHandle;
+
+// Files next to a track that share its name and move with it
+const SIDECAR_EXTENSIONS: &[&str] = &["lrc", "cue"];
+
+const ILLEGAL_CHARS: &[char] = &['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+
+// Reserved file names on Windows
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+// Most filesystems limit names to 255 bytes, leave room for the extension
+const MAX_COMPONENT_LEN: usize = 200;
+
+const FIELDS: &[&str] = &[
+    "title",
+    "artist",
+    "albumartist",
+    "album",
+    "year",
+    "track",
+    "totaltracks",
+    "disc",
+    "totaldiscs",
+    "genre",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum PatternPart {
+    Text(String),
+    Field { name: String, width: usize },
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct FileMove {
+    from: String,
+    to: String,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TrackMove {
+    track_id: String,
+    from: String,
+    to: String,
+    /// Lyrics and other sidecar files moved along with the track
+    sidecars: Vec<FileMove>,
+    error: Option<String>,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct OrganizeResult {
+    moves: Vec<TrackMove>,
+    /// Album art files (cover.*, anim.*) moved or copied along with their album
+    album_art: Vec<FileMove>,
+    library: Library,
+}
+
+/// Parse a pattern like `{albumartist}/{year} - {album}/{disc}-{track:02} {title}`
+fn parse_pattern(pattern: &str) -> Result<Vec<PatternPart>, String> {
+    let mut parts = vec![];
+    let mut text = String::new();
+    let mut chars = pattern.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '{' {
+            // Both separators start a new directory
+            text.push(if c == '\\' { '/' } else { c });
+            continue;
+        }
+
+        let mut field = String::new();
+
+        loop {
+            match chars.next() {
+                Some('}') => break,
+                Some(c) => field.push(c),
+                None => return Err(format!("Unclosed {{ in pattern \"{}\"", pattern)),
+            }
+        }
+
+        let (name, spec) = field.split_once(':').unwrap_or((field.as_str(), ""));
+        let name = name.trim().to_lowercase();
+
+        if !FIELDS.contains(&name.as_str()) {
+            return Err(format!(
+                "Unknown field {{{}}}, expected one of: {}",
+                name,
+                FIELDS.join(", ")
+            ));
+        }
+
+        // Zero padding is implied, `{track:02}` and `{track:2}` are the same, `{track:0}` is none
+        let width = if spec.is_empty() {
+            0
+        } else {
+            spec.parse()
+                .map_err(|_| format!("Invalid width \"{}\" for {{{}}}", spec, name))?
+        };
+
+        if !text.is_empty() {
+            parts.push(PatternPart::Text(std::mem::take(&mut text)));
+        }
+
+        parts.push(PatternPart::Field { name, width });
+    }
+
+    if !text.is_empty() {
+        parts.push(PatternPart::Text(text));
+    }
+
+    // Without a field or a name every track would end up at the same path
+    if parts.iter().all(|part| match part {
+        PatternPart::Text(text) => text.chars().all(|c| c == '/' || c.is_whitespace()),
+        PatternPart::Field { .. } => false,
+    }) {
+        return Err("The pattern is empty".to_string());
+    }
+
+    Ok(parts)
+}
+
+fn sanitize_value(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| {
+            if ILLEGAL_CHARS.contains(&c) || c.is_control() {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+fn sanitize_component(component: &str) -> String {
+    let mut component = sanitize_value(component);
+
+    if component.len() > MAX_COMPONENT_LEN {
+        let mut end = MAX_COMPONENT_LEN;
+
+        while !component.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        component.truncate(end);
+    }
+
+    // Windows doesn't allow trailing dots or spaces
+    let component = component.trim().trim_end_matches('.').trim().to_string();
+
+    if component.is_empty() {
+        return "_".to_string();
+    }
+
+    let stem = component.split('.').next().unwrap_or_default();
+
+    if RESERVED_NAMES
+        .iter()
+        .any(|name| name.eq_ignore_ascii_case(stem))
+    {
+        return format!("{}_", component);
+    }
+
+    component
+}
+
+fn field_value(library: &Library, track: &Track, name: &str) -> String {
+    let album = library
+        .albums
+        .iter()
+        .find(|album| album.id == track.album_id);
+    let artist_name = |id: &str| {
+        library
+            .artists
+            .iter()
+            .find(|artist| artist.id == id)
+            .map(|artist| artist.name.clone())
+            .unwrap_or_default()
+    };
+
+    match name {
+        "title" => track.title.clone(),
+        "artist" => artist_name(&track.artist_id),
+        "albumartist" => album
+            .map(|album| artist_name(&album.artist_id))
+            .unwrap_or_else(|| artist_name(&track.artist_id)),
+        "album" => album.map(|album| album.name.clone()).unwrap_or_default(),
+        "year" => album
+            .and_then(|album| album.year)
+            .map(|year| year.to_string())
+            .unwrap_or_default(),
+        "track" => track.track_num.to_string(),
+        "totaltracks" => track.total_tracks.to_string(),
+        "disc" => track.disc_num.to_string(),
+        "totaldiscs" => track.total_discs.to_string(),
+        "genre" => track.genres.first().cloned().unwrap_or_default(),
+        _ => String::new(),
+    }
+}
+
+/// Build the relative path (without extension) for a track
+fn render_pattern(parts: &[PatternPart], library: &Library, track: &Track) -> PathBuf {
+    let mut rendered = String::new();
+
+    for part in parts {
+        match part {
+            PatternPart::Text(text) => rendered.push_str(text),
+            PatternPart::Field { name, width } => {
+                let value = sanitize_value(&field_value(library, track, name));
+                rendered.push_str(&format!("{:0>width$}", value, width = width));
+            }
+        }
+    }
+
+    rendered_path(&rendered, &track.location)
+}
+
+/// Split a rendered pattern into sanitized components, empty directories are skipped and an
+/// empty file name falls back to the track's current one
+fn rendered_path(rendered: &str, location: &str) -> PathBuf {
+    let (directories, stem) = rendered.rsplit_once('/').unwrap_or(("", rendered));
+
+    let stem = if stem.trim().is_empty() {
+        Path::new(location)
+            .file_stem()
+            .map(|stem| sanitize_value(&stem.to_string_lossy()))
+            .unwrap_or_default()
+    } else {
+        stem.to_string()
+    };
+
+    directories
+        .split('/')
+        .filter(|component| !component.trim().is_empty())
+        .chain(std::iter::once(stem.as_str()))
+        .map(sanitize_component)
+        .collect()
+}
+
+fn sidecars(file: &Path) -> Vec<PathBuf> {
+    SIDECAR_EXTENSIONS
+        .iter()
+        .map(|ext| file.with_extension(ext))
+        .filter(|sidecar| sidecar.is_file())
+        .collect()
+}
+
+fn move_file(from: &Path, to: &Path) -> Result<(), String> {
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+
+    if to.exists() {
+        return Err(format!("{} already exists", to.display()));
+    }
+
+    // Renaming fails across filesystems, fall back to copying
+    if std::fs::rename(from, to).is_err() {
+        std::fs::copy(from, to).map_err(|e| e.to_string())?;
+        std::fs::remove_file(from).map_err(|e| e.to_string())?;
+    }
+
+    Ok(())
+}
+
+/// Remove `dir` and its parents while they are empty, stopping at `stop_at`
+fn remove_empty_dirs(dir: &Path, stop_at: &Path) {
+    let mut dir = Some(dir);
+
+    while let Some(current) = dir {
+        if current == stop_at || !current.starts_with(stop_at) {
+            break;
+        }
+
+        // Fails if the directory isn't empty
+        if std::fs::remove_dir(current).is_err() {
+            break;
+        }
+
+        debug!("Removed empty directory {}", current.display());
+        dir = current.parent();
+    }
+}
+
+#[tauri::command(async)]
+pub fn organize_library(
+    app_handle: AppHandle,
+    mut library: Library,
+    pattern: String,
+    destination: String,
+    track_ids: Option<Vec<String>>,
+    dry_run: bool,
+) -> Result<OrganizeResult, String> {
+    let parts = parse_pattern(&pattern)?;
+    let destination = PathBuf::from(destination);
+
+    if !destination.is_dir() {
+        return Err(format!("{} is not a directory", destination.display()));
+    }
+
+    // Files outside every music directory would be dropped from the library on the next update
+    if !library
+        .roots
+        .iter()
+        .any(|root| destination.starts_with(&root.path))
+    {
+        return Err(format!(
+            "{} is not in a music directory",
+            destination.display()
+        ));
+    }
+
+    let mut moves = vec![];
+    let mut targets: HashSet<PathBuf> = HashSet::new();
+    // Source directory -> target directories of the tracks that left it
+    let mut dir_targets: HashMap<PathBuf, HashSet<PathBuf>> = HashMap::new();
+
+    for track in &library.tracks {
+        if track.offline || track.r#type.as_deref() != Some("local") {
+            continue;
+        }
+
+        if let Some(track_ids) = &track_ids {
+            if !track_ids.contains(&track.id) {
+                continue;
+            }
+        }
+
+        let from = PathBuf::from(&track.location);
+        // Appended rather than set, rendered names can contain dots ("01 Mr. Blue")
+        let mut to = destination
+            .join(render_pattern(&parts, &library, track))
+            .into_os_string();
+
+        if let Some(ext) = from.extension() {
+            to.push(".");
+            to.push(ext);
+        }
+
+        let to = PathBuf::from(to);
+
+        if from == to {
+            continue;
+        }
+
+        let mut error = None;
+
+        if !targets.insert(to.clone()) {
+            error = Some("Another track would be moved to the same location".to_string());
+        } else if to.exists() {
+            error = Some(format!("{} already exists", to.display()));
+        }
+
+        if error.is_none() {
+            if let (Some(from_dir), Some(to_dir)) = (from.parent(), to.parent()) {
+                dir_targets
+                    .entry(from_dir.to_path_buf())
+                    .or_default()
+                    .insert(to_dir.to_path_buf());
+            }
+        }
+
+        moves.push(TrackMove {
+            track_id: track.id.clone(),
+            from: from.to_string_lossy().to_string(),
+            to: to.to_string_lossy().to_string(),
+            sidecars: sidecars(&from)
+                .into_iter()
+                .map(|sidecar| FileMove {
+                    to: to
+                        .with_extension(sidecar.extension().unwrap_or_default())
+                        .to_string_lossy()
+                        .to_string(),
+                    from: sidecar.to_string_lossy().to_string(),
+                })
+                .collect(),
+            error,
+        });
+    }
+
+    // Album art follows its tracks. It is moved if they all end up in the same directory and
+    // nothing else in the library is left behind, otherwise it is copied to each new directory.
+    let mut album_art = vec![];
+    let mut copy_album_art = HashSet::new();
+
+    for (from_dir, to_dirs) in &dir_targets {
+        let staying = library.tracks.iter().any(|track| {
+            Path::new(&track.location).parent() == Some(from_dir.as_path())
+                && !moves
+                    .iter()
+                    .any(|m| m.error.is_none() && m.track_id == track.id)
+        });
+
+        let Ok(entries) = read_dir(from_dir) else {
+            continue;
+        };
+
+        for art in entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| is_album_art_file(path))
+        {
+            for to_dir in to_dirs {
+                let to = to_dir.join(art.file_name().unwrap_or_default());
+
+                if to == art || to.exists() {
+                    continue;
+                }
+
+                if staying || to_dirs.len() > 1 {
+                    copy_album_art.insert(to.clone());
+                }
+
+                album_art.push(FileMove {
+                    from: art.to_string_lossy().to_string(),
+                    to: to.to_string_lossy().to_string(),
+                });
+            }
+        }
+    }
+
+    if dry_run {
+        return Ok(OrganizeResult {
+            moves,
+            album_art,
+            library,
+        });
+    }
+
+    // Copy and move album art first, so it's in place before the source directories are removed
+    for art in &album_art {
+        let to = PathBuf::from(&art.to);
+
+        let res = if copy_album_art.contains(&to) {
+            std::fs::create_dir_all(to.parent().unwrap_or(&destination))
+                .and_then(|_| std::fs::copy(&art.from, &to).map(|_| ()))
+                .map_err(|e| e.to_string())
+        } else {
+            move_file(Path::new(&art.from), &to)
+        };
+
+        match res {
+            Ok(()) => {
+                for album in library.albums.iter_mut() {
+                    if album.album_art.as_deref() == Some(art.from.as_str()) {
+                        album.album_art = Some(art.to.clone());
+                    }
+
+                    if album.animated_album_art.as_deref() == Some(art.from.as_str()) {
+                        album.animated_album_art = Some(art.to.clone());
+                    }
+                }
+            }
+            Err(err) => warn!("Failed to move {} to {}: {}", art.from, art.to, err),
+        }
+    }
+
+    for track_move in moves.iter_mut() {
+        if track_move.error.is_some() {
+            continue;
+        }
+
+        let from = PathBuf::from(&track_move.from);
+
+        if let Err(err) = move_file(&from, Path::new(&track_move.to)) {
+            warn!("Failed to move {}: {}", track_move.from, err);
+            track_move.error = Some(err);
+            continue;
+        }
+
+        for sidecar in &track_move.sidecars {
+            if let Err(err) = move_file(Path::new(&sidecar.from), Path::new(&sidecar.to)) {
+                warn!("Failed to move {}: {}", sidecar.from, err);
+            }
+        }
+
+        // Keep the id, so playlists and history still point to the track
+        if let Some(track) = library
+            .tracks
+            .iter_mut()
+            .find(|track| track.id == track_move.track_id)
+        {
+            track.location = track_move.to.clone();
+        }
+
+        // Clean up directories left empty, but never the music directory itself
+        let root = library
+            .roots
+            .iter()
+            .find(|root| from.starts_with(&root.path));
+
+        if let (Some(from_dir), Some(root)) = (from.parent(), root) {
+            remove_empty_dirs(from_dir, Path::new(&root.path));
+        }
+    }
+
+    update_search_index(&app_handle, &library);
+
+    Ok(OrganizeResult {
+        moves,
+        album_art,
+        library,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(text: &str) -> PatternPart {
+        PatternPart::Text(text.to_string())
+    }
+
+    fn field(name: &str, width: usize) -> PatternPart {
+        PatternPart::Field {
+            name: name.to_string(),
+            width,
+        }
+    }
+
+    #[test]
+    fn parse_pattern_fields_and_text() {
+        assert_eq!(
+            parse_pattern("{albumartist}/{year} - {album}/{disc}-{track:02} {title}"),
+            Ok(vec![
+                field("albumartist", 0),
+                text("/"),
+                field("year", 0),
+                text(" - "),
+                field("album", 0),
+                text("/"),
+                field("disc", 0),
+                text("-"),
+                field("track", 2),
+                text(" "),
+                field("title", 0),
+            ])
+        );
+    }
+
+    #[test]
+    fn parse_pattern_widths() {
+        assert_eq!(parse_pattern("{track:2}"), Ok(vec![field("track", 2)]));
+        assert_eq!(parse_pattern("{track:003}"), Ok(vec![field("track", 3)]));
+        assert_eq!(parse_pattern("{track:0}"), Ok(vec![field("track", 0)]));
+        assert_eq!(parse_pattern("{track:00}"), Ok(vec![field("track", 0)]));
+    }
+
+    #[test]
+    fn parse_pattern_normalizes() {
+        // Field names are case insensitive, both separators start a directory
+        assert_eq!(
+            parse_pattern("{ Artist }\\{TITLE}"),
+            Ok(vec![field("artist", 0), text("/"), field("title", 0)])
+        );
+        assert_eq!(parse_pattern("Music"), Ok(vec![text("Music")]));
+    }
+
+    #[test]
+    fn parse_pattern_errors() {
+        assert!(parse_pattern("{artist").is_err());
+        assert!(parse_pattern("{composer}").is_err());
+        assert!(parse_pattern("{track:x}").is_err());
+        assert!(parse_pattern("{track:-1}").is_err());
+    }
+
+    #[test]
+    fn parse_pattern_empty() {
+        assert!(parse_pattern("").is_err());
+        assert!(parse_pattern(" ").is_err());
+        assert!(parse_pattern("/\\/").is_err());
+        assert_eq!(
+            parse_pattern("/{title}"),
+            Ok(vec![text("/"), field("title", 0)])
+        );
+    }
+
+    #[test]
+    fn rendered_path_components() {
+        assert_eq!(
+            rendered_path("Artist/1969 - Album/1-01 Title", "/music/a.flac"),
+            PathBuf::from("Artist/1969 - Album/1-01 Title")
+        );
+        // Directories of missing fields are skipped
+        assert_eq!(
+            rendered_path("/ - Album/Title", "/music/a.flac"),
+            PathBuf::from("- Album/Title")
+        );
+    }
+
+    #[test]
+    fn rendered_path_empty_stem() {
+        assert_eq!(
+            rendered_path("Artist/", "/music/01 Song.flac"),
+            PathBuf::from("Artist/01 Song")
+        );
+        assert_eq!(
+            rendered_path(" ", "/music/01 Song.flac"),
+            PathBuf::from("01 Song")
+        );
+        assert_eq!(rendered_path("", "/music/CON.mp3"), PathBuf::from("CON_"));
+    }
+}
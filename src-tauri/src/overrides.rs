@@ -0,0 +1,518 @@
+/*
+    Manual metadata overrides
+
+    Changes to tracks, albums and artists that are kept outside of the music files, for files
+    that can't or shouldn't be retagged. They are stored in overrides.json in the app's data
+    directory and applied after tags are read, on every scan.
+*/
+
+use crate::backup::art_path;
+use crate::library::{idify, prune_library, rescan_tracks, Album, Artist, Library};
+use crate::search::update_search_index;
+use crate::sortname::{generate_sort_name, DEFAULT_ARTICLES};
+use log::warn;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tauri::{AppHandle, Manager};
+use time::OffsetDateTime;
+
+const OVERRIDES_FILE: &str = "overrides.json";
+/// Longest chain of merged artists that is followed
+const MAX_MERGE_DEPTH: usize = 16;
+
+/// Fields that are `None` are left as they are in the tags
+#[derive(serde::Deserialize, serde::Serialize, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct TrackOverride {
+    title: Option<String>,
+    /// Moves the track to this artist, created if it doesn't exist
+    artist: Option<String>,
+    /// Moves the track to this album by the same album artist, e.g. to split an album
+    album: Option<String>,
+    track_num: Option<u32>,
+    disc_num: Option<u32>,
+    genres: Option<Vec<String>>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AlbumOverride {
+    name: Option<String>,
+    /// The album artist, created if it doesn't exist
+    artist: Option<String>,
+    year: Option<u32>,
+    genres: Option<Vec<String>>,
+    /// Image file used instead of the album art from tags or `cover.*` files
+    album_art: Option<String>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ArtistOverride {
+    name: Option<String>,
+    /// Id of the artist this one is merged into, its tracks and albums are moved there
+    merge_into: Option<String>,
+}
+
+/// Overrides by track, album or artist id
+#[derive(serde::Deserialize, serde::Serialize, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Overrides {
+    tracks: HashMap<String, TrackOverride>,
+    albums: HashMap<String, AlbumOverride>,
+    artists: HashMap<String, ArtistOverride>,
+}
+
+/// A change to one override, `value: None` removes it
+#[derive(serde::Deserialize, Clone)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum OverrideChange {
+    Track {
+        id: String,
+        value: Option<TrackOverride>,
+    },
+    Album {
+        id: String,
+        value: Option<AlbumOverride>,
+    },
+    Artist {
+        id: String,
+        value: Option<ArtistOverride>,
+    },
+}
+
+/// The overrides, loaded on first use
+#[derive(Default)]
+pub struct OverrideState(Mutex<Option<Overrides>>);
+
+fn overrides_path(app_handle: &AppHandle) -> Result<PathBuf, String> {
+    Ok(app_handle
+        .path()
+        .app_data_dir()
+        .map_err(|e| e.to_string())?
+        .join(OVERRIDES_FILE))
+}
+
+fn load(app_handle: &AppHandle) -> Result<Overrides, String> {
+    let path = overrides_path(app_handle)?;
+
+    if !path.exists() {
+        return Ok(Overrides::default());
+    }
+
+    let text = std::fs::read_to_string(&path).map_err(|e| e.to_string())?;
+    serde_json::from_str(&text).map_err(|e| e.to_string())
+}
+
+fn save(app_handle: &AppHandle, overrides: &Overrides) -> Result<(), String> {
+    let path = overrides_path(app_handle)?;
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+
+    let text = serde_json::to_string_pretty(overrides).map_err(|e| e.to_string())?;
+    std::fs::write(path, text).map_err(|e| e.to_string())
+}
+
+/// Run `f` with the loaded overrides
+pub(crate) fn with_overrides<T>(
+    app_handle: &AppHandle,
+    f: impl FnOnce(&mut Overrides) -> Result<T, String>,
+) -> Result<T, String> {
+    let state = app_handle.state::<OverrideState>();
+    let mut overrides = state.0.lock().map_err(|e| e.to_string())?;
+
+    if overrides.is_none() {
+        *overrides = Some(load(app_handle)?);
+    }
+
+    f(overrides.as_mut().unwrap())
+}
+
+impl AlbumOverride {
+    fn is_empty(&self) -> bool {
+        self.name.is_none()
+            && self.artist.is_none()
+            && self.year.is_none()
+            && self.genres.is_none()
+            && self.album_art.is_none()
+    }
+}
+
+impl Overrides {
+    /// Change the paths of album art overrides, e.g. when restoring a backup
+    pub(crate) fn map_album_art(&mut self, f: impl Fn(&str) -> String) {
+        for album in self.albums.values_mut() {
+            if let Some(album_art) = &mut album.album_art {
+                *album_art = f(album_art);
+            }
+        }
+    }
+}
+
+/// Replace all overrides, e.g. when restoring a backup
+pub(crate) fn replace_overrides(
+    app_handle: &AppHandle,
+    new_overrides: Overrides,
+) -> Result<(), String> {
+    with_overrides(app_handle, |overrides| {
+        save(app_handle, &new_overrides)?;
+        *overrides = new_overrides;
+
+        Ok(())
+    })
+}
+
+/// Set or clear only the album art of an album's override, returns the art it replaced
+pub(crate) fn set_album_art_override(
+    app_handle: &AppHandle,
+    album_id: &str,
+    album_art: Option<String>,
+) -> Result<Option<String>, String> {
+    with_overrides(app_handle, |overrides| {
+        let album = overrides.albums.entry(album_id.to_string()).or_default();
+        let old = std::mem::replace(&mut album.album_art, album_art);
+
+        if album.is_empty() {
+            overrides.albums.remove(album_id);
+        }
+
+        save(app_handle, overrides)?;
+
+        Ok(old)
+    })
+}
+
+/// Id of the artist named `name`, added to the library if there isn't one
+fn get_or_add_artist(library: &mut Library, name: &str) -> String {
+    let id = idify(name);
+
+    if !library.artists.iter().any(|artist| artist.id == id) {
+        library.artists.push(Artist {
+            id: id.clone(),
+            name: name.to_string(),
+            sort_name: generate_sort_name(name, DEFAULT_ARTICLES),
+            tagged_sort_name: None,
+            genres: vec![],
+            created_at: OffsetDateTime::now_utc(),
+        });
+    }
+
+    id
+}
+
+/// Follow merges of `id` to the artist it ends up in
+fn merged_artist<'a>(overrides: &'a Overrides, mut id: &'a str) -> &'a str {
+    for _ in 0..MAX_MERGE_DEPTH {
+        match overrides
+            .artists
+            .get(id)
+            .and_then(|artist| artist.merge_into.as_deref())
+        {
+            Some(target) if target != id => id = target,
+            _ => break,
+        }
+    }
+
+    id
+}
+
+fn apply(overrides: &Overrides, library: &mut Library) {
+    for artist in &mut library.artists {
+        if let Some(name) = overrides
+            .artists
+            .get(&artist.id)
+            .and_then(|o| o.name.as_ref())
+        {
+            artist.name = name.clone();
+            artist.sort_name = artist
+                .tagged_sort_name
+                .clone()
+                .unwrap_or_else(|| generate_sort_name(name, DEFAULT_ARTICLES));
+        }
+    }
+
+    // Merges only apply when the artist they are merged into exists
+    let merges: HashMap<String, String> = library
+        .artists
+        .iter()
+        .filter_map(|artist| {
+            let target = merged_artist(overrides, &artist.id);
+
+            (target != artist.id && library.artists.iter().any(|other| other.id == target))
+                .then(|| (artist.id.clone(), target.to_string()))
+        })
+        .collect();
+
+    for track in &mut library.tracks {
+        if let Some(target) = merges.get(&track.artist_id) {
+            track.artist_id = target.clone();
+        }
+    }
+
+    for album in &mut library.albums {
+        if let Some(target) = merges.get(&album.artist_id) {
+            album.artist_id = target.clone();
+        }
+    }
+
+    for (id, album_override) in &overrides.albums {
+        let artist_id = album_override
+            .artist
+            .as_deref()
+            .map(|name| get_or_add_artist(library, name));
+
+        let Some(album) = library.albums.iter_mut().find(|album| &album.id == id) else {
+            continue;
+        };
+
+        if let Some(name) = &album_override.name {
+            album.name = name.clone();
+            album.sort_name = album
+                .tagged_sort_name
+                .clone()
+                .unwrap_or_else(|| generate_sort_name(name, DEFAULT_ARTICLES));
+        }
+
+        if let Some(artist_id) = artist_id {
+            album.artist_id = artist_id;
+        }
+
+        if let Some(year) = album_override.year {
+            let year_text = year.to_string();
+
+            album.year = Some(year);
+
+            // Dates are shown and sorted before the year, keep the month and day only if they
+            // are of the same year
+            if !album
+                .release_date
+                .as_ref()
+                .map_or(false, |date| date.starts_with(&year_text))
+            {
+                album.release_date = Some(year_text);
+            }
+
+            // An original release after the release isn't one
+            if album
+                .original_date
+                .as_ref()
+                .and_then(|date| date.get(..4)?.parse::<u32>().ok())
+                .map_or(false, |original_year| original_year > year)
+            {
+                album.original_date = None;
+            }
+        }
+
+        if let Some(genres) = &album_override.genres {
+            album.genres = genres.clone();
+        }
+
+        // Missing art, e.g. a cache that was cleared, falls back to what the scan found
+        if let Some(album_art) = album_override
+            .album_art
+            .as_ref()
+            .filter(|album_art| Path::new(album_art).exists())
+        {
+            if album.album_art.as_deref().map(art_path).as_ref() != Some(album_art) {
+                album.album_art = Some(album_art.clone());
+                album.palette = None;
+                album.blurhash = None;
+            }
+
+            album.animated_album_art = None;
+        }
+    }
+
+    for (id, track_override) in &overrides.tracks {
+        let artist_id = track_override
+            .artist
+            .as_deref()
+            .map(|name| get_or_add_artist(library, name));
+
+        let Some(index) = library.tracks.iter().position(|track| &track.id == id) else {
+            continue;
+        };
+
+        // Split the track off into an album of its own, based on the album it was in
+        if let Some(name) = &track_override.album {
+            let current = library
+                .albums
+                .iter()
+                .find(|album| album.id == library.tracks[index].album_id)
+                .cloned();
+
+            if let Some(current) = current {
+                let album_id = idify(&format!("{}-{}", name, current.artist_id));
+
+                if !library.albums.iter().any(|album| album.id == album_id) {
+                    library.albums.push(Album {
+                        id: album_id.clone(),
+                        name: name.clone(),
+                        sort_name: generate_sort_name(name, DEFAULT_ARTICLES),
+                        tagged_sort_name: None,
+                        created_at: OffsetDateTime::now_utc(),
+                        ..current
+                    });
+                }
+
+                library.tracks[index].album_id = album_id;
+            }
+        }
+
+        let track = &mut library.tracks[index];
+
+        if let Some(title) = &track_override.title {
+            track.title = title.clone();
+            track.sort_title = track
+                .tagged_sort_title
+                .clone()
+                .unwrap_or_else(|| generate_sort_name(title, DEFAULT_ARTICLES));
+        }
+
+        if let Some(artist_id) = artist_id {
+            track.artist_id = artist_id;
+        }
+
+        if let Some(track_num) = track_override.track_num {
+            track.track_num = track_num;
+        }
+
+        if let Some(disc_num) = track_override.disc_num {
+            track.disc_num = disc_num;
+        }
+
+        if let Some(genres) = &track_override.genres {
+            track.genres = genres.clone();
+        }
+    }
+}
+
+/// Apply the overrides to a freshly scanned library
+pub(crate) fn apply_overrides(app_handle: &AppHandle, library: &mut Library) {
+    if let Err(err) = with_overrides(app_handle, |overrides| {
+        apply(overrides, library);
+        Ok(())
+    }) {
+        warn!("Failed to apply metadata overrides: {}", err);
+    }
+}
+
+#[tauri::command(async)]
+pub fn get_overrides(app_handle: AppHandle) -> Result<Overrides, String> {
+    with_overrides(&app_handle, |overrides| Ok(overrides.clone()))
+}
+
+/// Add, change or remove an override. The affected tracks are read again, so removing an
+/// override brings back what their tags say.
+#[tauri::command(async)]
+pub fn set_override(
+    app_handle: AppHandle,
+    mut library: Library,
+    change: OverrideChange,
+) -> Result<Library, String> {
+    // Tracks of the artist or album before and after the change
+    let mut artist_ids = vec![];
+    let mut album_ids = vec![];
+    let mut track_ids = vec![];
+
+    with_overrides(&app_handle, |overrides| {
+        match change {
+            OverrideChange::Track { id, value } => {
+                track_ids.push(id.clone());
+
+                match value {
+                    Some(value) => overrides.tracks.insert(id, value),
+                    None => overrides.tracks.remove(&id),
+                };
+            }
+            OverrideChange::Album { id, value } => {
+                album_ids.push(id.clone());
+
+                match value {
+                    Some(value) => overrides.albums.insert(id, value),
+                    None => overrides.albums.remove(&id),
+                };
+            }
+            OverrideChange::Artist { id, value } => {
+                artist_ids.push(id.clone());
+
+                let old = match value {
+                    Some(value) => overrides.artists.insert(id, value),
+                    None => overrides.artists.remove(&id),
+                };
+
+                // Tracks merged into another artist point there now
+                artist_ids.extend(old.and_then(|old| old.merge_into));
+            }
+        }
+
+        save(&app_handle, overrides)
+    })?;
+
+    // Scanning only adds albums and artists that don't exist yet, so the affected ones are
+    // taken out to be created from the tags again
+    let (removed_albums, albums): (Vec<Album>, Vec<Album>) = library
+        .albums
+        .into_iter()
+        .partition(|album| album_ids.contains(&album.id) || artist_ids.contains(&album.artist_id));
+    library.albums = albums;
+
+    let (removed_artists, artists): (Vec<Artist>, Vec<Artist>) = library
+        .artists
+        .into_iter()
+        .partition(|artist| artist_ids.contains(&artist.id));
+    library.artists = artists;
+
+    let files: Vec<PathBuf> = library
+        .tracks
+        .iter()
+        .filter(|track| {
+            track_ids.contains(&track.id)
+                || artist_ids.contains(&track.artist_id)
+                || removed_albums
+                    .iter()
+                    .any(|album| album.id == track.album_id)
+        })
+        .map(|track| PathBuf::from(&track.location))
+        .filter(|file| file.exists())
+        .collect();
+
+    // Also applies the overrides
+    rescan_tracks(
+        &app_handle,
+        &mut library,
+        &files,
+        !removed_albums.is_empty(),
+    );
+
+    for old in removed_albums {
+        match library.albums.iter_mut().find(|album| album.id == old.id) {
+            Some(album) => {
+                album.created_at = old.created_at;
+                album.loudness = old.loudness;
+            }
+            // Tracks that couldn't be read again, e.g. offline ones, keep their album
+            None => library.albums.push(old),
+        }
+    }
+
+    for old in removed_artists {
+        match library
+            .artists
+            .iter_mut()
+            .find(|artist| artist.id == old.id)
+        {
+            Some(artist) => artist.created_at = old.created_at,
+            None => library.artists.push(old),
+        }
+    }
+
+    prune_library(&mut library);
+
+    update_search_index(&app_handle, &library);
+
+    Ok(library)
+}
@@ -0,0 +1,141 @@
+/*
+    Color palettes of album art
+
+    Computed once when an album's art is found or changed, so the UI can be tinted to match
+    without decoding images in the webview.
+*/
+
+use image::DynamicImage;
+use std::collections::HashMap;
+
+/// Size the art is scaled down to before counting colors
+const SAMPLE_SIZE: u32 = 64;
+/// Saturation that separates vibrant from muted colors
+const VIBRANT_SATURATION: f32 = 0.35;
+
+/// Colors as `#rrggbb`
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Palette {
+    /// The most common color
+    dominant: String,
+    /// The most common bright, saturated color, or the dominant color if there is none
+    vibrant: String,
+    /// The most common dull color, or the dominant color if there is none
+    muted: String,
+    /// Black or white, whichever is readable on the dominant color
+    text: String,
+}
+
+#[derive(Default)]
+struct Bucket {
+    count: u32,
+    sum: [u32; 3],
+}
+
+impl Bucket {
+    fn color(&self) -> [u8; 3] {
+        self.sum.map(|channel| (channel / self.count) as u8)
+    }
+}
+
+fn hex(color: [u8; 3]) -> String {
+    format!("#{:02x}{:02x}{:02x}", color[0], color[1], color[2])
+}
+
+/// HSL saturation and lightness, from 0 to 1
+fn saturation_lightness(color: [u8; 3]) -> (f32, f32) {
+    let [r, g, b] = color.map(|channel| channel as f32 / 255.0);
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let lightness = (max + min) / 2.0;
+
+    if max == min {
+        return (0.0, lightness);
+    }
+
+    let saturation = (max - min) / (1.0 - (2.0 * lightness - 1.0).abs());
+
+    (saturation, lightness)
+}
+
+/// WCAG relative luminance
+fn luminance(color: [u8; 3]) -> f32 {
+    let [r, g, b] = color.map(|channel| {
+        let channel = channel as f32 / 255.0;
+
+        if channel <= 0.03928 {
+            channel / 12.92
+        } else {
+            ((channel + 0.055) / 1.055).powf(2.4)
+        }
+    });
+
+    0.2126 * r + 0.7152 * g + 0.0722 * b
+}
+
+pub(crate) fn extract_palette(image: &DynamicImage) -> Option<Palette> {
+    let sample = image.thumbnail(SAMPLE_SIZE, SAMPLE_SIZE).to_rgb8();
+
+    // Similar colors are counted together, with 4 bits per channel
+    let mut buckets: HashMap<[u8; 3], Bucket> = HashMap::new();
+
+    for pixel in sample.pixels() {
+        let bucket = buckets
+            .entry(pixel.0.map(|channel| channel >> 4))
+            .or_default();
+
+        bucket.count += 1;
+
+        for (sum, channel) in bucket.sum.iter_mut().zip(pixel.0) {
+            *sum += channel as u32;
+        }
+    }
+
+    let colors: Vec<([u8; 3], u32)> = buckets
+        .values()
+        .map(|bucket| (bucket.color(), bucket.count))
+        .collect();
+
+    // Ties are broken by the color, buckets come in no particular order
+    let dominant = colors
+        .iter()
+        .max_by_key(|(color, count)| (*count, *color))?
+        .0;
+
+    // Weighted by saturation, so a small bright accent can beat a large dull area
+    let vibrant = colors
+        .iter()
+        .filter_map(|(color, count)| {
+            let (saturation, lightness) = saturation_lightness(*color);
+
+            (saturation >= VIBRANT_SATURATION && (0.3..=0.8).contains(&lightness))
+                .then_some((*color, *count as f32 * saturation * saturation))
+        })
+        .max_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0)))
+        .map_or(dominant, |(color, _)| color);
+
+    let muted = colors
+        .iter()
+        .filter(|(color, _)| {
+            let (saturation, lightness) = saturation_lightness(*color);
+
+            saturation < VIBRANT_SATURATION && (0.2..=0.8).contains(&lightness)
+        })
+        .max_by_key(|(color, count)| (*count, *color))
+        .map_or(dominant, |(color, _)| *color);
+
+    // Where black and white have the same contrast
+    let text = if luminance(dominant) > 0.179 {
+        "#000000"
+    } else {
+        "#ffffff"
+    };
+
+    Some(Palette {
+        dominant: hex(dominant),
+        vibrant: hex(vibrant),
+        muted: hex(muted),
+        text: text.to_string(),
+    })
+}
@@ -0,0 +1,32 @@
+/*
+    Tiny placeholders of album art
+
+    BlurHash strings of a few dozen characters, which the frontend paints as a blurred preview
+    while the real art loads.
+*/
+
+use image::DynamicImage;
+use log::warn;
+
+/// Detail of the placeholder, in cosine components along each side
+const COMPONENTS: u32 = 4;
+/// Size the art is scaled down to before encoding, the placeholder is blurry anyway
+const SAMPLE_SIZE: u32 = 32;
+
+pub(crate) fn encode_placeholder(image: &DynamicImage) -> Option<String> {
+    let sample = image.thumbnail(SAMPLE_SIZE, SAMPLE_SIZE).to_rgba8();
+
+    match blurhash::encode(
+        COMPONENTS,
+        COMPONENTS,
+        sample.width(),
+        sample.height(),
+        sample.as_raw(),
+    ) {
+        Ok(hash) => Some(hash),
+        Err(err) => {
+            warn!("Failed to encode album art placeholder: {}", err);
+            None
+        }
+    }
+}
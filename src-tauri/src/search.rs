@@ -0,0 +1,707 @@
+/*
+    Full-text search over the library
+
+    An in-memory inverted index over titles, artists, albums, genres and lyrics, with a trigram
+    index over its terms for typo tolerance. It is kept in sync with the library incrementally,
+    only tracks that changed are reindexed.
+*/
+
+use crate::library::Library;
+use log::debug;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use tauri::{AppHandle, Manager, State};
+use unicode_normalization::UnicodeNormalization;
+
+const DEFAULT_LIMIT: usize = 100;
+
+// Short prefixes match a lot of terms, only the first ones are considered
+const MAX_PREFIX_TERMS: usize = 500;
+
+const EXACT_SCORE: f64 = 1.0;
+const PREFIX_SCORE: f64 = 0.7;
+const FUZZY_SCORE: f64 = 0.5;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Field {
+    Title,
+    Artist,
+    Album,
+    Genre,
+    Lyrics,
+}
+
+// Searched when a query has no field prefix
+const DEFAULT_FIELDS: [Field; 4] = [Field::Title, Field::Artist, Field::Album, Field::Genre];
+
+impl Field {
+    fn parse(name: &str) -> Option<Field> {
+        match name {
+            "title" | "track" => Some(Field::Title),
+            "artist" => Some(Field::Artist),
+            "album" => Some(Field::Album),
+            "genre" => Some(Field::Genre),
+            "lyrics" => Some(Field::Lyrics),
+            _ => None,
+        }
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+
+    fn weight(self) -> f64 {
+        match self {
+            Field::Title => 3.0,
+            Field::Artist => 2.0,
+            Field::Album => 1.5,
+            Field::Genre => 1.0,
+            Field::Lyrics => 0.5,
+        }
+    }
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c as u32,
+        0x1100..=0x11FF // Hangul Jamo
+        | 0x3040..=0x30FF // Hiragana and Katakana
+        | 0x3130..=0x318F // Hangul Compatibility Jamo
+        | 0x3400..=0x4DBF // CJK Extension A
+        | 0x4E00..=0x9FFF // CJK Unified Ideographs
+        | 0xAC00..=0xD7AF // Hangul Syllables
+        | 0xF900..=0xFAFF // CJK Compatibility Ideographs
+        | 0x20000..=0x2FFFF // CJK Extensions B and up
+    )
+}
+
+fn is_combining_mark(c: char) -> bool {
+    matches!(c as u32,
+        0x0300..=0x036F | 0x1AB0..=0x1AFF | 0x1DC0..=0x1DFF | 0x20D0..=0x20FF | 0xFE20..=0xFE2F
+    )
+}
+
+/// Lowercase and strip diacritics, "Beyoncé" and "beyonce" are the same
+pub(crate) fn normalize(text: &str) -> String {
+    let mut normalized = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        // Decomposing kana and Hangul would split them into parts
+        if is_cjk(c) {
+            normalized.push(c);
+            continue;
+        }
+
+        for c in std::iter::once(c).nfkd().filter(|c| !is_combining_mark(*c)) {
+            match c {
+                'ß' => normalized.push_str("ss"),
+                'æ' | 'Æ' => normalized.push_str("ae"),
+                'œ' | 'Œ' => normalized.push_str("oe"),
+                'ø' | 'Ø' => normalized.push('o'),
+                'ł' | 'Ł' => normalized.push('l'),
+                'đ' | 'Đ' => normalized.push('d'),
+                'þ' | 'Þ' => normalized.push_str("th"),
+                _ => normalized.extend(c.to_lowercase()),
+            }
+        }
+    }
+
+    normalized
+}
+
+/// Split text into terms. CJK text has no spaces between words, so runs of CJK characters
+/// are split into overlapping pairs of characters. When indexing, single characters are
+/// added too so one character queries match.
+fn tokenize(text: &str, indexing: bool) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut word = String::new();
+    let mut cjk: Vec<char> = vec![];
+
+    let flush_cjk = |cjk: &mut Vec<char>, tokens: &mut Vec<String>| {
+        if cjk.len() == 1 || indexing {
+            tokens.extend(cjk.iter().map(|c| c.to_string()));
+        }
+
+        tokens.extend(cjk.windows(2).map(|pair| pair.iter().collect::<String>()));
+        cjk.clear();
+    };
+
+    for c in normalize(text).chars() {
+        if is_cjk(c) {
+            if !word.is_empty() {
+                tokens.push(std::mem::take(&mut word));
+            }
+
+            cjk.push(c);
+            continue;
+        }
+
+        if !cjk.is_empty() {
+            flush_cjk(&mut cjk, &mut tokens);
+        }
+
+        if c.is_alphanumeric() {
+            word.push(c);
+        } else if c != '\'' && c != '’' && !word.is_empty() {
+            // "Don't" is one word
+            tokens.push(std::mem::take(&mut word));
+        }
+    }
+
+    if !word.is_empty() {
+        tokens.push(word);
+    }
+
+    flush_cjk(&mut cjk, &mut tokens);
+
+    tokens
+}
+
+fn trigrams(term: &str) -> Vec<String> {
+    let chars: Vec<char> = format!("^{}$", term).chars().collect();
+
+    chars
+        .windows(3)
+        .map(|trigram| trigram.iter().collect())
+        .collect()
+}
+
+/// Optimal string alignment distance, gives up once it's over `max`
+fn edit_distance(a: &[char], b: &[char], max: usize) -> Option<usize> {
+    if a.len().abs_diff(b.len()) > max {
+        return None;
+    }
+
+    let mut previous2: Vec<usize> = vec![0; b.len() + 1];
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for i in 1..=a.len() {
+        let mut current = vec![i; b.len() + 1];
+
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+
+            current[j] = (previous[j] + 1)
+                .min(current[j - 1] + 1)
+                .min(previous[j - 1] + cost);
+
+            // Swapped letters count as one typo
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                current[j] = current[j].min(previous2[j - 2] + 1);
+            }
+        }
+
+        if current.iter().min().map_or(true, |min| *min > max) {
+            return None;
+        }
+
+        previous2 = std::mem::replace(&mut previous, current);
+    }
+
+    Some(previous[b.len()]).filter(|distance| *distance <= max)
+}
+
+/// The .lrc or .txt file next to a track and when it was last modified
+fn lyrics_file(location: &str) -> Option<(PathBuf, Option<SystemTime>)> {
+    let path = Path::new(location);
+
+    ["lrc", "txt"].iter().find_map(|extension| {
+        let file = path.with_extension(extension);
+        let metadata = std::fs::metadata(&file).ok()?;
+
+        Some((file, metadata.modified().ok()))
+    })
+}
+
+fn read_lyrics(file: &Path) -> Option<String> {
+    let text = std::fs::read_to_string(file).ok()?;
+
+    // Drop LRC timestamps and tags like [00:12.34] and [ar:Artist]
+    Some(
+        text.lines()
+            .map(|line| {
+                let mut line = line.trim_start();
+
+                while let Some(rest) = line
+                    .strip_prefix('[')
+                    .and_then(|rest| rest.split_once(']'))
+                    .map(|(_, rest)| rest)
+                {
+                    line = rest.trim_start();
+                }
+
+                line
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+    )
+}
+
+struct Document {
+    track_id: String,
+    /// Hash of the indexed values, to tell if the track changed
+    signature: u64,
+    /// Normalized title, for breaking ties
+    title: String,
+    terms: Vec<String>,
+}
+
+#[derive(Default)]
+pub struct SearchIndex {
+    documents: HashMap<u32, Document>,
+    ids: HashMap<String, u32>,
+    /// Term -> document -> fields it appears in
+    terms: BTreeMap<String, HashMap<u32, u8>>,
+    trigrams: HashMap<String, HashSet<String>>,
+    next_document: u32,
+}
+
+impl SearchIndex {
+    fn add(&mut self, track_id: &str, signature: u64, fields: &[(Field, &str)]) {
+        let document = self.next_document;
+        self.next_document += 1;
+
+        let mut terms: HashMap<String, u8> = HashMap::new();
+
+        for (field, text) in fields {
+            for token in tokenize(text, true) {
+                *terms.entry(token).or_default() |= field.bit();
+            }
+        }
+
+        for (term, mask) in &terms {
+            let postings = self.terms.entry(term.clone()).or_default();
+
+            if postings.is_empty() {
+                for trigram in trigrams(term) {
+                    self.trigrams
+                        .entry(trigram)
+                        .or_default()
+                        .insert(term.clone());
+                }
+            }
+
+            postings.insert(document, *mask);
+        }
+
+        let title = fields
+            .iter()
+            .find(|(field, _)| *field == Field::Title)
+            .map(|(_, title)| normalize(title))
+            .unwrap_or_default();
+
+        self.documents.insert(
+            document,
+            Document {
+                track_id: track_id.to_string(),
+                signature,
+                title,
+                terms: terms.into_keys().collect(),
+            },
+        );
+        self.ids.insert(track_id.to_string(), document);
+    }
+
+    fn remove(&mut self, track_id: &str) {
+        let Some(document) = self.ids.remove(track_id) else {
+            return;
+        };
+
+        let Some(removed) = self.documents.remove(&document) else {
+            return;
+        };
+
+        for term in removed.terms {
+            let Some(postings) = self.terms.get_mut(&term) else {
+                continue;
+            };
+
+            postings.remove(&document);
+
+            if postings.is_empty() {
+                self.terms.remove(&term);
+
+                for trigram in trigrams(&term) {
+                    if let Some(terms) = self.trigrams.get_mut(&trigram) {
+                        terms.remove(&term);
+
+                        if terms.is_empty() {
+                            self.trigrams.remove(&trigram);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reindex tracks that were added or changed and drop tracks that are gone
+    pub(crate) fn sync(&mut self, library: &Library) {
+        let artists: HashMap<&str, &str> = library
+            .artists
+            .iter()
+            .map(|artist| (artist.id.as_str(), artist.name.as_str()))
+            .collect();
+
+        let albums: HashMap<&str, &str> = library
+            .albums
+            .iter()
+            .map(|album| (album.id.as_str(), album.name.as_str()))
+            .collect();
+
+        let mut seen = HashSet::new();
+        let mut reindexed = 0;
+
+        for track in library.tracks.iter().filter(|track| !track.hidden) {
+            let artist = artists.get(track.artist_id.as_str()).copied().unwrap_or("");
+            let album = albums.get(track.album_id.as_str()).copied().unwrap_or("");
+            let genres = track.genres.join(" ");
+            // Lyrics files change without the track changing, e.g. when they are downloaded.
+            // Only their modification time is checked, they are read when the track is reindexed.
+            let lyrics_file = lyrics_file(&track.location);
+
+            let mut hasher = DefaultHasher::new();
+            (
+                &track.title,
+                artist,
+                album,
+                &genres,
+                &track.location,
+                &lyrics_file,
+            )
+                .hash(&mut hasher);
+            let signature = hasher.finish();
+
+            seen.insert(track.id.as_str());
+
+            if let Some(document) = self.ids.get(&track.id) {
+                if self.documents[document].signature == signature {
+                    continue;
+                }
+
+                self.remove(&track.id);
+            }
+
+            let lyrics = lyrics_file
+                .and_then(|(file, _)| read_lyrics(&file))
+                .unwrap_or_default();
+
+            self.add(
+                &track.id,
+                signature,
+                &[
+                    (Field::Title, &track.title),
+                    (Field::Artist, artist),
+                    (Field::Album, album),
+                    (Field::Genre, &genres),
+                    (Field::Lyrics, &lyrics),
+                ],
+            );
+
+            reindexed += 1;
+        }
+
+        let removed: Vec<String> = self
+            .ids
+            .keys()
+            .filter(|id| !seen.contains(id.as_str()))
+            .cloned()
+            .collect();
+
+        for id in &removed {
+            self.remove(id);
+        }
+
+        debug!(
+            "Search index: {} tracks reindexed, {} removed",
+            reindexed,
+            removed.len()
+        );
+    }
+
+    /// Terms matching a query token, with how well they match
+    fn matching_terms(&self, token: &str) -> Vec<(&String, f64)> {
+        let mut matches: HashMap<&String, f64> = HashMap::new();
+
+        for (term, _) in self
+            .terms
+            .range(token.to_string()..)
+            .take_while(|(term, _)| term.starts_with(token))
+            .take(MAX_PREFIX_TERMS)
+        {
+            let score = if term == token {
+                EXACT_SCORE
+            } else {
+                PREFIX_SCORE
+            };
+
+            matches.insert(term, score);
+        }
+
+        let chars: Vec<char> = token.chars().collect();
+
+        // Allow one typo in longer words, two in long ones
+        let max_distance = match chars.len() {
+            0..=3 => 0,
+            4..=7 => 1,
+            _ => 2,
+        };
+
+        if max_distance == 0 || chars.iter().any(|c| is_cjk(*c)) {
+            return matches.into_iter().collect();
+        }
+
+        let token_trigrams = trigrams(token);
+        let mut shared: HashMap<&String, usize> = HashMap::new();
+
+        for trigram in &token_trigrams {
+            for term in self.trigrams.get(trigram).into_iter().flatten() {
+                *shared.entry(term).or_default() += 1;
+            }
+        }
+
+        // Each typo changes at most 3 trigrams
+        let min_shared = token_trigrams.len().saturating_sub(3 * max_distance).max(1);
+
+        for (term, count) in shared {
+            if count < min_shared || matches.contains_key(term) {
+                continue;
+            }
+
+            let term_chars: Vec<char> = term.chars().collect();
+
+            if let Some(distance) = edit_distance(&chars, &term_chars, max_distance) {
+                matches.insert(term, FUZZY_SCORE / distance as f64);
+            }
+        }
+
+        matches.into_iter().collect()
+    }
+
+    /// Score of each document matching a token in one of `fields`
+    fn search_token(&self, token: &str, fields: &[Field]) -> HashMap<u32, f64> {
+        let mut scores: HashMap<u32, f64> = HashMap::new();
+
+        for (term, score) in self.matching_terms(token) {
+            for (document, mask) in &self.terms[term] {
+                let weight = fields
+                    .iter()
+                    .filter(|field| mask & field.bit() != 0)
+                    .map(|field| field.weight())
+                    .fold(0.0, f64::max);
+
+                if weight == 0.0 {
+                    continue;
+                }
+
+                let best = scores.entry(*document).or_default();
+                *best = best.max(score * weight);
+            }
+        }
+
+        scores
+    }
+
+    /// Track ids matching every word of the query, best matches first.
+    /// Words can be limited to a field with a prefix,
+    /// e.g. `artist:beatles` or `album:"abbey road"`.
+    pub(crate) fn search(&self, query: &str, limit: usize) -> Vec<String> {
+        let mut scores: Option<HashMap<u32, f64>> = None;
+
+        for (fields, text) in parse_query(query) {
+            for token in tokenize(&text, false) {
+                let token_scores = self.search_token(&token, &fields);
+
+                scores = Some(match scores {
+                    None => token_scores,
+                    // Every word has to match
+                    Some(scores) => scores
+                        .into_iter()
+                        .filter_map(|(document, score)| {
+                            Some((document, score + token_scores.get(&document)?))
+                        })
+                        .collect(),
+                });
+            }
+        }
+
+        let normalized_query = normalize(query.trim());
+
+        let mut results: Vec<(&Document, f64)> = scores
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(document, score)| {
+                let document = self.documents.get(&document)?;
+
+                // Titles that start with the query are most likely what's being typed
+                let bonus = if document.title.starts_with(&normalized_query) {
+                    Field::Title.weight()
+                } else {
+                    0.0
+                };
+
+                Some((document, score + bonus))
+            })
+            .collect();
+
+        results.sort_by(|(a, a_score), (b, b_score)| {
+            b_score
+                .total_cmp(a_score)
+                .then_with(|| a.title.cmp(&b.title))
+        });
+
+        results
+            .into_iter()
+            .take(limit)
+            .map(|(document, _)| document.track_id.clone())
+            .collect()
+    }
+}
+
+/// Split a query into the fields to search and the text to search for.
+/// `artist:"the beatles" help` searches artists for "the beatles"
+/// and the default fields for "help".
+fn parse_query(query: &str) -> Vec<(Vec<Field>, String)> {
+    let mut clauses = vec![];
+    let mut chars = query.chars().peekable();
+
+    while chars.peek().is_some() {
+        // Skip whitespace between clauses
+        while chars.peek().map_or(false, |c| c.is_whitespace()) {
+            chars.next();
+        }
+
+        let mut word = String::new();
+        let mut fields = DEFAULT_FIELDS.to_vec();
+
+        while let Some(c) = chars.next() {
+            if c.is_whitespace() {
+                break;
+            }
+
+            if c == ':' {
+                if let Some(field) = Field::parse(&word.to_lowercase()) {
+                    fields = vec![field];
+                    word.clear();
+                    continue;
+                }
+            }
+
+            if c == '"' {
+                // Quoted text is one clause, spaces and all
+                for c in chars.by_ref() {
+                    if c == '"' {
+                        break;
+                    }
+
+                    word.push(c);
+                }
+
+                continue;
+            }
+
+            word.push(c);
+        }
+
+        if !word.is_empty() {
+            clauses.push((fields, word));
+        }
+    }
+
+    clauses
+}
+
+#[derive(Default)]
+pub struct SearchState(Mutex<SearchIndex>);
+
+/// Bring the search index up to date after the library changed
+pub(crate) fn update_search_index(app_handle: &AppHandle, library: &Library) {
+    if let Ok(mut index) = app_handle.state::<SearchState>().0.lock() {
+        index.sync(library);
+    }
+}
+
+#[tauri::command(async)]
+pub fn index_library(state: State<SearchState>, library: Library) -> Result<(), String> {
+    state.0.lock().map_err(|e| e.to_string())?.sync(&library);
+
+    Ok(())
+}
+
+#[tauri::command(async)]
+pub fn search_library(
+    state: State<SearchState>,
+    query: String,
+    limit: Option<usize>,
+) -> Result<Vec<String>, String> {
+    let index = state.0.lock().map_err(|e| e.to_string())?;
+
+    Ok(index.search(&query, limit.unwrap_or(DEFAULT_LIMIT)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clause(fields: &[Field], text: &str) -> (Vec<Field>, String) {
+        (fields.to_vec(), text.to_string())
+    }
+
+    #[test]
+    fn parse_query_plain_words() {
+        assert_eq!(
+            parse_query("  hey  jude "),
+            vec![
+                clause(&DEFAULT_FIELDS, "hey"),
+                clause(&DEFAULT_FIELDS, "jude")
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_query_fields() {
+        assert_eq!(
+            parse_query("artist:\"the beatles\" help"),
+            vec![
+                clause(&[Field::Artist], "the beatles"),
+                clause(&DEFAULT_FIELDS, "help"),
+            ]
+        );
+        assert_eq!(
+            parse_query("Track:yesterday LYRICS:troubles"),
+            vec![
+                clause(&[Field::Title], "yesterday"),
+                clause(&[Field::Lyrics], "troubles"),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_query_unknown_field_is_text() {
+        assert_eq!(
+            parse_query("year:1969 \"a:b\""),
+            vec![
+                clause(&DEFAULT_FIELDS, "year:1969"),
+                clause(&DEFAULT_FIELDS, "a:b"),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_query_edge_cases() {
+        assert_eq!(parse_query(""), vec![]);
+        assert_eq!(parse_query("   "), vec![]);
+        // A field without text is dropped
+        assert_eq!(
+            parse_query("artist: help"),
+            vec![clause(&DEFAULT_FIELDS, "help")]
+        );
+        // An unclosed quote runs to the end
+        assert_eq!(
+            parse_query("album:\"abbey road"),
+            vec![clause(&[Field::Album], "abbey road")]
+        );
+    }
+}
@@ -0,0 +1,640 @@
+/*
+    Smart playlists, evaluated from rules against the library
+*/
+
+use crate::library::{Library, Track};
+use crate::sortname::{natural_key, NaturalKey};
+use globset::{GlobBuilder, GlobMatcher};
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use time::{Duration, OffsetDateTime};
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Rule {
+    /// Every rule matches
+    All {
+        rules: Vec<Rule>,
+    },
+    /// At least one rule matches
+    Any {
+        rules: Vec<Rule>,
+    },
+    Not {
+        rule: Box<Rule>,
+    },
+    /// One of the track's genres, case insensitive
+    Genre {
+        genre: String,
+    },
+    /// Release year of the track's album
+    Year {
+        min: Option<u32>,
+        max: Option<u32>,
+    },
+    AddedWithinDays {
+        days: u32,
+    },
+    PlayCount {
+        min: Option<u32>,
+        max: Option<u32>,
+    },
+    PlayedWithinDays {
+        days: u32,
+    },
+    /// In seconds
+    Duration {
+        min: Option<u64>,
+        max: Option<u64>,
+    },
+    /// File extensions, e.g. `["flac", "wav"]`
+    Format {
+        formats: Vec<String>,
+    },
+    /// 1 to 5 stars, unrated tracks never match
+    Rating {
+        min: Option<u8>,
+        max: Option<u8>,
+    },
+    /// Glob matched against the track's location, e.g. `**/Soundtracks/**`
+    Path {
+        glob: String,
+    },
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum SortField {
+    Title,
+    Artist,
+    Album,
+    /// Original release date, then release date, then year
+    Year,
+    Added,
+    LastPlayed,
+    PlayCount,
+    Rating,
+    Duration,
+    Bpm,
+    /// Shuffled, but stays the same between evaluations
+    Random,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Sort {
+    field: SortField,
+    #[serde(default)]
+    descending: bool,
+}
+
+#[derive(serde::Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SmartPlaylist {
+    id: String,
+    rule: Rule,
+    #[serde(default)]
+    sort: Option<Sort>,
+    /// Maximum number of tracks
+    #[serde(default)]
+    limit: Option<usize>,
+    /// Stop adding tracks once they add up to this many minutes
+    #[serde(default)]
+    limit_minutes: Option<u64>,
+}
+
+struct Context<'a> {
+    now: OffsetDateTime,
+    album_years: HashMap<&'a str, u32>,
+    /// Partial ISO dates sort chronologically as strings
+    album_dates: HashMap<&'a str, String>,
+    artist_names: HashMap<&'a str, NaturalKey>,
+    album_names: HashMap<&'a str, NaturalKey>,
+}
+
+type Matcher<'a> = Box<dyn Fn(&Track) -> bool + 'a>;
+
+fn in_range<T: PartialOrd>(value: T, min: Option<T>, max: Option<T>) -> bool {
+    min.map_or(true, |min| value >= min) && max.map_or(true, |max| value <= max)
+}
+
+/// The start of the last `days` days, `None` when that is before the earliest representable date
+fn since_days(now: OffsetDateTime, days: u32) -> Option<OffsetDateTime> {
+    now.checked_sub(Duration::days(i64::from(days)))
+}
+
+/// Turn a rule into a function, so globs are only compiled once
+fn compile<'a>(rule: &Rule, context: &'a Context) -> Result<Matcher<'a>, String> {
+    Ok(match rule {
+        Rule::All { rules } => {
+            let matchers = compile_all(rules, context)?;
+            Box::new(move |track| matchers.iter().all(|matcher| matcher(track)))
+        }
+        Rule::Any { rules } => {
+            let matchers = compile_all(rules, context)?;
+            Box::new(move |track| matchers.iter().any(|matcher| matcher(track)))
+        }
+        Rule::Not { rule } => {
+            let matcher = compile(rule, context)?;
+            Box::new(move |track| !matcher(track))
+        }
+        Rule::Genre { genre } => {
+            let genre = genre.trim().to_lowercase();
+            Box::new(move |track| {
+                track
+                    .genres
+                    .iter()
+                    .any(|other| other.to_lowercase() == genre)
+            })
+        }
+        Rule::Year { min, max } => {
+            let (min, max) = (*min, *max);
+
+            Box::new(move |track| {
+                context
+                    .album_years
+                    .get(track.album_id.as_str())
+                    .map_or(false, |year| in_range(*year, min, max))
+            })
+        }
+        Rule::AddedWithinDays { days } => {
+            let since = since_days(context.now, *days);
+            Box::new(move |track| since.map_or(true, |since| track.created_at >= since))
+        }
+        Rule::PlayCount { min, max } => {
+            let (min, max) = (*min, *max);
+            Box::new(move |track| in_range(track.play_count, min, max))
+        }
+        Rule::PlayedWithinDays { days } => {
+            let since = since_days(context.now, *days);
+            Box::new(move |track| {
+                track
+                    .last_played_at
+                    .map_or(false, |played| since.map_or(true, |since| played >= since))
+            })
+        }
+        Rule::Duration { min, max } => {
+            let (min, max) = (*min, *max);
+            Box::new(move |track| in_range(track.duration, min, max))
+        }
+        Rule::Format { formats } => {
+            let formats: Vec<String> = formats
+                .iter()
+                .map(|format| format.trim_start_matches('.').to_lowercase())
+                .collect();
+
+            Box::new(move |track| {
+                Path::new(&track.location)
+                    .extension()
+                    .and_then(|extension| extension.to_str())
+                    .map_or(false, |extension| {
+                        formats.contains(&extension.to_lowercase())
+                    })
+            })
+        }
+        Rule::Rating { min, max } => {
+            let (min, max) = (*min, *max);
+            Box::new(move |track| {
+                track
+                    .rating
+                    .map_or(false, |rating| in_range(rating, min, max))
+            })
+        }
+        Rule::Path { glob } => {
+            let matcher: GlobMatcher = GlobBuilder::new(glob)
+                .literal_separator(true)
+                .build()
+                .map_err(|e| format!("Invalid path glob \"{}\": {}", glob, e))?
+                .compile_matcher();
+
+            Box::new(move |track| matcher.is_match(&track.location))
+        }
+    })
+}
+
+fn compile_all<'a>(rules: &[Rule], context: &'a Context) -> Result<Vec<Matcher<'a>>, String> {
+    rules.iter().map(|rule| compile(rule, context)).collect()
+}
+
+fn shuffle_key(playlist_id: &str, track: &Track) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (playlist_id, &track.id).hash(&mut hasher);
+    hasher.finish()
+}
+
+fn compare(
+    a: &Track,
+    b: &Track,
+    field: SortField,
+    playlist_id: &str,
+    context: &Context,
+) -> Ordering {
+    let artist = |track: &Track| context.artist_names.get(track.artist_id.as_str());
+    let album = |track: &Track| context.album_names.get(track.album_id.as_str());
+
+    match field {
+        SortField::Title => natural_key(&a.sort_title).cmp(&natural_key(&b.sort_title)),
+        SortField::Artist => artist(a).cmp(&artist(b)),
+        SortField::Album => album(a)
+            .cmp(&album(b))
+            .then(a.disc_num.cmp(&b.disc_num))
+            .then(a.track_num.cmp(&b.track_num)),
+        SortField::Year => context
+            .album_dates
+            .get(a.album_id.as_str())
+            .cmp(&context.album_dates.get(b.album_id.as_str())),
+        SortField::Added => a.created_at.cmp(&b.created_at),
+        SortField::LastPlayed => a.last_played_at.cmp(&b.last_played_at),
+        SortField::PlayCount => a.play_count.cmp(&b.play_count),
+        SortField::Rating => a.rating.cmp(&b.rating),
+        SortField::Duration => a.duration.cmp(&b.duration),
+        SortField::Bpm => a.bpm.unwrap_or(0.0).total_cmp(&b.bpm.unwrap_or(0.0)),
+        SortField::Random => shuffle_key(playlist_id, a).cmp(&shuffle_key(playlist_id, b)),
+    }
+}
+
+fn evaluate(
+    library: &Library,
+    playlist: &SmartPlaylist,
+    context: &Context,
+) -> Result<Vec<String>, String> {
+    let matcher = compile(&playlist.rule, context)?;
+
+    let mut tracks: Vec<&Track> = library
+        .tracks
+        .iter()
+        .filter(|track| !track.hidden && matcher(track))
+        .collect();
+
+    if let Some(sort) = &playlist.sort {
+        tracks.sort_by(|a, b| {
+            let ordering = compare(a, b, sort.field, &playlist.id, context);
+
+            if sort.descending {
+                ordering.reverse()
+            } else {
+                ordering
+            }
+        });
+    }
+
+    if let Some(limit) = playlist.limit {
+        tracks.truncate(limit);
+    }
+
+    if let Some(minutes) = playlist.limit_minutes {
+        let mut total = 0;
+
+        tracks.retain(|track| {
+            total += track.duration;
+            total <= minutes * 60
+        });
+    }
+
+    Ok(tracks.into_iter().map(|track| track.id.clone()).collect())
+}
+
+/// Evaluate smart playlists against the library, returns the track ids of each playlist by id
+#[tauri::command(async)]
+pub fn evaluate_smart_playlists(
+    library: Library,
+    playlists: Vec<SmartPlaylist>,
+) -> Result<HashMap<String, Vec<String>>, String> {
+    let context = Context {
+        now: OffsetDateTime::now_utc(),
+        album_years: library
+            .albums
+            .iter()
+            .filter_map(|album| Some((album.id.as_str(), album.year?)))
+            .collect(),
+        album_dates: library
+            .albums
+            .iter()
+            .filter_map(|album| {
+                let date = album
+                    .original_date
+                    .clone()
+                    .or(album.release_date.clone())
+                    .or(album.year.map(|year| year.to_string()))?;
+
+                Some((album.id.as_str(), date))
+            })
+            .collect(),
+        artist_names: library
+            .artists
+            .iter()
+            .map(|artist| (artist.id.as_str(), natural_key(&artist.sort_name)))
+            .collect(),
+        album_names: library
+            .albums
+            .iter()
+            .map(|album| (album.id.as_str(), natural_key(&album.sort_name)))
+            .collect(),
+    };
+
+    playlists
+        .iter()
+        .map(|playlist| Ok((playlist.id.clone(), evaluate(&library, playlist, &context)?)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::library::test_track;
+
+    fn track(created_at: OffsetDateTime, last_played_at: Option<OffsetDateTime>) -> Track {
+        Track {
+            created_at,
+            last_played_at,
+            ..test_track("track")
+        }
+    }
+
+    fn context(now: OffsetDateTime) -> Context<'static> {
+        Context {
+            now,
+            album_years: HashMap::new(),
+            album_dates: HashMap::new(),
+            artist_names: HashMap::new(),
+            album_names: HashMap::new(),
+        }
+    }
+
+    fn matches(rule: &Rule, context: &Context, track: &Track) -> bool {
+        compile(rule, context).unwrap()(track)
+    }
+
+    #[test]
+    fn within_days() {
+        let now = OffsetDateTime::UNIX_EPOCH + Duration::days(365);
+        let context = context(now);
+        let recent = track(now - Duration::days(2), Some(now - Duration::days(2)));
+        let old = track(now - Duration::days(30), Some(now - Duration::days(30)));
+        let never_played = track(now, None);
+
+        let added = Rule::AddedWithinDays { days: 7 };
+        assert!(matches(&added, &context, &recent));
+        assert!(!matches(&added, &context, &old));
+
+        let played = Rule::PlayedWithinDays { days: 7 };
+        assert!(matches(&played, &context, &recent));
+        assert!(!matches(&played, &context, &old));
+        assert!(!matches(&played, &context, &never_played));
+    }
+
+    #[test]
+    fn within_days_overflow() {
+        // Far enough in the past that subtracting u32::MAX days overflows
+        let context = context(OffsetDateTime::UNIX_EPOCH);
+        let old = track(
+            OffsetDateTime::UNIX_EPOCH - Duration::days(365 * 1000),
+            Some(OffsetDateTime::UNIX_EPOCH - Duration::days(365 * 1000)),
+        );
+
+        assert!(matches(
+            &Rule::AddedWithinDays { days: u32::MAX },
+            &context,
+            &old
+        ));
+        assert!(matches(
+            &Rule::PlayedWithinDays { days: u32::MAX },
+            &context,
+            &old
+        ));
+        assert!(!matches(
+            &Rule::PlayedWithinDays { days: u32::MAX },
+            &context,
+            &track(OffsetDateTime::UNIX_EPOCH, None)
+        ));
+    }
+
+    fn genre(genre: &str) -> Rule {
+        Rule::Genre {
+            genre: genre.to_string(),
+        }
+    }
+
+    #[test]
+    fn compile_combinators() {
+        let context = context(OffsetDateTime::UNIX_EPOCH);
+        let rock = Track {
+            genres: vec!["Rock".to_string()],
+            play_count: 3,
+            ..test_track("rock")
+        };
+        let jazz = Track {
+            genres: vec!["Jazz".to_string(), "Fusion".to_string()],
+            play_count: 10,
+            ..test_track("jazz")
+        };
+
+        let all = Rule::All {
+            rules: vec![
+                genre(" rock "),
+                Rule::PlayCount {
+                    min: Some(2),
+                    max: Some(5),
+                },
+            ],
+        };
+        assert!(matches(&all, &context, &rock));
+        assert!(!matches(&all, &context, &jazz));
+
+        let any = Rule::Any {
+            rules: vec![
+                genre("fusion"),
+                Rule::PlayCount {
+                    min: Some(100),
+                    max: None,
+                },
+            ],
+        };
+        assert!(!matches(&any, &context, &rock));
+        assert!(matches(&any, &context, &jazz));
+
+        let not = Rule::Not {
+            rule: Box::new(genre("JAZZ")),
+        };
+        assert!(matches(&not, &context, &rock));
+        assert!(!matches(&not, &context, &jazz));
+
+        // Nothing to fail for All, nothing to match for Any
+        assert!(matches(&Rule::All { rules: vec![] }, &context, &rock));
+        assert!(!matches(&Rule::Any { rules: vec![] }, &context, &rock));
+    }
+
+    #[test]
+    fn compile_year_and_rating() {
+        let mut context = context(OffsetDateTime::UNIX_EPOCH);
+        context.album_years.insert("album", 1969);
+        let track = test_track("track");
+        let no_year = Track {
+            album_id: "no-year".to_string(),
+            ..test_track("no-year")
+        };
+
+        let sixties = Rule::Year {
+            min: Some(1960),
+            max: Some(1969),
+        };
+        assert!(matches(&sixties, &context, &track));
+        assert!(!matches(&sixties, &context, &no_year));
+        assert!(!matches(
+            &Rule::Year {
+                min: Some(1970),
+                max: None
+            },
+            &context,
+            &track
+        ));
+
+        let rated = Track {
+            rating: Some(4),
+            ..test_track("rated")
+        };
+        let good = Rule::Rating {
+            min: Some(4),
+            max: None,
+        };
+        assert!(matches(&good, &context, &rated));
+        assert!(!matches(&good, &context, &track));
+        // Unrated tracks never match, even without bounds
+        assert!(!matches(
+            &Rule::Rating {
+                min: None,
+                max: None
+            },
+            &context,
+            &track
+        ));
+    }
+
+    #[test]
+    fn compile_duration() {
+        let context = context(OffsetDateTime::UNIX_EPOCH);
+        let rule = Rule::Duration {
+            min: Some(120),
+            max: Some(240),
+        };
+
+        assert!(matches(&rule, &context, &test_track("track")));
+        assert!(!matches(
+            &rule,
+            &context,
+            &Track {
+                duration: 300,
+                ..test_track("long")
+            }
+        ));
+    }
+
+    #[test]
+    fn compile_format_and_path() {
+        let context = context(OffsetDateTime::UNIX_EPOCH);
+        let flac = Track {
+            location: "/music/Soundtracks/Score/01 Theme.FLAC".to_string(),
+            ..test_track("flac")
+        };
+        let mp3 = Track {
+            location: "/music/Rock/Album/01 Song.mp3".to_string(),
+            ..test_track("mp3")
+        };
+
+        let lossless = Rule::Format {
+            formats: vec![".flac".to_string(), "WAV".to_string()],
+        };
+        assert!(matches(&lossless, &context, &flac));
+        assert!(!matches(&lossless, &context, &mp3));
+
+        let soundtracks = Rule::Path {
+            glob: "**/Soundtracks/**".to_string(),
+        };
+        assert!(matches(&soundtracks, &context, &flac));
+        assert!(!matches(&soundtracks, &context, &mp3));
+
+        // * stays within a directory
+        let top_level = Rule::Path {
+            glob: "/music/*.mp3".to_string(),
+        };
+        assert!(!matches(&top_level, &context, &mp3));
+    }
+
+    #[test]
+    fn compile_invalid_glob() {
+        let context = context(OffsetDateTime::UNIX_EPOCH);
+        let invalid = Rule::Path {
+            glob: "[".to_string(),
+        };
+
+        assert!(compile(&invalid, &context).is_err());
+        assert!(compile(
+            &Rule::Any {
+                rules: vec![genre("Rock"), invalid]
+            },
+            &context
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn evaluate_sort_and_limits() {
+        let context = context(OffsetDateTime::UNIX_EPOCH);
+        let library = Library {
+            tracks: vec![
+                Track {
+                    duration: 200,
+                    ..test_track("b")
+                },
+                Track {
+                    duration: 100,
+                    ..test_track("a")
+                },
+                Track {
+                    duration: 300,
+                    ..test_track("c")
+                },
+                Track {
+                    duration: 50,
+                    hidden: true,
+                    ..test_track("hidden")
+                },
+            ],
+            albums: vec![],
+            artists: vec![],
+            composers: vec![],
+            roots: vec![],
+        };
+        let playlist = |sort: Option<Sort>, limit, limit_minutes| SmartPlaylist {
+            id: "playlist".to_string(),
+            rule: Rule::All { rules: vec![] },
+            sort,
+            limit,
+            limit_minutes,
+        };
+        let longest = Some(Sort {
+            field: SortField::Duration,
+            descending: true,
+        });
+        let shortest = Some(Sort {
+            field: SortField::Duration,
+            descending: false,
+        });
+
+        assert_eq!(
+            evaluate(&library, &playlist(None, None, None), &context),
+            Ok(vec!["b".to_string(), "a".to_string(), "c".to_string()])
+        );
+        assert_eq!(
+            evaluate(&library, &playlist(longest, Some(2), None), &context),
+            Ok(vec!["c".to_string(), "b".to_string()])
+        );
+        assert_eq!(
+            evaluate(&library, &playlist(shortest, None, Some(5)), &context),
+            Ok(vec!["a".to_string(), "b".to_string()])
+        );
+    }
+}
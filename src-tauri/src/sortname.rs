@@ -0,0 +1,268 @@
+/*
+    Sort names and natural sorting
+
+    Artists, albums and tracks are sorted by their sort tags, or by their name with a leading
+    article moved to the end ("The Beatles" is sorted as "Beatles, The"). Numbers in names are
+    compared by value, so "Disc 2" comes before "Disc 10".
+*/
+
+use crate::backup::config_path;
+use crate::library::Library;
+use crate::search::normalize;
+use tauri::AppHandle;
+
+pub(crate) const DEFAULT_ARTICLES: &[&str] = &[
+    "The", "A", "An", "Der", "Die", "Das", "Le", "La", "Les", "El", "Los", "Las", "Il",
+];
+
+/// The articles set in config.json, for changes made outside of a library update
+pub(crate) fn configured_articles(app_handle: &AppHandle) -> Vec<String> {
+    let articles = config_path(app_handle)
+        .and_then(|path| std::fs::read_to_string(path).map_err(|e| e.to_string()))
+        .and_then(|text| {
+            serde_json::from_str::<serde_json::Value>(&text).map_err(|e| e.to_string())
+        })
+        .ok()
+        .and_then(|config| {
+            serde_json::from_value::<Vec<String>>(config.get("sort_articles")?.clone()).ok()
+        });
+
+    articles.unwrap_or_else(|| DEFAULT_ARTICLES.iter().map(|a| a.to_string()).collect())
+}
+
+/// Sort name of `name`, with the first matching article moved to the end
+pub(crate) fn generate_sort_name<S: AsRef<str>>(name: &str, articles: &[S]) -> String {
+    let name = name.trim();
+
+    for article in articles {
+        let article = article.as_ref();
+
+        let Some(prefix) = name.get(..article.len()) else {
+            continue;
+        };
+
+        let rest = &name[article.len()..];
+
+        // Only whole words, and never the whole name
+        if prefix.eq_ignore_ascii_case(article) && rest.starts_with(char::is_whitespace) {
+            let rest = rest.trim_start();
+
+            if !rest.is_empty() {
+                return format!("{}, {}", rest, prefix);
+            }
+        }
+    }
+
+    name.to_string()
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug)]
+enum Chunk {
+    /// Length without leading zeros first, so longer numbers are bigger
+    Number(usize, String),
+    Text(String),
+}
+
+/// Key that sorts case and accent insensitively, with numbers by value
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub(crate) struct NaturalKey(Vec<Chunk>);
+
+pub(crate) fn natural_key(text: &str) -> NaturalKey {
+    let mut chunks = vec![];
+    let mut current = String::new();
+
+    for c in normalize(text).chars() {
+        let is_digit = c.is_ascii_digit();
+
+        if !current.is_empty() && current.starts_with(|c: char| c.is_ascii_digit()) != is_digit {
+            chunks.push(chunk(std::mem::take(&mut current)));
+        }
+
+        current.push(c);
+    }
+
+    if !current.is_empty() {
+        chunks.push(chunk(current));
+    }
+
+    NaturalKey(chunks)
+}
+
+fn chunk(text: String) -> Chunk {
+    if text.starts_with(|c: char| c.is_ascii_digit()) {
+        let digits = text.trim_start_matches('0').to_string();
+        Chunk::Number(digits.len(), digits)
+    } else {
+        Chunk::Text(text)
+    }
+}
+
+/// Set generated sort names of everything without sort tags, and sort the library by them
+pub(crate) fn sort_library<S: AsRef<str>>(library: &mut Library, articles: &[S]) {
+    for artist in &mut library.artists {
+        artist.sort_name = artist
+            .tagged_sort_name
+            .clone()
+            .unwrap_or_else(|| generate_sort_name(&artist.name, articles));
+    }
+
+    for composer in &mut library.composers {
+        composer.sort_name = composer
+            .tagged_sort_name
+            .clone()
+            .unwrap_or_else(|| generate_sort_name(&composer.name, articles));
+    }
+
+    for album in &mut library.albums {
+        album.sort_name = album
+            .tagged_sort_name
+            .clone()
+            .unwrap_or_else(|| generate_sort_name(&album.name, articles));
+    }
+
+    for track in &mut library.tracks {
+        track.sort_title = track
+            .tagged_sort_title
+            .clone()
+            .unwrap_or_else(|| generate_sort_name(&track.title, articles));
+    }
+
+    library
+        .artists
+        .sort_by_cached_key(|artist| natural_key(&artist.sort_name));
+    library
+        .composers
+        .sort_by_cached_key(|composer| natural_key(&composer.sort_name));
+    library
+        .albums
+        .sort_by_cached_key(|album| natural_key(&album.sort_name));
+    library
+        .tracks
+        .sort_by_cached_key(|track| natural_key(&track.sort_title));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::library::{test_track, Artist, Track};
+    use time::OffsetDateTime;
+
+    fn artist(name: &str, tagged_sort_name: Option<&str>) -> Artist {
+        Artist {
+            id: name.to_string(),
+            name: name.to_string(),
+            sort_name: String::new(),
+            tagged_sort_name: tagged_sort_name.map(|name| name.to_string()),
+            genres: vec![],
+            created_at: OffsetDateTime::UNIX_EPOCH,
+        }
+    }
+
+    #[test]
+    fn sort_name_moves_article() {
+        assert_eq!(
+            generate_sort_name("The Beatles", DEFAULT_ARTICLES),
+            "Beatles, The"
+        );
+        assert_eq!(
+            generate_sort_name("  the  Who ", DEFAULT_ARTICLES),
+            "Who, the"
+        );
+        assert_eq!(
+            generate_sort_name("A Tribe Called Quest", DEFAULT_ARTICLES),
+            "Tribe Called Quest, A"
+        );
+        assert_eq!(
+            generate_sort_name("Die Ärzte", DEFAULT_ARTICLES),
+            "Ärzte, Die"
+        );
+    }
+
+    #[test]
+    fn sort_name_whole_words() {
+        assert_eq!(
+            generate_sort_name("Theatre of Tragedy", DEFAULT_ARTICLES),
+            "Theatre of Tragedy"
+        );
+        assert_eq!(generate_sort_name("Anthrax", DEFAULT_ARTICLES), "Anthrax");
+        assert_eq!(generate_sort_name("The", DEFAULT_ARTICLES), "The");
+        assert_eq!(generate_sort_name("The ", DEFAULT_ARTICLES), "The");
+        assert_eq!(generate_sort_name("Ñu", DEFAULT_ARTICLES), "Ñu");
+    }
+
+    #[test]
+    fn sort_name_configured_articles() {
+        assert_eq!(
+            generate_sort_name("The Beatles", &[] as &[&str]),
+            "The Beatles"
+        );
+        assert_eq!(
+            generate_sort_name("Les Misérables", &["Les"]),
+            "Misérables, Les"
+        );
+    }
+
+    #[test]
+    fn natural_key_order() {
+        let mut names = vec![
+            "Disc 10", "disc 2", "Émile", "Disc 1", "Edgar", "track 02", "Track 1", "10cc",
+        ];
+        names.sort_by_cached_key(|name| natural_key(name));
+
+        assert_eq!(
+            names,
+            vec!["10cc", "Disc 1", "disc 2", "Disc 10", "Edgar", "Émile", "Track 1", "track 02"]
+        );
+    }
+
+    #[test]
+    fn natural_key_equal() {
+        assert_eq!(natural_key("Track 01"), natural_key("track 1"));
+        assert_eq!(natural_key("Beyonce"), natural_key("Beyoncé"));
+        assert_ne!(natural_key("Track 1"), natural_key("Track 10"));
+    }
+
+    #[test]
+    fn sort_library_sort_tags() {
+        let mut library = Library {
+            tracks: vec![
+                Track {
+                    title: "Song 10".to_string(),
+                    ..test_track("10")
+                },
+                Track {
+                    title: "The Song".to_string(),
+                    tagged_sort_title: Some("Zzz".to_string()),
+                    ..test_track("zzz")
+                },
+                Track {
+                    title: "Song 9".to_string(),
+                    ..test_track("9")
+                },
+            ],
+            albums: vec![],
+            artists: vec![
+                artist("The Beatles", None),
+                artist("Bob Dylan", Some("Dylan, Bob")),
+                artist("ABBA", None),
+            ],
+            composers: vec![],
+            roots: vec![],
+        };
+
+        sort_library(&mut library, DEFAULT_ARTICLES);
+
+        let artists: Vec<&str> = library
+            .artists
+            .iter()
+            .map(|artist| artist.sort_name.as_str())
+            .collect();
+        let tracks: Vec<&str> = library
+            .tracks
+            .iter()
+            .map(|track| track.id.as_str())
+            .collect();
+        assert_eq!(artists, vec!["ABBA", "Beatles, The", "Dylan, Bob"]);
+        assert_eq!(tracks, vec!["9", "10", "zzz"]);
+    }
+}
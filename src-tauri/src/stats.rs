@@ -0,0 +1,199 @@
+/*
+    Library statistics
+*/
+
+use crate::library::{Library, Track};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+#[derive(serde::Serialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Breakdown {
+    /// Unset for tracks where it isn't known, e.g. the bit depth of lossy files
+    key: Option<String>,
+    tracks: u32,
+    /// In seconds
+    duration: u64,
+    /// In bytes
+    size: u64,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TagCompleteness {
+    field: &'static str,
+    missing: u32,
+    /// Percentage of tracks that have it
+    complete: f64,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LibraryStats {
+    tracks: u32,
+    albums: u32,
+    artists: u32,
+    /// In seconds
+    duration: u64,
+    /// In bytes, of the files whose size is known
+    size: u64,
+    formats: Vec<Breakdown>,
+    sample_rates: Vec<Breakdown>,
+    bit_depths: Vec<Breakdown>,
+    genres: Vec<Breakdown>,
+    decades: Vec<Breakdown>,
+    completeness: Vec<TagCompleteness>,
+}
+
+#[derive(Default)]
+struct Breakdowns(HashMap<Option<String>, Breakdown>);
+
+impl Breakdowns {
+    fn add(&mut self, key: Option<String>, track: &Track) {
+        let breakdown = self.0.entry(key.clone()).or_insert_with(|| Breakdown {
+            key,
+            ..Default::default()
+        });
+
+        breakdown.tracks += 1;
+        breakdown.duration += track.duration;
+        breakdown.size += track.file_size.unwrap_or(0);
+    }
+
+    /// Most tracks first
+    fn by_tracks(self) -> Vec<Breakdown> {
+        let mut breakdowns: Vec<Breakdown> = self.0.into_values().collect();
+        breakdowns.sort_by(|a, b| b.tracks.cmp(&a.tracks).then(a.key.cmp(&b.key)));
+        breakdowns
+    }
+
+    /// Ordered by a numeric key, unknown last
+    fn by_number(self) -> Vec<Breakdown> {
+        let mut breakdowns: Vec<Breakdown> = self.0.into_values().collect();
+        breakdowns.sort_by_key(|breakdown| {
+            breakdown
+                .key
+                .as_ref()
+                .and_then(|key| key.parse::<u64>().ok())
+                .unwrap_or(u64::MAX)
+        });
+        breakdowns
+    }
+}
+
+/// Totals and breakdowns of the tracks in the library, hidden tracks aren't counted
+#[tauri::command(async)]
+pub fn library_stats(library: Library) -> Result<LibraryStats, String> {
+    let tracks: Vec<&Track> = library
+        .tracks
+        .iter()
+        .filter(|track| !track.hidden)
+        .collect();
+
+    let album_years: HashMap<&str, u32> = library
+        .albums
+        .iter()
+        .filter_map(|album| Some((album.id.as_str(), album.year?)))
+        .collect();
+
+    let albums_with_art: HashSet<&str> = library
+        .albums
+        .iter()
+        .filter(|album| album.album_art.is_some())
+        .map(|album| album.id.as_str())
+        .collect();
+
+    let mut formats = Breakdowns::default();
+    let mut sample_rates = Breakdowns::default();
+    let mut bit_depths = Breakdowns::default();
+    let mut genres = Breakdowns::default();
+    let mut decades = Breakdowns::default();
+
+    let mut missing: [(&'static str, u32); 6] = [
+        ("albumArt", 0),
+        ("year", 0),
+        ("genre", 0),
+        // Tracks without a title tag are named after their file
+        ("title", 0),
+        ("bpm", 0),
+        ("key", 0),
+    ];
+
+    for track in &tracks {
+        let path = Path::new(&track.location);
+        let year = album_years.get(track.album_id.as_str());
+
+        formats.add(
+            path.extension()
+                .and_then(|extension| extension.to_str())
+                .map(|extension| extension.to_lowercase()),
+            track,
+        );
+        sample_rates.add(track.sample_rate.map(|rate| rate.to_string()), track);
+        bit_depths.add(track.bit_depth.map(|depth| depth.to_string()), track);
+        decades.add(year.map(|year| (year - year % 10).to_string()), track);
+
+        if track.genres.is_empty() {
+            genres.add(None, track);
+        }
+
+        for genre in &track.genres {
+            genres.add(Some(genre.clone()), track);
+        }
+
+        let has_art =
+            track.album_art.is_some() || albums_with_art.contains(track.album_id.as_str());
+        let untitled = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .map_or(false, |stem| stem == track.title);
+
+        for ((_, count), is_missing) in missing.iter_mut().zip([
+            !has_art,
+            year.is_none(),
+            track.genres.is_empty(),
+            untitled,
+            track.bpm.is_none(),
+            track.key.is_none(),
+        ]) {
+            if is_missing {
+                *count += 1;
+            }
+        }
+    }
+
+    let track_count = tracks.len() as u32;
+
+    Ok(LibraryStats {
+        tracks: track_count,
+        albums: tracks
+            .iter()
+            .map(|track| track.album_id.as_str())
+            .collect::<HashSet<_>>()
+            .len() as u32,
+        artists: tracks
+            .iter()
+            .map(|track| track.artist_id.as_str())
+            .collect::<HashSet<_>>()
+            .len() as u32,
+        duration: tracks.iter().map(|track| track.duration).sum(),
+        size: tracks.iter().filter_map(|track| track.file_size).sum(),
+        formats: formats.by_tracks(),
+        sample_rates: sample_rates.by_number(),
+        bit_depths: bit_depths.by_number(),
+        genres: genres.by_tracks(),
+        decades: decades.by_number(),
+        completeness: missing
+            .into_iter()
+            .map(|(field, missing)| TagCompleteness {
+                field,
+                missing,
+                complete: if track_count == 0 {
+                    100.0
+                } else {
+                    100.0 * (track_count - missing) as f64 / track_count as f64
+                },
+            })
+            .collect(),
+    })
+}
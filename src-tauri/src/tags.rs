@@ -0,0 +1,527 @@
+/*
+    Writing metadata back to audio files
+*/
+
+use crate::library::{rescan_tracks, Library};
+use crate::loudness::Loudness;
+use crate::search::update_search_index;
+use lofty::{
+    Accessor, AudioFile, ItemKey, ItemValue, Picture, PictureType, Tag, TagItem, TaggedFile,
+    TaggedFileExt,
+};
+use log::{debug, warn};
+use std::path::{Path, PathBuf};
+use tauri::{AppHandle, Manager};
+use time::OffsetDateTime;
+
+/// Date keys read before the year tag, in order
+const DATE_KEYS: &[ItemKey] = &[ItemKey::ReleaseDate, ItemKey::RecordingDate];
+
+/// Fields to change in a file's tags, fields that are `None` are left as they are
+#[derive(serde::Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct TagChanges {
+    title: Option<String>,
+    artist: Option<String>,
+    album: Option<String>,
+    album_artist: Option<String>,
+    track_num: Option<u32>,
+    total_tracks: Option<u32>,
+    disc_num: Option<u32>,
+    total_discs: Option<u32>,
+    year: Option<u32>,
+    genres: Option<Vec<String>>,
+    /// Path to an image file to use as the front cover
+    cover_art: Option<String>,
+    remove_cover_art: bool,
+}
+
+#[derive(serde::Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TagEdit {
+    location: String,
+    changes: TagChanges,
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FieldChange {
+    field: String,
+    old: Option<String>,
+    new: Option<String>,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TagEditResult {
+    location: String,
+    changes: Vec<FieldChange>,
+    backup: Option<String>,
+    /// Set when the file was changed, files are either fully written or left as they were
+    written: bool,
+    error: Option<String>,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct WriteTagsResult {
+    results: Vec<TagEditResult>,
+    library: Library,
+}
+
+fn change(field: &str, old: Option<String>, new: Option<String>) -> Option<FieldChange> {
+    if old == new {
+        return None;
+    }
+
+    Some(FieldChange {
+        field: field.to_string(),
+        old,
+        new,
+    })
+}
+
+/// The year as the library reads it, from the release or recording date before the year tag
+fn tag_year(tag: &Tag) -> Option<u32> {
+    DATE_KEYS
+        .iter()
+        .find_map(|key| tag.get_string(key)?.get(..4)?.parse().ok())
+        .or(tag.year())
+}
+
+fn describe_picture(picture: &Picture) -> String {
+    format!(
+        "{} ({} bytes)",
+        picture
+            .mime_type()
+            .map(|mime| mime.as_str())
+            .unwrap_or("unknown"),
+        picture.data().len()
+    )
+}
+
+/// Compare the current tag against the requested changes
+fn diff_tag(
+    tag: Option<&Tag>,
+    changes: &TagChanges,
+    picture: Option<&Picture>,
+) -> Vec<FieldChange> {
+    let text = |key: &ItemKey| tag.and_then(|tag| tag.get_string(key)).map(String::from);
+    let number = |value: Option<u32>| value.map(|v| v.to_string());
+
+    let mut diff = vec![];
+
+    if let Some(title) = &changes.title {
+        diff.push(change(
+            "title",
+            text(&ItemKey::TrackTitle),
+            Some(title.clone()),
+        ));
+    }
+
+    if let Some(artist) = &changes.artist {
+        diff.push(change(
+            "artist",
+            text(&ItemKey::TrackArtist),
+            Some(artist.clone()),
+        ));
+    }
+
+    if let Some(album) = &changes.album {
+        diff.push(change(
+            "album",
+            text(&ItemKey::AlbumTitle),
+            Some(album.clone()),
+        ));
+    }
+
+    if let Some(album_artist) = &changes.album_artist {
+        diff.push(change(
+            "albumArtist",
+            text(&ItemKey::AlbumArtist),
+            Some(album_artist.clone()),
+        ));
+    }
+
+    if changes.track_num.is_some() {
+        diff.push(change(
+            "trackNum",
+            number(tag.and_then(|tag| tag.track())),
+            number(changes.track_num),
+        ));
+    }
+
+    if changes.total_tracks.is_some() {
+        diff.push(change(
+            "totalTracks",
+            number(tag.and_then(|tag| tag.track_total())),
+            number(changes.total_tracks),
+        ));
+    }
+
+    if changes.disc_num.is_some() {
+        diff.push(change(
+            "discNum",
+            number(tag.and_then(|tag| tag.disk())),
+            number(changes.disc_num),
+        ));
+    }
+
+    if changes.total_discs.is_some() {
+        diff.push(change(
+            "totalDiscs",
+            number(tag.and_then(|tag| tag.disk_total())),
+            number(changes.total_discs),
+        ));
+    }
+
+    if changes.year.is_some() {
+        diff.push(change(
+            "year",
+            number(tag.and_then(tag_year)),
+            number(changes.year),
+        ));
+    }
+
+    if let Some(genres) = &changes.genres {
+        let old = tag
+            .map(|tag| {
+                tag.get_strings(&ItemKey::Genre)
+                    .collect::<Vec<_>>()
+                    .join("; ")
+            })
+            .filter(|genres| !genres.is_empty());
+        let new = Some(genres.join("; ")).filter(|genres| !genres.is_empty());
+
+        diff.push(change("genres", old, new));
+    }
+
+    if picture.is_some() || changes.remove_cover_art {
+        let old = tag
+            .and_then(|tag| tag.pictures().first())
+            .map(describe_picture);
+
+        diff.push(change("coverArt", old, picture.map(describe_picture)));
+    }
+
+    diff.into_iter().flatten().collect()
+}
+
+fn apply_changes(tag: &mut Tag, changes: &TagChanges, picture: Option<Picture>) {
+    if let Some(title) = &changes.title {
+        tag.set_title(title.clone());
+    }
+
+    if let Some(artist) = &changes.artist {
+        tag.set_artist(artist.clone());
+    }
+
+    if let Some(album) = &changes.album {
+        tag.set_album(album.clone());
+    }
+
+    if let Some(album_artist) = &changes.album_artist {
+        tag.insert_text(ItemKey::AlbumArtist, album_artist.clone());
+    }
+
+    if let Some(track_num) = changes.track_num {
+        tag.set_track(track_num);
+    }
+
+    if let Some(total_tracks) = changes.total_tracks {
+        tag.set_track_total(total_tracks);
+    }
+
+    if let Some(disc_num) = changes.disc_num {
+        tag.set_disk(disc_num);
+    }
+
+    if let Some(total_discs) = changes.total_discs {
+        tag.set_disk_total(total_discs);
+    }
+
+    if let Some(year) = changes.year {
+        let year_text = year.to_string();
+
+        tag.set_year(year);
+
+        // Dates of another year would still be read instead of the year, the month and day are
+        // kept when the year doesn't change
+        for key in DATE_KEYS {
+            if tag
+                .get_string(key)
+                .map_or(false, |date| !date.starts_with(&year_text))
+            {
+                tag.insert_text(key.clone(), year_text.clone());
+            }
+        }
+    }
+
+    if let Some(genres) = &changes.genres {
+        tag.remove_key(&ItemKey::Genre);
+
+        for genre in genres {
+            tag.push(TagItem::new(ItemKey::Genre, ItemValue::Text(genre.clone())));
+        }
+    }
+
+    if picture.is_some() || changes.remove_cover_art {
+        // Covers are often stored as "Other", the scanner uses whichever picture comes first
+        tag.remove_picture_type(PictureType::CoverFront);
+        tag.remove_picture_type(PictureType::Other);
+    }
+
+    if let Some(picture) = picture {
+        tag.push_picture(picture);
+    }
+}
+
+fn read_cover_art(path: &str) -> Result<Picture, String> {
+    let mut file = std::fs::File::open(path).map_err(|e| e.to_string())?;
+    let mut picture = Picture::from_reader(&mut file).map_err(|e| e.to_string())?;
+
+    picture.set_pic_type(PictureType::CoverFront);
+
+    Ok(picture)
+}
+
+fn backup_file(backup_dir: &Path, file: &Path) -> Result<PathBuf, String> {
+    std::fs::create_dir_all(backup_dir).map_err(|e| e.to_string())?;
+
+    // Prefix with a hash of the full path, files in different albums often share a name
+    let backup_path = backup_dir.join(format!(
+        "{:x}-{}",
+        md5::compute(file.to_string_lossy().as_bytes()),
+        file.file_name().unwrap_or_default().to_string_lossy()
+    ));
+
+    std::fs::copy(file, &backup_path).map_err(|e| e.to_string())?;
+
+    Ok(backup_path)
+}
+
+/// Save tags to a copy of the file next to it and move that over the file, so a failed write
+/// leaves the file untouched
+fn save_replacing(tagged_file: &TaggedFile, path: &Path) -> Result<(), String> {
+    let temp_path = path.with_file_name(format!(
+        ".{}.tmp",
+        path.file_name().unwrap_or_default().to_string_lossy()
+    ));
+
+    let result = std::fs::copy(path, &temp_path)
+        .map_err(|e| e.to_string())
+        .and_then(|_| {
+            tagged_file
+                .save_to_path(&temp_path)
+                .map_err(|e| e.to_string())
+        })
+        .and_then(|_| std::fs::rename(&temp_path, path).map_err(|e| e.to_string()));
+
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+
+    result
+}
+
+/// A new directory for the backups of one edit. Edits made at the same time get their own
+/// directory, so they don't overwrite each other's originals.
+fn create_backup_dir(parent: &Path) -> Result<PathBuf, String> {
+    std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+
+    let name = OffsetDateTime::now_utc().unix_timestamp_nanos();
+
+    let mut attempt = 0;
+
+    loop {
+        let dir = match attempt {
+            0 => parent.join(name.to_string()),
+            _ => parent.join(format!("{}-{}", name, attempt)),
+        };
+
+        match std::fs::create_dir(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => attempt += 1,
+            Err(err) => return Err(err.to_string()),
+        }
+    }
+}
+
+/// Returns the changes made (or that would be made) and the backup location
+fn edit_file(
+    edit: &TagEdit,
+    dry_run: bool,
+    backup_dir: Option<&Path>,
+) -> Result<(Vec<FieldChange>, Option<PathBuf>), String> {
+    let path = Path::new(&edit.location);
+
+    let mut tagged_file = lofty::read_from_path(path).map_err(|e| e.to_string())?;
+
+    let picture = match &edit.changes.cover_art {
+        Some(cover_art) => Some(read_cover_art(cover_art)?),
+        None => None,
+    };
+
+    let changes = diff_tag(tagged_file.primary_tag(), &edit.changes, picture.as_ref());
+
+    if dry_run || changes.is_empty() {
+        return Ok((changes, None));
+    }
+
+    let backup = match backup_dir {
+        Some(backup_dir) => Some(backup_file(backup_dir, path)?),
+        None => None,
+    };
+
+    if tagged_file.primary_tag().is_none() {
+        let tag_type = tagged_file.primary_tag_type();
+        tagged_file.insert_tag(Tag::new(tag_type));
+    }
+
+    let tag = tagged_file
+        .primary_tag_mut()
+        .ok_or("File does not support tags")?;
+
+    apply_changes(tag, &edit.changes, picture);
+
+    save_replacing(&tagged_file, path)?;
+
+    debug!("Wrote {} tag changes to {}", changes.len(), path.display());
+
+    Ok((changes, backup))
+}
+
+/// Embed the image at `cover_art` as the front cover of a file, replacing its current cover
+pub(crate) fn write_cover_art(location: &str, cover_art: &str) -> Result<(), String> {
+    let edit = TagEdit {
+        location: location.to_string(),
+        changes: TagChanges {
+            cover_art: Some(cover_art.to_string()),
+            ..Default::default()
+        },
+    };
+
+    edit_file(&edit, false, None).map(|_| ())
+}
+
+/// Write REPLAYGAIN_* tags, files that already have them are left alone unless `overwrite` is set.
+/// Returns whether the file was changed.
+pub(crate) fn write_replay_gain(
+    path: &Path,
+    track: &Loudness,
+    album: Option<&Loudness>,
+    overwrite: bool,
+) -> Result<bool, String> {
+    let mut tagged_file = lofty::read_from_path(path).map_err(|e| e.to_string())?;
+
+    if tagged_file.primary_tag().is_none() {
+        let tag_type = tagged_file.primary_tag_type();
+        tagged_file.insert_tag(Tag::new(tag_type));
+    }
+
+    let tag = tagged_file
+        .primary_tag_mut()
+        .ok_or("File does not support tags")?;
+
+    if !overwrite && tag.get_string(&ItemKey::ReplayGainTrackGain).is_some() {
+        return Ok(false);
+    }
+
+    tag.insert_text(
+        ItemKey::ReplayGainTrackGain,
+        format!("{:.2} dB", track.gain()),
+    );
+    tag.insert_text(
+        ItemKey::ReplayGainTrackPeak,
+        format!("{:.6}", track.true_peak),
+    );
+
+    if let Some(album) = album {
+        tag.insert_text(
+            ItemKey::ReplayGainAlbumGain,
+            format!("{:.2} dB", album.gain()),
+        );
+        tag.insert_text(
+            ItemKey::ReplayGainAlbumPeak,
+            format!("{:.6}", album.true_peak),
+        );
+    }
+
+    save_replacing(&tagged_file, path)?;
+
+    debug!("Wrote ReplayGain tags to {}", path.display());
+
+    Ok(true)
+}
+
+#[tauri::command(async)]
+pub fn write_tags(
+    app_handle: AppHandle,
+    mut library: Library,
+    edits: Vec<TagEdit>,
+    dry_run: bool,
+    backup: bool,
+) -> Result<WriteTagsResult, String> {
+    let backup_dir = if backup && !dry_run {
+        Some(create_backup_dir(
+            &app_handle
+                .path()
+                .app_data_dir()
+                .map_err(|e| e.to_string())?
+                .join("backups"),
+        )?)
+    } else {
+        None
+    };
+
+    let mut results = vec![];
+    let mut written = vec![];
+    let mut art_changed = false;
+
+    for edit in edits {
+        let result = match edit_file(&edit, dry_run, backup_dir.as_deref()) {
+            Ok((changes, backup)) => {
+                let was_written = !dry_run && !changes.is_empty();
+
+                if was_written {
+                    art_changed |= changes.iter().any(|c| c.field == "coverArt");
+                    written.push(PathBuf::from(&edit.location));
+                }
+
+                TagEditResult {
+                    location: edit.location,
+                    changes,
+                    backup: backup.map(|path| path.to_string_lossy().to_string()),
+                    written: was_written,
+                    error: None,
+                }
+            }
+            Err(err) => {
+                warn!("Failed to write tags to {}: {}", edit.location, err);
+
+                TagEditResult {
+                    location: edit.location,
+                    changes: vec![],
+                    backup: None,
+                    written: false,
+                    error: Some(err),
+                }
+            }
+        };
+
+        results.push(result);
+    }
+
+    if !written.is_empty() {
+        rescan_tracks(&app_handle, &mut library, &written, art_changed);
+    }
+
+    // Nothing was backed up if nothing changed, fails if the directory isn't empty
+    if let Some(backup_dir) = &backup_dir {
+        let _ = std::fs::remove_dir(backup_dir);
+    }
+
+    update_search_index(&app_handle, &library);
+
+    Ok(WriteTagsResult { results, library })
+}
@@ -0,0 +1,170 @@
+/*
+    Tempo (BPM) estimation
+*/
+
+use crate::decode::resample;
+use rustfft::{num_complex::Complex, FftPlanner};
+
+const SAMPLE_RATE: u32 = 11025;
+const FRAME_SIZE: usize = 1024;
+const HOP_SIZE: usize = 256;
+const MIN_BPM: f64 = 60.0;
+const MAX_BPM: f64 = 200.0;
+
+// Periodicity at half a beat and at 2 and 4 beats also counts towards a tempo. The half beat
+// tells apart tempos whose beats land on offbeats of the actual tempo.
+const HARMONICS: [f64; 4] = [0.5, 1.0, 2.0, 4.0];
+const MAX_HARMONIC: f64 = 4.0;
+
+// Tempos near 120 BPM are preferred, which avoids picking half or double time
+const PRIOR_BPM: f64 = 120.0;
+const PRIOR_OCTAVES: f64 = 1.0;
+
+/// Spectral flux of the audio, peaks where notes and beats start
+fn onset_envelope(samples: &[f32]) -> Vec<f64> {
+    if samples.len() < FRAME_SIZE {
+        return vec![];
+    }
+
+    let fft = FftPlanner::new().plan_fft_forward(FRAME_SIZE);
+
+    let window: Vec<f64> = (0..FRAME_SIZE)
+        .map(|i| 0.5 - 0.5 * (2.0 * std::f64::consts::PI * i as f64 / FRAME_SIZE as f64).cos())
+        .collect();
+
+    let mut buffer = vec![Complex::new(0.0, 0.0); FRAME_SIZE];
+    let mut previous = vec![0.0; FRAME_SIZE / 2];
+    let mut envelope = vec![];
+
+    for start in (0..=samples.len() - FRAME_SIZE).step_by(HOP_SIZE) {
+        for (i, value) in buffer.iter_mut().enumerate() {
+            *value = Complex::new(samples[start + i] as f64 * window[i], 0.0);
+        }
+
+        fft.process(&mut buffer);
+
+        let mut flux = 0.0;
+
+        for (bin, previous) in buffer[..FRAME_SIZE / 2].iter().zip(previous.iter_mut()) {
+            // Log compression, so quiet instruments still register
+            let magnitude = (1.0 + 100.0 * bin.norm()).ln();
+
+            flux += (magnitude - *previous).max(0.0);
+            *previous = magnitude;
+        }
+
+        envelope.push(flux);
+    }
+
+    // The first frame is compared against silence
+    if let Some(first) = envelope.first_mut() {
+        *first = 0.0;
+    }
+
+    // Only keep what sticks out from the local average
+    const AVERAGE_FRAMES: usize = 16;
+
+    (0..envelope.len())
+        .map(|i| {
+            let window = &envelope
+                [i.saturating_sub(AVERAGE_FRAMES)..(i + AVERAGE_FRAMES).min(envelope.len())];
+            let average = window.iter().sum::<f64>() / window.len() as f64;
+
+            (envelope[i] - average).max(0.0)
+        })
+        .collect()
+}
+
+fn autocorrelation(envelope: &[f64], max_lag: usize) -> Vec<f64> {
+    (0..=max_lag)
+        .map(|lag| {
+            envelope
+                .iter()
+                .zip(&envelope[lag..])
+                .map(|(a, b)| a * b)
+                .sum::<f64>()
+                / (envelope.len() - lag) as f64
+        })
+        .collect()
+}
+
+/// Linear interpolation between lags
+fn interpolate(values: &[f64], position: f64) -> f64 {
+    let index = position.floor() as usize;
+    let fraction = position - index as f64;
+
+    match (values.get(index), values.get(index + 1)) {
+        (Some(a), Some(b)) => a + (b - a) * fraction,
+        (Some(a), None) => *a,
+        _ => 0.0,
+    }
+}
+
+/// The candidate tempos are 0.1 BPM apart and the autocorrelation is coarse, so find the
+/// actual peak around the longest lag, where the resolution is highest
+fn refine(acf: &[f64], bpm: f64, frame_rate: f64) -> f64 {
+    let target = (60.0 * frame_rate / bpm * MAX_HARMONIC).round() as usize;
+
+    let peak = (target.saturating_sub(2)..=(target + 2).min(acf.len() - 2))
+        .max_by(|a, b| acf[*a].total_cmp(&acf[*b]))
+        .unwrap_or(target);
+
+    if peak == 0 || peak + 1 >= acf.len() {
+        return bpm;
+    }
+
+    // Parabolic interpolation
+    let (a, b, c) = (acf[peak - 1], acf[peak], acf[peak + 1]);
+    let denominator = a - 2.0 * b + c;
+    let offset = if denominator == 0.0 {
+        0.0
+    } else {
+        0.5 * (a - c) / denominator
+    };
+
+    let refined = 60.0 * frame_rate * MAX_HARMONIC / (peak as f64 + offset);
+
+    // Don't let a neighbouring peak pull it too far
+    if (refined - bpm).abs() > 2.0 {
+        return bpm;
+    }
+
+    (refined * 10.0).round() / 10.0
+}
+
+/// Estimate the tempo of mono audio in BPM
+pub(crate) fn detect_tempo(samples: &[f32], sample_rate: u32) -> Option<f64> {
+    let envelope = onset_envelope(&resample(samples, sample_rate, SAMPLE_RATE));
+    let frame_rate = SAMPLE_RATE as f64 / HOP_SIZE as f64;
+
+    let max_lag = (60.0 * frame_rate / MIN_BPM * MAX_HARMONIC).ceil() as usize + 1;
+
+    // Need a few bars of audio
+    if envelope.len() < max_lag * 2 {
+        return None;
+    }
+
+    let acf = autocorrelation(&envelope, max_lag);
+
+    let (bpm, score) = (0..=((MAX_BPM - MIN_BPM) * 10.0) as usize)
+        .map(|i| {
+            let bpm = MIN_BPM + i as f64 / 10.0;
+            let lag = 60.0 * frame_rate / bpm;
+
+            let periodicity: f64 = HARMONICS
+                .iter()
+                .map(|harmonic| interpolate(&acf, lag * harmonic))
+                .sum();
+
+            let prior = (-0.5 * ((bpm / PRIOR_BPM).log2() / PRIOR_OCTAVES).powi(2)).exp();
+
+            (bpm, periodicity * prior)
+        })
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))?;
+
+    if score <= 0.0 {
+        return None;
+    }
+
+    Some(refine(&acf, bpm, frame_rate))
+}
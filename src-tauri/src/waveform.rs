@@ -0,0 +1,162 @@
+/*
+    Waveform peaks for seek bars
+*/
+
+use crate::decode::{decode_file, mix_to_mono};
+use log::{debug, warn};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tauri::{AppHandle, Manager};
+
+const MAX_RESOLUTION: usize = 10000;
+
+// Peaks are first collected per block of this many frames, then merged down to the resolution
+const BLOCK_SIZE: usize = 256;
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Waveform {
+    /// Minimum and maximum sample of each slice of the track, between -1 and 1
+    peaks: Vec<[f32; 2]>,
+}
+
+/// Cached by file, the size and modification time change when a file is replaced or edited
+fn cache_path(
+    app_handle: &AppHandle,
+    location: &Path,
+    resolution: usize,
+) -> Result<PathBuf, String> {
+    let metadata = std::fs::metadata(location).map_err(|e| e.to_string())?;
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |duration| duration.as_secs());
+
+    Ok(app_handle
+        .path()
+        .app_cache_dir()
+        .map_err(|e| e.to_string())?
+        .join("waveforms")
+        .join(format!(
+            "{:x}-{}.bin",
+            md5::compute(format!(
+                "{}-{}-{}",
+                location.to_string_lossy(),
+                metadata.len(),
+                modified
+            )),
+            resolution
+        )))
+}
+
+fn read_cache(path: &Path) -> Option<Vec<[f32; 2]>> {
+    let bytes = std::fs::read(path).ok()?;
+
+    Some(
+        bytes
+            .chunks_exact(8)
+            .map(|chunk| {
+                [
+                    f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]),
+                    f32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]),
+                ]
+            })
+            .collect(),
+    )
+}
+
+fn write_cache(path: &Path, peaks: &[[f32; 2]]) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+
+    let bytes: Vec<u8> = peaks
+        .iter()
+        .flat_map(|[min, max]| min.to_le_bytes().into_iter().chain(max.to_le_bytes()))
+        .collect();
+
+    std::fs::write(path, bytes).map_err(|e| e.to_string())
+}
+
+/// Merge block peaks down to `resolution` slices
+fn downsample(blocks: &[[f32; 2]], resolution: usize) -> Vec<[f32; 2]> {
+    if blocks.is_empty() {
+        return vec![[0.0, 0.0]; resolution];
+    }
+
+    (0..resolution)
+        .map(|i| {
+            let start = i * blocks.len() / resolution;
+            let end = ((i + 1) * blocks.len() / resolution).max(start + 1);
+
+            blocks[start..end.min(blocks.len())].iter().fold(
+                [0.0, 0.0],
+                |[min, max]: [f32; 2], [block_min, block_max]| {
+                    [min.min(*block_min), max.max(*block_max)]
+                },
+            )
+        })
+        .collect()
+}
+
+/// Decode a track and compute the peaks of each block
+fn analyze(path: &Path) -> Result<Vec<[f32; 2]>, String> {
+    let mut blocks = vec![];
+    let mut block = [0.0f32, 0.0f32];
+    let mut block_len = 0;
+
+    decode_file(path, None, |samples, format| {
+        for sample in mix_to_mono(samples, format.channels) {
+            block = [block[0].min(sample), block[1].max(sample)];
+            block_len += 1;
+
+            if block_len == BLOCK_SIZE {
+                blocks.push(block);
+                block = [0.0, 0.0];
+                block_len = 0;
+            }
+        }
+
+        true
+    })?;
+
+    if block_len > 0 {
+        blocks.push(block);
+    }
+
+    Ok(blocks)
+}
+
+/// Get the waveform of a track as `resolution` min/max pairs. Only decodes the track, so it is
+/// quick enough to prefetch the next track in the queue, and is cached after the first time.
+#[tauri::command(async)]
+pub fn get_waveform(
+    app_handle: AppHandle,
+    location: String,
+    resolution: usize,
+) -> Result<Waveform, String> {
+    if resolution == 0 || resolution > MAX_RESOLUTION {
+        return Err(format!(
+            "Resolution must be between 1 and {}",
+            MAX_RESOLUTION
+        ));
+    }
+
+    let path = Path::new(&location);
+    let cache_path = cache_path(&app_handle, path, resolution)?;
+
+    if let Some(peaks) = read_cache(&cache_path).filter(|peaks| peaks.len() == resolution) {
+        return Ok(Waveform { peaks });
+    }
+
+    debug!("Generating waveform for {}", location);
+
+    let peaks = downsample(&analyze(path)?, resolution);
+
+    if let Err(err) = write_cache(&cache_path, &peaks) {
+        warn!("Failed to cache waveform of {}: {}", location, err);
+    }
+
+    Ok(Waveform { peaks })
+}
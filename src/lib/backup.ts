@@ -0,0 +1,99 @@
+import { invoke } from '@tauri-apps/api/core';
+import { open, save } from '@tauri-apps/plugin-dialog';
+import type { KV, Playlist } from './db';
+import db from './db';
+import { currentLibrary, storeLibrary, type Library } from './library';
+import { playlists } from './stores';
+
+export type BackupInfo = {
+    version: number;
+    createdAt: string;
+    tracks: number;
+    playlists: number;
+    plays: number;
+    /** Music directories of the backed up library */
+    musicDirectories: string[];
+};
+
+/** Paths starting with `from` are moved to `to` when restoring */
+export type PathRemap = { from: string; to: string };
+
+// Dates are strings once they went through JSON
+type StoredPlaylist = Omit<Playlist, 'createdAt' | 'updatedAt'> & {
+    createdAt: string;
+    updatedAt: string;
+};
+
+/** Back up the library, playlists, settings, listening history and overrides to a file */
+export async function exportAndSaveLibrary() {
+    const date = new Date().toISOString().slice(0, 10);
+
+    const loc = await save({
+        title: 'Back up library',
+        defaultPath: `cleftly-backup-${date}.json`
+    });
+
+    if (!loc) return null;
+
+    await invoke('export_library', {
+        path: loc,
+        library: await currentLibrary(),
+        playlists: await db.playlists.toArray(),
+        kvs: await db.kvs.toArray()
+    });
+
+    return loc;
+}
+
+/** Pick a backup file and read its summary, nothing is restored yet */
+export async function selectBackup() {
+    const loc = (await open({
+        title: 'Restore library',
+        filters: [
+            {
+                name: 'Backups',
+                extensions: ['json']
+            }
+        ]
+    })) as string | null;
+
+    if (!loc) return null;
+
+    const info = (await invoke('inspect_backup', { path: loc })) as BackupInfo;
+
+    return { path: loc, info };
+}
+
+/** Replace everything with the contents of a backup */
+export async function importLibrary(path: string, remaps: PathRemap[] = []) {
+    const restored = (await invoke('import_library', {
+        path,
+        remaps: remaps.filter((remap) => remap.from !== remap.to)
+    })) as {
+        library: Library;
+        playlists: StoredPlaylist[];
+        kvs: KV[];
+    };
+
+    const restoredPlaylists: Playlist[] = restored.playlists.map(
+        (playlist) => ({
+            ...playlist,
+            createdAt: new Date(playlist.createdAt),
+            updatedAt: new Date(playlist.updatedAt)
+        })
+    );
+
+    await db.transaction('rw', [db.playlists, db.kvs], async () => {
+        await db.playlists.clear();
+        await db.kvs.clear();
+        await db.playlists.bulkPut(restoredPlaylists);
+        await db.kvs.bulkPut(restored.kvs);
+    });
+
+    playlists.set(
+        await db.playlists.orderBy('updatedAt').reverse().toArray()
+    );
+
+    // Also refreshes smart playlists and the library roots
+    await storeLibrary(restored.library);
+}
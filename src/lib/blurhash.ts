@@ -0,0 +1,141 @@
+/*
+ * Decoding of BlurHash placeholders for album art, following the reference implementation
+ * (https://github.com/woltapp/blurhash)
+ */
+
+const CHARACTERS =
+    '0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~';
+
+/** Size of the decoded image, it is scaled up with CSS */
+const SIZE = 32;
+
+const cache = new Map<string, string>();
+
+function decode83(str: string) {
+    let value = 0;
+
+    for (const c of str) {
+        value = value * 83 + CHARACTERS.indexOf(c);
+    }
+
+    return value;
+}
+
+function srgbToLinear(value: number) {
+    const v = value / 255;
+
+    return v <= 0.04045 ? v / 12.92 : Math.pow((v + 0.055) / 1.055, 2.4);
+}
+
+function linearToSrgb(value: number) {
+    const v = Math.max(0, Math.min(1, value));
+
+    return Math.round(
+        v <= 0.0031308
+            ? v * 12.92 * 255
+            : (1.055 * Math.pow(v, 1 / 2.4) - 0.055) * 255
+    );
+}
+
+function signPow(value: number, exp: number) {
+    return Math.sign(value) * Math.pow(Math.abs(value), exp);
+}
+
+/** Pixels of a placeholder as RGBA */
+function decode(hash: string, width: number, height: number) {
+    const sizeFlag = decode83(hash[0]);
+    const componentsX = (sizeFlag % 9) + 1;
+    const componentsY = Math.floor(sizeFlag / 9) + 1;
+
+    if (hash.length !== 4 + 2 * componentsX * componentsY) {
+        throw new Error(`Invalid BlurHash length: ${hash}`);
+    }
+
+    const maxValue = (decode83(hash[1]) + 1) / 166;
+    const colors: number[][] = [];
+
+    for (let i = 0; i < componentsX * componentsY; i++) {
+        if (i === 0) {
+            const value = decode83(hash.substring(2, 6));
+
+            colors.push([
+                srgbToLinear(value >> 16),
+                srgbToLinear((value >> 8) & 255),
+                srgbToLinear(value & 255)
+            ]);
+        } else {
+            const value = decode83(hash.substring(4 + i * 2, 6 + i * 2));
+
+            colors.push(
+                [
+                    Math.floor(value / (19 * 19)),
+                    Math.floor(value / 19) % 19,
+                    value % 19
+                ].map((quant) => signPow((quant - 9) / 9, 2) * maxValue)
+            );
+        }
+    }
+
+    const pixels = new Uint8ClampedArray(width * height * 4);
+
+    for (let y = 0; y < height; y++) {
+        for (let x = 0; x < width; x++) {
+            let r = 0;
+            let g = 0;
+            let b = 0;
+
+            for (let j = 0; j < componentsY; j++) {
+                for (let i = 0; i < componentsX; i++) {
+                    const basis =
+                        Math.cos((Math.PI * x * i) / width) *
+                        Math.cos((Math.PI * y * j) / height);
+                    const color = colors[i + j * componentsX];
+
+                    r += color[0] * basis;
+                    g += color[1] * basis;
+                    b += color[2] * basis;
+                }
+            }
+
+            const index = 4 * (x + y * width);
+
+            pixels[index] = linearToSrgb(r);
+            pixels[index + 1] = linearToSrgb(g);
+            pixels[index + 2] = linearToSrgb(b);
+            pixels[index + 3] = 255;
+        }
+    }
+
+    return pixels;
+}
+
+/** Data URL of a placeholder, for use as a background image */
+export function blurhashToDataUrl(hash: string | undefined) {
+    if (!hash) return undefined;
+
+    const cached = cache.get(hash);
+    if (cached) return cached;
+
+    try {
+        const canvas = document.createElement('canvas');
+        canvas.width = SIZE;
+        canvas.height = SIZE;
+
+        const ctx = canvas.getContext('2d');
+        if (!ctx) return undefined;
+
+        ctx.putImageData(
+            new ImageData(decode(hash, SIZE, SIZE), SIZE, SIZE),
+            0,
+            0
+        );
+
+        const url = canvas.toDataURL();
+        cache.set(hash, url);
+
+        return url;
+    } catch (err) {
+        console.error(err);
+        return undefined;
+    }
+}
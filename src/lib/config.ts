@@ -6,6 +6,14 @@ import { platform } from '@tauri-apps/plugin-os';
 export type Config = {
     version: number;
     music_directories: string[];
+    exclude_globs: string[];
+    follow_symlinks: boolean;
+    cross_filesystems: boolean;
+    scan_max_depth: number | null;
+    sort_articles: string[];
+    /** Where the dates new tracks were added come from */
+    date_added: 'created' | 'modified' | 'scanned';
+    write_replay_gain: boolean;
     setup_done: boolean;
     lastfm_token: string;
     audio_backend: 'native' | 'web';
@@ -24,6 +32,27 @@ export type Config = {
 const DEFAULT_CONFIG = {
     version: 0,
     music_directories: [],
+    exclude_globs: [],
+    follow_symlinks: true,
+    cross_filesystems: true,
+    scan_max_depth: null,
+    sort_articles: [
+        'The',
+        'A',
+        'An',
+        'Der',
+        'Die',
+        'Das',
+        'Le',
+        'La',
+        'Les',
+        'El',
+        'Los',
+        'Las',
+        'Il'
+    ],
+    date_added: 'created',
+    write_replay_gain: false,
     setup_done: false,
     lastfm_token: '',
     audio_backend: 'web',
@@ -7,12 +7,24 @@ import { get } from 'svelte/store';
 
 import { playlists } from './stores';
 
+export interface Loudness {
+    /** Integrated loudness in LUFS */
+    integrated: number;
+    /** Loudness range in LU */
+    range: number;
+    /** Linear, 1.0 is full scale */
+    truePeak: number;
+}
+
 export interface Track {
     id: string;
     location: string;
     type?: 'local' | 'http'; // TODO: For future use
 
     title: string;
+    /** The TITLESORT tag, or the title with a leading article moved to the end */
+    sortTitle?: string;
+    taggedSortTitle?: string;
     artistId: string;
     albumId: string;
     albumArt?: string;
@@ -26,31 +38,148 @@ export interface Track {
     totalDiscs: number;
     createdAt: Date;
 
+    // Derived from the listening history
+    /** Unset for tracks that were never played */
     lastPlayedAt?: Date;
+    playCount?: number;
+    skipCount?: number;
+    /** 1 to 5 stars */
+    rating?: number;
+
+    /** The track's music directory is currently unavailable */
+    offline?: boolean;
+    /** Hidden from the library, e.g. a duplicate the user chose not to keep */
+    hidden?: boolean;
+
+    bitrate?: number;
+    sampleRate?: number;
+    bitDepth?: number;
+    fileSize?: number;
+
+    /** Compressed Chromaprint fingerprint, computed in the background */
+    fingerprint?: string;
+    /** EBU R128 loudness, computed in the background */
+    loudness?: Loudness;
+
+    /** From tags, or estimated in the background */
+    bpm?: number;
+    /** Standard notation, e.g. "Am" or "F#" */
+    key?: string;
+    /** Camelot wheel notation, e.g. "8A" */
+    camelot?: string;
+
+    // Classical music
+    composerId?: string;
+    conductor?: string;
+    performer?: string;
+    /** The work this track is a movement of */
+    work?: string;
+    movement?: string;
+    movementNum?: number;
+    movementTotal?: number;
+}
+
+/** Colors of album art as `#rrggbb` */
+export interface Palette {
+    dominant: string;
+    vibrant: string;
+    muted: string;
+    /** Black or white, whichever is readable on the dominant color */
+    text: string;
 }
 
 export interface Album {
     id: string;
     name: string;
+    /** The ALBUMSORT tag, or the name with a leading article moved to the end */
+    sortName?: string;
+    taggedSortName?: string;
     genres: string[];
     artistId: string;
     albumArt?: string;
     animatedAlbumArt?: string;
+    palette?: Palette;
+    /** BlurHash of the album art, shown while it loads */
+    blurhash?: string;
     createdAt: Date;
     year: number | undefined;
+    /** Partial ISO 8601 dates, "1969", "1969-09" or "1969-09-26" */
+    releaseDate?: string;
+    /** First release of a reissue or remaster */
+    originalDate?: string;
+
+    loudness?: Loudness;
 }
 
 export interface Artist {
     id: string;
     name: string;
+    /** The ALBUMARTISTSORT or ARTISTSORT tag, or the name with a leading article moved to the end */
+    sortName?: string;
+    taggedSortName?: string;
     genres: string[];
     createdAt: Date;
 }
 
+export interface Composer {
+    id: string;
+    name: string;
+    /** The COMPOSERSORT tag, or the name with a leading article moved to the end */
+    sortName?: string;
+    taggedSortName?: string;
+    createdAt: Date;
+}
+
+export interface LibraryRoot {
+    path: string;
+    available: boolean;
+}
+
+export type SmartPlaylistRule =
+    | { type: 'all'; rules: SmartPlaylistRule[] }
+    | { type: 'any'; rules: SmartPlaylistRule[] }
+    | { type: 'not'; rule: SmartPlaylistRule }
+    | { type: 'genre'; genre: string }
+    | { type: 'year'; min?: number; max?: number }
+    | { type: 'addedWithinDays'; days: number }
+    | { type: 'playCount'; min?: number; max?: number }
+    | { type: 'playedWithinDays'; days: number }
+    /** In seconds */
+    | { type: 'duration'; min?: number; max?: number }
+    /** File extensions, e.g. `['flac', 'wav']` */
+    | { type: 'format'; formats: string[] }
+    | { type: 'rating'; min?: number; max?: number }
+    // Glob matched against the track's location, e.g. `**/Soundtracks/**`
+    | { type: 'path'; glob: string };
+
+export type SmartPlaylistSortField =
+    | 'title'
+    | 'artist'
+    | 'album'
+    | 'year'
+    | 'added'
+    | 'lastPlayed'
+    | 'playCount'
+    | 'rating'
+    | 'duration'
+    | 'bpm'
+    | 'random';
+
+export interface SmartPlaylistRules {
+    rule: SmartPlaylistRule;
+    sort?: { field: SmartPlaylistSortField; descending?: boolean };
+    /** Maximum number of tracks */
+    limit?: number;
+    /** Stop adding tracks once they add up to this many minutes */
+    limitMinutes?: number;
+}
+
 export interface Playlist {
     id: string;
     name: string;
+    /** For smart playlists, the result of the last evaluation of `smart` */
     trackIds: string[];
+    smart?: SmartPlaylistRules;
     createdAt: Date;
     updatedAt: Date;
 }
@@ -82,6 +211,7 @@ export class Database extends Dexie {
     tracks!: Table<Track>;
     albums!: Table<Album>;
     artists!: Table<Artist>;
+    composers!: Table<Composer>;
     playlists!: Table<Playlist>;
     kvs!: Table<KV>;
 
@@ -94,6 +224,13 @@ export class Database extends Dexie {
             playlists: 'id, name, tracks, createdAt, updatedAt',
             kvs: 'key, value'
         });
+        this.version(0.2).stores({
+            tracks: 'id, title, artistId, albumId, albumArt, genres, duration, trackNum, totalTracks, type, createdAt, discNum, totalDiscs, lastPlayedAt, bpm, key, camelot'
+        });
+        this.version(0.3).stores({
+            tracks: 'id, title, artistId, albumId, albumArt, genres, duration, trackNum, totalTracks, type, createdAt, discNum, totalDiscs, lastPlayedAt, bpm, key, camelot, composerId',
+            composers: 'id, name, createdAt'
+        });
     }
 
     async friendlyTrack(track: Track): Promise<FriendlyTrack> {
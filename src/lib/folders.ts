@@ -0,0 +1,75 @@
+import { invoke } from '@tauri-apps/api/core';
+import db, { type FriendlyTrack } from './db';
+import { currentLibrary } from './library';
+import { playable, playTrack } from './player';
+import { queue } from './stores';
+
+export type FolderSummary = {
+    path: string;
+    name: string;
+    /** Album art of the first track with art in the folder or its subfolders */
+    cover?: string;
+    /** Tracks in the folder and its subfolders */
+    trackCount: number;
+    /** In seconds, of all tracks in the folder and its subfolders */
+    duration: number;
+};
+
+export type Folder = Omit<FolderSummary, 'path'> & {
+    /** Null for the list of music directories */
+    path: string | null;
+    folders: FolderSummary[];
+    /** Tracks directly in the folder, by file name */
+    trackIds: string[];
+};
+
+/** A folder with its subfolders and tracks, or the music directories without `path` */
+export async function browseFolder(path?: string | null): Promise<Folder> {
+    return (await invoke('browse_folder', {
+        library: await currentLibrary(),
+        path: path ?? null
+    })) as Folder;
+}
+
+export async function friendlyTracks(ids: string[]): Promise<FriendlyTrack[]> {
+    return await Promise.all(
+        (await db.tracks.bulkGet(ids))
+            .filter((track) => track !== undefined)
+            .map(async (track) => await db.friendlyTrack(track!))
+    );
+}
+
+/** All tracks in a folder and its subfolders, in folder order */
+export async function folderTracks(path: string): Promise<FriendlyTrack[]> {
+    const ids = (await invoke('folder_tracks', {
+        library: await currentLibrary(),
+        path
+    })) as string[];
+
+    return await friendlyTracks(ids);
+}
+
+/** Play a folder and everything in its subfolders */
+export async function playFolder(path: string, shuffle = false) {
+    const tracks = playable(await folderTracks(path));
+
+    if (tracks.length === 0) return;
+
+    const first = shuffle
+        ? tracks[Math.floor(Math.random() * tracks.length)]
+        : tracks[0];
+
+    await playTrack(first, tracks, undefined, shuffle);
+}
+
+/** Add a folder and everything in its subfolders to the end of the queue */
+export async function enqueueFolder(path: string) {
+    const tracks = playable(await folderTracks(path));
+
+    queue.update((q) => {
+        q.tracks.push(...tracks);
+        return q;
+    });
+
+    return tracks.length;
+}
@@ -3,14 +3,17 @@ Access and management of user library (Music directories, playlists, etc.)
 */
 import { BaseDirectory, writeFile } from '@tauri-apps/plugin-fs';
 import { invoke, convertFileSrc } from '@tauri-apps/api/core';
+import { open } from '@tauri-apps/plugin-dialog';
 import { get } from 'svelte/store';
 import { join } from '@tauri-apps/api/path';
 import { md5 } from 'js-md5';
 import db, {
     type Artist,
     type Album,
+    type Composer,
     type Track,
-    type FriendlyTrack
+    type FriendlyTrack,
+    type LibraryRoot
 } from './db';
 import { getOrCreateCacheDir, getOrCreateConfig } from './config';
 import { playlists, progress } from './stores';
@@ -99,6 +102,88 @@ export async function saveArt(id: string, art: Blob | null) {
     );
 }
 
+export type Library = {
+    tracks: Track[];
+    artists: Artist[];
+    composers: Composer[];
+    albums: Album[];
+    roots: LibraryRoot[];
+};
+
+/** The library in the shape the backend expects */
+export async function currentLibrary(): Promise<Library> {
+    return {
+        tracks: await db.tracks.toArray(),
+        artists: await db.artists.toArray(),
+        composers: await db.composers.toArray(),
+        albums: await db.albums.toArray(),
+        roots: ((await db.kvs.get('libraryRoots'))?.value ??
+            []) as LibraryRoot[]
+    };
+}
+
+/** Replace the stored library with one returned by the backend */
+export async function storeLibrary(library: Library) {
+    // Convert album art locations into stream URLs
+    library.albums.forEach((t) => {
+        // TODO: Proper solution for this
+        if (t.albumArt) {
+            if (
+                !['stream://', 'http://', 'https://'].some((prefix) =>
+                    t.albumArt?.startsWith(prefix)
+                )
+            ) {
+                t.albumArt = convertFileSrc(t.albumArt, 'stream');
+            }
+        }
+
+        if (t.animatedAlbumArt) {
+            if (
+                !['stream://', 'http://', 'https://'].some((prefix) =>
+                    t.animatedAlbumArt?.startsWith(prefix)
+                )
+            ) {
+                t.animatedAlbumArt = convertFileSrc(
+                    t.animatedAlbumArt,
+                    'stream'
+                );
+            }
+        }
+    });
+
+    await db.transaction(
+        'rw',
+        [db.tracks, db.artists, db.composers, db.albums, db.kvs],
+        async () => {
+            await db.tracks.clear();
+            await db.artists.clear();
+            await db.composers.clear();
+            await db.albums.clear();
+            await db.tracks.bulkAdd(library.tracks);
+            await db.artists.bulkAdd(library.artists);
+            await db.composers.bulkAdd(library.composers ?? []);
+            await db.albums.bulkAdd(library.albums);
+            await db.kvs.put({
+                key: 'libraryRoots',
+                value: library.roots
+            });
+        }
+    );
+
+    refreshSmartPlaylists().catch((err) => {
+        console.error(err);
+        console.error('Failed to refresh smart playlists');
+    });
+
+    eventManager
+        .fireEvent('onLibraryUpdate', library)
+        .then(() => {})
+        .catch((err) => {
+            console.error(err);
+            console.error('Failed to fire event onLibraryUpdate');
+        });
+}
+
 export async function updateLibrary() {
     progress.set(
         get(progress).set('updateLibrary', {
@@ -115,62 +200,18 @@ export async function updateLibrary() {
 
     await invoke('update_library', {
         musicDirectories: config.music_directories,
-        library: {
-            tracks: await db.tracks.toArray(),
-            artists: await db.artists.toArray(),
-            albums: await db.albums.toArray()
-        }
+        scanOptions: {
+            excludeGlobs: config.exclude_globs,
+            followSymlinks: config.follow_symlinks,
+            crossFilesystems: config.cross_filesystems,
+            maxDepth: config.scan_max_depth,
+            sortArticles: config.sort_articles,
+            dateAdded: config.date_added
+        },
+        library: await currentLibrary()
     })
-        .then((newLibrary: unknown) => {
-            const library = newLibrary as {
-                tracks: Track[];
-                artists: Artist[];
-                albums: Album[];
-            };
-
-            // Convert album art locations into stream URLs
-            library.albums.forEach((t) => {
-                // TODO: Proper solution for this
-                if (t.albumArt) {
-                    if (
-                        !['stream://', 'http://', 'https://'].some((prefix) =>
-                            t.albumArt?.startsWith(prefix)
-                        )
-                    ) {
-                        t.albumArt = convertFileSrc(t.albumArt, 'stream');
-                    }
-                }
-
-                if (t.animatedAlbumArt) {
-                    if (
-                        !['stream://', 'http://', 'https://'].some((prefix) =>
-                            t.animatedAlbumArt?.startsWith(prefix)
-                        )
-                    ) {
-                        t.animatedAlbumArt = convertFileSrc(
-                            t.animatedAlbumArt,
-                            'stream'
-                        );
-                    }
-                }
-            });
-
-            db.transaction('rw', db.tracks, db.artists, db.albums, async () => {
-                await db.tracks.clear();
-                await db.artists.clear();
-                await db.albums.clear();
-                await db.tracks.bulkAdd(library.tracks);
-                await db.artists.bulkAdd(library.artists);
-                await db.albums.bulkAdd(library.albums);
-            });
-
-            eventManager
-                .fireEvent('onLibraryUpdate', library)
-                .then(() => {})
-                .catch((err) => {
-                    console.error(err);
-                    console.error('Failed to fire event onLibraryUpdate');
-                });
+        .then(async (newLibrary: unknown) => {
+            await storeLibrary(newLibrary as Library);
         })
         .catch((e) => {
             console.error(e);
@@ -183,6 +224,121 @@ export async function updateLibrary() {
             progress: 1
         })
     );
+
+    startAnalysis({ writeReplayGain: config.write_replay_gain }).catch((err) => {
+        console.error(err);
+        console.error('Failed to start library analysis');
+    });
+}
+
+/**
+ * Analyze tracks (acoustic fingerprints, loudness, tempo and key) in the background.
+ * Results are stored as they come in, see `taurievents.ts`.
+ */
+export async function startAnalysis(
+    options: {
+        fingerprint?: boolean;
+        loudness?: boolean;
+        /** Estimate BPM and key of tracks that aren't tagged with them */
+        tempoAndKey?: boolean;
+        /** Write REPLAYGAIN_* tags to files that don't have them */
+        writeReplayGain?: boolean;
+        reanalyze?: boolean;
+    } = {}
+) {
+    await invoke('start_analysis', {
+        library: await currentLibrary(),
+        options
+    });
+}
+
+export async function cancelAnalysis() {
+    await invoke('cancel_analysis');
+}
+
+export type TagChanges = {
+    title?: string;
+    artist?: string;
+    album?: string;
+    albumArtist?: string;
+    trackNum?: number;
+    totalTracks?: number;
+    discNum?: number;
+    totalDiscs?: number;
+    year?: number;
+    genres?: string[];
+    /** Path to an image file */
+    coverArt?: string;
+    removeCoverArt?: boolean;
+};
+
+export type TagEditResult = {
+    location: string;
+    changes: { field: string; old?: string; new?: string }[];
+    backup?: string;
+    /** Files are either fully written or left as they were */
+    written: boolean;
+    error?: string;
+};
+
+/**
+ * Write tags to one or more files and update the library with the result.
+ * With `dryRun`, only returns the changes that would be made.
+ */
+export async function writeTags(
+    edits: { location: string; changes: TagChanges }[],
+    { dryRun = false, backup = true } = {}
+): Promise<TagEditResult[]> {
+    const res = (await invoke('write_tags', {
+        library: await currentLibrary(),
+        edits,
+        dryRun,
+        backup
+    })) as { results: TagEditResult[]; library: Library };
+
+    if (!dryRun) {
+        await storeLibrary(res.library);
+    }
+
+    return res.results;
+}
+
+let searchIndexed = false;
+
+/**
+ * Search titles, artists, albums and genres, tolerating typos and accents.
+ * Prefix words with `title:`, `artist:`, `album:`, `genre:` or `lyrics:` to search one field,
+ * e.g. `artist:"the beatles" help`. Returns track ids, best matches first.
+ */
+export async function searchLibrary(
+    query: string,
+    limit?: number
+): Promise<string[]> {
+    // Commands that change the library keep the index up to date after this
+    if (!searchIndexed) {
+        await invoke('index_library', { library: await currentLibrary() });
+        searchIndexed = true;
+    }
+
+    return (await invoke('search_library', { query, limit })) as string[];
+}
+
+export const WAVEFORM_RESOLUTION = 512;
+
+/**
+ * Get min/max peaks of a track for drawing its waveform. Waveforms are cached on disk,
+ * so this is cheap for tracks that were loaded before.
+ */
+export async function getWaveform(
+    track: Track,
+    resolution: number = WAVEFORM_RESOLUTION
+): Promise<[number, number][]> {
+    const res = (await invoke('get_waveform', {
+        location: track.location,
+        resolution
+    })) as { peaks: [number, number][] };
+
+    return res.peaks;
 }
 
 export async function getLibrary(): Promise<FriendlyTrack[]> {
@@ -190,11 +346,12 @@ export async function getLibrary(): Promise<FriendlyTrack[]> {
 
     console;
     return await Promise.all(
-        (
-            await db.tracks.toArray()
-        ).map(async (t) => {
-            return await db.friendlyTrack(t);
-        })
+        (await db.tracks.toArray())
+            // Tracks hidden as duplicates stay in the library but aren't listed
+            .filter((t) => !t.hidden)
+            .map(async (t) => {
+                return await db.friendlyTrack(t);
+            })
     );
 }
 
@@ -236,3 +393,345 @@ export async function toggleFavorite(trackId: string) {
         ]);
     }
 }
+
+/** Add a play to the listening history and update the track's play stats */
+export async function recordPlay(play: {
+    trackId: string;
+    startedAt: Date;
+    listenedMs: number;
+    outcome: 'completed' | 'skipped';
+}) {
+    const stats = (await invoke('record_play', {
+        play: { ...play, listenedMs: Math.round(play.listenedMs) }
+    })) as { playCount: number; skipCount: number; lastPlayedAt?: string };
+
+    await db.tracks.update(play.trackId, {
+        playCount: stats.playCount,
+        skipCount: stats.skipCount,
+        lastPlayedAt: stats.lastPlayedAt
+            ? new Date(stats.lastPlayedAt)
+            : undefined
+    });
+
+    await refreshSmartPlaylists();
+}
+
+export type TopEntry = {
+    /** Track, album or artist id */
+    id: string;
+    plays: number;
+    skips: number;
+    listenedMs: number;
+};
+
+/** Most played tracks, albums and artists of plays started between `from` and `to` */
+export async function getTopListening(
+    { from, to }: { from?: Date; to?: Date } = {},
+    limit: number = 10
+) {
+    return (await invoke('get_top_listening', {
+        library: await currentLibrary(),
+        range: { from, to },
+        limit
+    })) as {
+        tracks: TopEntry[];
+        albums: TopEntry[];
+        artists: TopEntry[];
+        totalPlays: number;
+        totalListenedMs: number;
+    };
+}
+
+/** Rate a track from 1 to 5 stars, or remove its rating */
+export async function setRating(trackId: string, rating?: number) {
+    if (rating !== undefined && (rating < 1 || rating > 5)) {
+        throw new Error('Rating must be between 1 and 5');
+    }
+
+    await db.tracks.update(trackId, { rating });
+    await refreshSmartPlaylists();
+}
+
+/** Re-evaluate the rules of smart playlists and store their tracks */
+export async function refreshSmartPlaylists() {
+    const smartPlaylists = (await db.playlists.toArray()).filter(
+        (playlist) => playlist.smart
+    );
+
+    if (smartPlaylists.length === 0) return;
+
+    const res = (await invoke('evaluate_smart_playlists', {
+        library: await currentLibrary(),
+        playlists: smartPlaylists.map((playlist) => ({
+            id: playlist.id,
+            ...playlist.smart
+        }))
+    })) as { [playlistId: string]: string[] };
+
+    for (const playlist of smartPlaylists) {
+        const trackIds = res[playlist.id];
+
+        if (
+            !trackIds ||
+            (trackIds.length === playlist.trackIds.length &&
+                trackIds.every((id, i) => id === playlist.trackIds[i]))
+        ) {
+            continue;
+        }
+
+        await db.playlists.update(playlist.id, { trackIds });
+    }
+}
+
+export type StatsBreakdown = {
+    /** Unset where it isn't known, e.g. the bit depth of lossy files */
+    key?: string;
+    tracks: number;
+    /** In seconds */
+    duration: number;
+    /** In bytes */
+    size: number;
+};
+
+export type LibraryStats = {
+    tracks: number;
+    albums: number;
+    artists: number;
+    duration: number;
+    size: number;
+    formats: StatsBreakdown[];
+    sampleRates: StatsBreakdown[];
+    bitDepths: StatsBreakdown[];
+    genres: StatsBreakdown[];
+    /** Keyed by the decade's first year, e.g. "1990" */
+    decades: StatsBreakdown[];
+    /** Percentage of tracks with album art, a year, a genre, a title tag, a BPM and a key */
+    completeness: { field: string; missing: number; complete: number }[];
+};
+
+export type Work = {
+    name: string;
+    composerId?: string;
+    /** Ordered by movement */
+    trackIds: string[];
+    albumId: string;
+    /** In seconds */
+    duration: number;
+};
+
+/** Tracks grouped into works, one per recording, optionally only those by one composer */
+export async function groupByWork(composerId?: string): Promise<Work[]> {
+    return (await invoke('group_by_work', {
+        library: await currentLibrary(),
+        composerId
+    })) as Work[];
+}
+
+/** Fields that are left out keep their value from the tags */
+export type TrackOverride = {
+    title?: string;
+    artist?: string;
+    album?: string;
+    trackNum?: number;
+    discNum?: number;
+    genres?: string[];
+};
+
+export type AlbumOverride = {
+    name?: string;
+    artist?: string;
+    year?: number;
+    genres?: string[];
+    albumArt?: string;
+};
+
+export type ArtistOverride = {
+    name?: string;
+    /** Id of the artist this one is merged into */
+    mergeInto?: string;
+};
+
+export type Overrides = {
+    tracks: { [trackId: string]: TrackOverride };
+    albums: { [albumId: string]: AlbumOverride };
+    artists: { [artistId: string]: ArtistOverride };
+};
+
+/** A change to one override, a missing `value` removes it */
+export type OverrideChange =
+    | { kind: 'track'; id: string; value?: TrackOverride }
+    | { kind: 'album'; id: string; value?: AlbumOverride }
+    | { kind: 'artist'; id: string; value?: ArtistOverride };
+
+export async function getOverrides(): Promise<Overrides> {
+    return (await invoke('get_overrides')) as Overrides;
+}
+
+/** Set or remove a metadata override, kept across rescans without touching the files */
+export async function setOverride(change: OverrideChange) {
+    const library = (await invoke('set_override', {
+        library: await currentLibrary(),
+        change
+    })) as Library;
+
+    await storeLibrary(library);
+}
+
+export type AlbumArtOptions = {
+    /** Also embed the art into the album's files */
+    embed?: boolean;
+    /** Also save the art as cover.jpg in the album's folder */
+    writeCoverFile?: boolean;
+};
+
+/**
+ * Use an image file as the art of an album, or go back to the art from its files if `path` is
+ * null. Returns the files the art couldn't be written to.
+ */
+export async function setAlbumArt(
+    albumId: string,
+    path: string | null,
+    options: AlbumArtOptions = {}
+): Promise<string[]> {
+    const res = (await invoke('set_album_art', {
+        library: await currentLibrary(),
+        albumId,
+        path,
+        options
+    })) as { library: Library; errors: string[] };
+
+    await storeLibrary(res.library);
+
+    return res.errors;
+}
+
+/**
+ * Set when tracks were added from an iTunes or Apple Music library export (Library.xml), or a
+ * CSV file with a path and a date on each line
+ */
+export async function importDateAdded() {
+    const path = (await open({
+        title: 'Import dates added',
+        filters: [
+            {
+                name: 'Library exports',
+                extensions: ['xml', 'csv']
+            }
+        ]
+    })) as string | null;
+
+    if (!path) return null;
+
+    const res = (await invoke('import_date_added', {
+        library: await currentLibrary(),
+        path
+    })) as { library: Library; matched: number; unmatched: number };
+
+    await storeLibrary(res.library);
+
+    return { matched: res.matched, unmatched: res.unmatched };
+}
+
+/** Totals and breakdowns of the library, for auditing it */
+export async function getLibraryStats(): Promise<LibraryStats> {
+    return (await invoke('library_stats', {
+        library: await currentLibrary()
+    })) as LibraryStats;
+}
+
+export type FileMove = { from: string; to: string };
+
+export type OrganizeResult = {
+    moves: {
+        trackId: string;
+        from: string;
+        to: string;
+        sidecars: FileMove[];
+        error?: string;
+    }[];
+    albumArt: FileMove[];
+};
+
+/**
+ * Move and rename files based on their metadata, e.g.
+ * `{albumartist}/{year} - {album}/{disc}-{track:02} {title}`.
+ * With `dryRun`, only returns the moves that would be made.
+ */
+export async function organizeLibrary(
+    pattern: string,
+    destination: string,
+    { trackIds, dryRun = false }: { trackIds?: string[]; dryRun?: boolean } = {}
+): Promise<OrganizeResult> {
+    const res = (await invoke('organize_library', {
+        library: await currentLibrary(),
+        pattern,
+        destination,
+        trackIds,
+        dryRun
+    })) as OrganizeResult & { library: Library };
+
+    if (!dryRun) {
+        await storeLibrary(res.library);
+    }
+
+    return { moves: res.moves, albumArt: res.albumArt };
+}
+
+export type DuplicateGroup = {
+    /** Best quality first */
+    tracks: {
+        trackId: string;
+        location: string;
+        lossless: boolean;
+        bitrate?: number;
+        sampleRate?: number;
+        bitDepth?: number;
+        tagCompleteness: number;
+    }[];
+};
+
+export async function findDuplicates(
+    options: {
+        durationTolerance?: number;
+        sameAlbum?: boolean;
+        /** Also match tracks that sound the same (0-1), needs fingerprints */
+        minFingerprintSimilarity?: number;
+    } = {}
+): Promise<DuplicateGroup[]> {
+    return (await invoke('find_duplicates', {
+        library: await currentLibrary(),
+        options
+    })) as DuplicateGroup[];
+}
+
+/**
+ * Keep one track of each group of duplicates and hide or trash the others.
+ * Playlists pointing to trashed tracks are updated to the kept track.
+ */
+export async function resolveDuplicates(
+    resolutions: { keep: string; remove: string[]; action: 'hide' | 'trash' }[]
+): Promise<string[]> {
+    const res = (await invoke('resolve_duplicates', {
+        library: await currentLibrary(),
+        resolutions
+    })) as {
+        library: Library;
+        replaced: { [trackId: string]: string };
+        errors: string[];
+    };
+
+    await storeLibrary(res.library);
+
+    for (const playlist of await db.playlists.toArray()) {
+        if (!playlist.trackIds.some((id) => id in res.replaced)) continue;
+
+        await db.playlists.update(playlist.id, {
+            trackIds: [
+                ...new Set(playlist.trackIds.map((id) => res.replaced[id] ?? id))
+            ],
+            updatedAt: new Date()
+        });
+    }
+
+    return res.errors;
+}
@@ -110,6 +110,7 @@ export async function openTrackMenu(
         items: [
             {
                 text: get(_)('play_now'),
+                enabled: !track.offline,
                 action: () => playTrack(track, [track])
             },
             { item: 'Separator' },
@@ -150,18 +151,21 @@ export async function openTrackMenu(
                         }
                     },
                     { item: 'Separator' },
-                    ...get(playlists).map((playlist) => ({
-                        text: playlist.name,
-                        action: () => {
-                            add(playlist, [track], modalStore, toastStore);
-                        }
-                    }))
+                    ...get(playlists)
+                        .filter((playlist) => !playlist.smart)
+                        .map((playlist) => ({
+                            text: playlist.name,
+                            action: () => {
+                                add(playlist, [track], modalStore, toastStore);
+                            }
+                        }))
                 ]
             }),
 
             { item: 'Separator' },
             {
                 text: get(_)('play_next'),
+                enabled: !track.offline,
                 action: () => {
                     queue.update((q) => {
                         q.tracks.splice(q.index + 1, 0, track);
@@ -176,6 +180,7 @@ export async function openTrackMenu(
             },
             {
                 text: get(_)('play_later'),
+                enabled: !track.offline,
                 action: () => {
                     queue.update((q) => {
                         q.tracks.push(track);
@@ -241,12 +246,28 @@ export async function openTrackMenu(
                         ${get(_)('album')}: ${track.album.name} (${
                             track.albumId
                         })<br>
+                        ${
+                            track.work
+                                ? `${get(_)('work')}: ${track.work}<br>
+                        ${get(_)('movement')}: ${track.movementNum ?? '-'}. ${
+                            track.movement ?? '-'
+                        }<br>`
+                                : ''
+                        }${
+                            track.conductor
+                                ? `${get(_)('conductor')}: ${track.conductor}<br>`
+                                : ''
+                        }${
+                            track.performer
+                                ? `${get(_)('performer')}: ${track.performer}<br>`
+                                : ''
+                        }
                         ${get(_)('location')}: ${track.location}<br>
                         ${get(_)('album_art')}: ${track.album.albumArt}<br>
                         ${get(_)('animated_album_art')}: ${
                             track.album.animatedAlbumArt
                         }<br>
-                        ${get(_)('last_played_at')}: ${track.lastPlayedAt}<br>
+                        ${get(_)('last_played_at')}: ${track.lastPlayedAt ?? '-'}<br>
                         ${get(_)('created_at')}: ${track.createdAt}<br>
                         ${get(_)('duration')}: ${getTimestamp(
                             track.duration
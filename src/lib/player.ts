@@ -8,12 +8,41 @@ import type { FriendlyTrack } from './db';
 import { nowPlaying } from './integrations/lastfm';
 import { getStreamUrl } from './utils';
 import { getOrCreateConfig } from './config';
-import db from './db';
 import { eventManager } from './events';
+import { getWaveform, recordPlay } from './library';
 import WebBackend from './backends/web';
 import NativeBackend from './backends/native';
 import { INITIAL_AUDIO, audio, player, queue } from '$lib/stores';
 
+// Stopping this close to the end still counts as listening to the whole track
+const COMPLETED_MARGIN = 5;
+// Tracks skipped quicker than this weren't really listened to, e.g. while looking for a song
+const MIN_SKIP_LISTENED = 2;
+
+/** Add the current track to the listening history, once it ends or another track starts */
+export function logPlay() {
+    const aud = get(audio);
+
+    if (!aud || aud.logged) return;
+
+    aud.logged = true;
+
+    const completed =
+        aud.duration > 0 && aud.currentTime >= aud.duration - COMPLETED_MARGIN;
+
+    if (!completed && aud.listened < MIN_SKIP_LISTENED) return;
+
+    recordPlay({
+        trackId: aud.track.id,
+        startedAt: aud.playedAt,
+        listenedMs: aud.listened * 1000,
+        outcome: completed ? 'completed' : 'skipped'
+    }).catch((err) => {
+        console.error(err);
+        console.error('Failed to record play');
+    });
+}
+
 export async function play(
     src: string,
     track: FriendlyTrack,
@@ -39,6 +68,8 @@ export async function play(
     //     });
     // }
 
+    logPlay();
+
     audio.set({
         ...INITIAL_AUDIO,
         track,
@@ -110,6 +141,32 @@ export async function play(
             console.error('Failed to find lyrics');
         });
 
+    if ((track.type || 'local') === 'local') {
+        getWaveform(track)
+            .then((waveform) => {
+                const aud = get(audio);
+
+                if (!aud || aud.track.id !== track.id) return;
+
+                audio.set({ ...aud, waveform });
+            })
+            .catch((err) => {
+                console.error(err);
+                console.error('Failed to get waveform');
+            });
+
+        // Have the next track's waveform ready by the time it plays
+        const { tracks, index } = get(queue);
+        const upcoming = tracks[index + 1];
+
+        if (upcoming && (upcoming.type || 'local') === 'local') {
+            getWaveform(upcoming).catch((err) => {
+                console.error(err);
+                console.error('Failed to prefetch waveform');
+            });
+        }
+    }
+
     nowPlaying(track)
         .then((res) => {
             if (res) {
@@ -136,12 +193,6 @@ export async function play(
             console.error(err);
             console.error('Failed to fire event onTrackChange');
         });
-
-    db.tracks
-        .update(track.id, {
-            lastPlayedAt: new Date()
-        })
-        .then(() => {});
 }
 
 export function parseMMMetadata(
@@ -174,12 +225,33 @@ export function parseMMMetadata(
     };
 }
 
+/** Tracks that can be played, tracks in offline music directories are left out */
+export function playable(tracks: FriendlyTrack[]) {
+    return tracks.filter((track) => !track.offline);
+}
+
 export async function playTrack(
     track: FriendlyTrack,
     queued?: FriendlyTrack[],
     index?: number,
     shuffle: boolean = false
 ) {
+    // e.g. playing a whole album that starts with an offline track
+    if (track.offline) {
+        const position = queued?.findIndex((t) => t.id === track.id) ?? -1;
+        const next = queued?.slice(position + 1).find((t) => !t.offline);
+
+        if (!next) return;
+
+        track = next;
+    }
+
+    if (queued && queued.some((t) => t.offline)) {
+        queued = playable(queued);
+        // The index was into the full list, the track is looked up instead
+        index = undefined;
+    }
+
     const backend = (await getOrCreateConfig()).audio_backend;
 
     if (backend === 'web') {
@@ -1,13 +1,19 @@
 import { open, save } from '@tauri-apps/plugin-dialog';
 import { readTextFile, writeTextFile } from '@tauri-apps/plugin-fs';
-import type { FriendlyPlaylist } from './db';
+import type { FriendlyPlaylist, SmartPlaylistRules } from './db';
 import db from './db';
-import { getAlbumId, getOrCreateArtist, idify } from './library';
+import {
+    getAlbumId,
+    getOrCreateArtist,
+    idify,
+    refreshSmartPlaylists
+} from './library';
 
 export type PlaylistExport = {
     name: string;
     createdAt?: Date;
     updatedAt?: Date;
+    smart?: SmartPlaylistRules;
     tracks: {
         id?: string;
         title: string;
@@ -22,6 +28,7 @@ export function exportPlaylist(playlist: FriendlyPlaylist) {
         name: playlist.name,
         createdAt: playlist.createdAt,
         updatedAt: playlist.updatedAt,
+        smart: playlist.smart,
         tracks: playlist.tracks.map((t) => {
             return {
                 id: t.id,
@@ -56,9 +63,36 @@ export async function importPlaylist(playlist: PlaylistExport) {
         id: crypto.randomUUID(),
         name: playlist.name,
         trackIds,
+        smart: playlist.smart,
         createdAt: playlist.createdAt || new Date(),
         updatedAt: playlist.updatedAt || new Date()
     });
+
+    if (playlist.smart) {
+        await refreshSmartPlaylists();
+    }
+}
+
+/** Create or update a smart playlist, its tracks are filled in from its rules */
+export async function saveSmartPlaylist(
+    name: string,
+    smart: SmartPlaylistRules,
+    id: string = crypto.randomUUID()
+) {
+    const existing = await db.playlists.get(id);
+
+    await db.playlists.put({
+        id,
+        name,
+        trackIds: existing?.trackIds ?? [],
+        smart,
+        createdAt: existing?.createdAt ?? new Date(),
+        updatedAt: new Date()
+    });
+
+    await refreshSmartPlaylists();
+
+    return id;
 }
 
 export async function selectAndImportPlaylist() {
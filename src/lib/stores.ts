@@ -20,7 +20,13 @@ type Audio = {
     duration: number;
     scrobbled: boolean; // For .fm scrobbling
     playedAt: Date;
+    /** Seconds actually listened, not counting pauses and seeking */
+    listened: number;
+    /** Added to the listening history */
+    logged: boolean;
     lyrics: Lyrics | null;
+    /** Min/max peaks for the seek bar */
+    waveform: [number, number][] | null;
     backend: 'native' | 'web';
 };
 
@@ -63,7 +69,10 @@ export const INITIAL_AUDIO = {
     currentTime: 0,
     duration: 0,
     scrobbled: false,
+    listened: 0,
+    logged: false,
     lyrics: null,
+    waveform: null,
     backend: 'web' as const
 };
 
@@ -119,3 +128,17 @@ audio.subscribe((a) => {
             });
     }
 });
+
+let previousTime = 0;
+
+audio.subscribe((a) => {
+    if (!a) return;
+
+    const elapsed = a.currentTime - previousTime;
+    previousTime = a.currentTime;
+
+    // Playback moves forward a little at a time, seeking jumps
+    if (elapsed > 0 && elapsed < 1) {
+        a.listened += elapsed;
+    }
+});
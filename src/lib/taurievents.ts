@@ -1,6 +1,7 @@
 import { listen } from '@tauri-apps/api/event';
 import { get } from 'svelte/store';
 import { progress } from './stores';
+import db, { type Loudness, type Track } from './db';
 
 export default async function () {
     await listen('progressUpdate', (event) => {
@@ -19,4 +20,21 @@ export default async function () {
             })
         );
     });
+
+    await listen('trackAnalyzed', async (event) => {
+        const { trackId, ...analysis } = event.payload as {
+            trackId: string;
+        } & Partial<Track>;
+
+        await db.tracks.update(trackId, analysis);
+    });
+
+    await listen('albumAnalyzed', async (event) => {
+        const { albumId, loudness } = event.payload as {
+            albumId: string;
+            loudness: Loudness;
+        };
+
+        await db.albums.update(albumId, { loudness });
+    });
 }
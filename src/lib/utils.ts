@@ -31,6 +31,40 @@ export function getTimestamp(seconds: number): string {
         .padStart(2, '0')}`;
 }
 
+const naturalCollator = new Intl.Collator(undefined, {
+    numeric: true,
+    sensitivity: 'base'
+});
+
+/** Case and accent insensitive, numbers by value, so "Disc 2" comes before "Disc 10" */
+export function naturalCompare(a: string, b: string): number {
+    return naturalCollator.compare(a, b);
+}
+
+/** Sort by sort names, falling back to display names for records from older versions */
+export function bySortName<T extends { name: string; sortName?: string }>(
+    a: T,
+    b: T
+): number {
+    return naturalCompare(a.sortName || a.name, b.sortName || b.name);
+}
+
+/** Original release date of an album, or when this edition was released */
+export function albumDate(album: {
+    year?: number;
+    releaseDate?: string;
+    originalDate?: string;
+}): string {
+    return album.originalDate || album.releaseDate || `${album.year ?? ''}`;
+}
+
+/** Newest first, partial ISO dates sort chronologically as strings */
+export function byAlbumDateDescending<
+    T extends { year?: number; releaseDate?: string; originalDate?: string }
+>(a: T, b: T): number {
+    return naturalCompare(albumDate(b), albumDate(a));
+}
+
 export function removeExtension(path: string): string {
     const parts = path.split('.');
 
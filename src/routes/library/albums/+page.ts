@@ -4,11 +4,12 @@ export async function load() {
     if (!browser) return;
 
     const { default: db } = await import('$lib/db');
+    const { bySortName } = await import('$lib/utils');
 
     const albums = await Promise.all(
-        (
-            await db.albums.orderBy('name').toArray()
-        ).map(async (album) => await db.friendlyAlbum(album))
+        (await db.albums.toArray())
+            .sort(bySortName)
+            .map(async (album) => await db.friendlyAlbum(album))
     );
 
     return {
@@ -23,7 +23,11 @@ export const load: PageLoad = async ({ url }) => {
 
     const tracks = await Promise.all(
         (
-            await db.tracks.where('artistId').equals(slug).toArray()
+            await db.tracks
+                .where('artistId')
+                .equals(slug)
+                .filter((track) => !track.hidden)
+                .toArray()
         ).map(async (track) => await db.friendlyTrack(track))
     );
 
@@ -4,8 +4,9 @@ export async function load() {
     if (!browser) return;
 
     const { default: db } = await import('$lib/db');
+    const { bySortName } = await import('$lib/utils');
 
-    const artists = await db.artists.orderBy('name').toArray();
+    const artists = (await db.artists.toArray()).sort(bySortName);
 
     return {
         artists
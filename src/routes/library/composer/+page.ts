@@ -0,0 +1,47 @@
+import { error } from '@sveltejs/kit';
+import type { PageLoad } from './$types';
+import { browser } from '$app/environment';
+
+export const load: PageLoad = async ({ url }) => {
+    if (!browser) return;
+
+    const { default: db } = await import('$lib/db');
+    const { groupByWork } = await import('$lib/library');
+
+    const slug = url.searchParams.get('id');
+
+    if (!slug) {
+        error(404, 'Composer not found');
+    }
+
+    const composer = await db.composers.get(slug);
+
+    if (!composer) {
+        error(404, 'Composer not found');
+    }
+
+    const works = await Promise.all(
+        (await groupByWork(slug)).map(async (work) => ({
+            ...work,
+            album: await db.albums.get(work.albumId),
+            tracks: await Promise.all(
+                (await db.tracks.bulkGet(work.trackIds))
+                    .filter((track) => track !== undefined)
+                    .map(async (track) => await db.friendlyTrack(track!))
+            )
+        }))
+    );
+
+    // Tracks that aren't part of a work
+    const otherTracks = await Promise.all(
+        (await db.tracks.where('composerId').equals(slug).toArray())
+            .filter((track) => !track.work && !track.hidden)
+            .map(async (track) => await db.friendlyTrack(track))
+    );
+
+    return {
+        composer,
+        works,
+        otherTracks
+    };
+};
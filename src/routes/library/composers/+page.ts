@@ -0,0 +1,14 @@
+import { browser } from '$app/environment';
+
+export async function load() {
+    if (!browser) return;
+
+    const { default: db } = await import('$lib/db');
+    const { bySortName } = await import('$lib/utils');
+
+    const composers = (await db.composers.toArray()).sort(bySortName);
+
+    return {
+        composers
+    };
+}
@@ -0,0 +1,28 @@
+import type { PageLoad } from './$types';
+import { browser } from '$app/environment';
+import type { LibraryRoot } from '$lib/db';
+
+export const load: PageLoad = async ({ url }) => {
+    if (!browser) return;
+
+    const { default: db } = await import('$lib/db');
+    const { browseFolder, friendlyTracks } = await import('$lib/folders');
+
+    const path = url.searchParams.get('path');
+    const folder = await browseFolder(path);
+
+    const roots = ((await db.kvs.get('libraryRoots'))?.value ??
+        []) as LibraryRoot[];
+
+    // Music directories go back to the list of them
+    const parent =
+        !path || roots.some((root) => root.path === path)
+            ? null
+            : path.replace(/[/\\][^/\\]*[/\\]?$/, '');
+
+    return {
+        folder,
+        parent,
+        tracks: await friendlyTracks(folder.trackIds)
+    };
+};
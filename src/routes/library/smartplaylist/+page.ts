@@ -0,0 +1,16 @@
+import { browser } from '$app/environment';
+import type { PageLoad } from './$types';
+
+// Editing an existing smart playlist with ?id=, or creating one without
+export const load: PageLoad = async ({ url }) => {
+    if (!browser) return;
+
+    const { default: db } = await import('$lib/db');
+
+    const id = url.searchParams.get('id');
+    const playlist = id ? await db.playlists.get(id) : undefined;
+
+    return {
+        playlist: playlist?.smart ? playlist : undefined
+    };
+};
@@ -1,17 +1,55 @@
 import { browser } from '$app/environment';
+import type { Track } from '$lib/db';
 
-export async function load() {
+const SORT_FIELDS = ['title', 'bpm', 'key'] as const;
+
+// Keys sort around the Camelot wheel so neighbouring keys mix well, "10A" comes after "9B"
+function camelotOrder(track: Track) {
+    if (!track.camelot) return Infinity;
+
+    return parseInt(track.camelot) * 2 + (track.camelot.endsWith('B') ? 1 : 0);
+}
+
+export async function load({ url }) {
     if (!browser) return;
 
     const { default: db } = await import('$lib/db');
+    const { naturalCompare } = await import('$lib/utils');
+
+    const sortParam = url.searchParams.get('sort');
+    const sort = SORT_FIELDS.find((field) => field === sortParam) || 'title';
+
+    // Filters, e.g. ?key=8A&bpmMin=120&bpmMax=130. Keys match either notation.
+    const key = url.searchParams.get('key');
+    const bpmMin = Number(url.searchParams.get('bpmMin')) || 0;
+    const bpmMax = Number(url.searchParams.get('bpmMax')) || Infinity;
+
+    let tracks = (await db.tracks.toArray())
+        .filter((t) => !t.hidden)
+        .sort((a, b) =>
+            naturalCompare(a.sortTitle || a.title, b.sortTitle || b.title)
+        );
+
+    if (key) {
+        tracks = tracks.filter((t) => t.key === key || t.camelot === key);
+    }
+
+    if (bpmMin > 0 || bpmMax < Infinity) {
+        tracks = tracks.filter(
+            (t) => t.bpm !== undefined && t.bpm >= bpmMin && t.bpm <= bpmMax
+        );
+    }
 
-    const tracks = await Promise.all(
-        (
-            await db.tracks.orderBy('title').toArray()
-        ).map(async (track) => await db.friendlyTrack(track))
-    );
+    if (sort === 'bpm') {
+        tracks.sort((a, b) => (a.bpm ?? Infinity) - (b.bpm ?? Infinity));
+    } else if (sort === 'key') {
+        tracks.sort((a, b) => camelotOrder(a) - camelotOrder(b));
+    }
 
     return {
-        tracks
+        sort,
+        tracks: await Promise.all(
+            tracks.map(async (track) => await db.friendlyTrack(track))
+        )
     };
 }